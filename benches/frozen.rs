@@ -0,0 +1,56 @@
+/*
+ * SlabRedBlack::search against the same keys frozen into a FrozenSet
+ *
+ *     cargo bench --bench frozen
+ */
+use red_black_tree::redblack::RedBlack;
+use red_black_tree::slab::SlabRedBlack;
+use std::hint::black_box;
+use std::time::Instant;
+
+const OPS: u64 = 2_000_000;
+
+fn lcg(x: u64) -> u64 {
+    x.wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407)
+}
+
+// every other lookup hits an existing key
+fn lookups<F: FnMut(&u64) -> bool>(mut found: F) -> f64 {
+    let start = Instant::now();
+    let mut num = 1;
+    let mut hits = 0;
+    for i in 0..OPS {
+        num = lcg(num);
+        let key = if i % 2 == 0 { num >> 16 } else { num };
+        if found(black_box(&key)) {
+            hits += 1;
+        }
+    }
+    black_box(hits);
+    start.elapsed().as_nanos() as f64 / OPS as f64
+}
+
+fn run(n: u64) {
+    let mut rb: SlabRedBlack<u64, u32> = SlabRedBlack::new();
+    let mut num = 1;
+    for _ in 0..n {
+        num = lcg(num);
+        rb.insert(num >> 16);
+    }
+    let set = rb.freeze();
+
+    let tree = lookups(|key| rb.search(key).is_some());
+    let frozen = lookups(|key| set.contains(key));
+    println!(
+        "{:>9} keys   SlabRedBlack {:>7.1} ns/op   FrozenSet {:>7.1} ns/op",
+        n, tree, frozen
+    );
+}
+
+fn main() {
+    run(1_000);
+    run(100_000);
+    run(1_000_000);
+    run(4_000_000);
+}
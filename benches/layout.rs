@@ -0,0 +1,69 @@
+/*
+ * search time on a slab whose nodes were scattered by churn, before and
+ * after relayout
+ *
+ *     cargo bench --bench layout
+ */
+use red_black_tree::redblack::RedBlack;
+use red_black_tree::slab::{Layout, SlabRedBlack};
+use std::hint::black_box;
+use std::time::Instant;
+
+const N: u64 = 2_000_000;
+const SEARCHES: u64 = 2_000_000;
+
+fn lcg(x: u64) -> u64 {
+    x.wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407)
+}
+
+// inserts then deletes and reinserts, so slab slots end up in random order
+fn churned() -> SlabRedBlack<u64, u32> {
+    let mut rb = SlabRedBlack::new();
+    let mut num = 1;
+    for _ in 0..N {
+        num = lcg(num);
+        rb.insert(num >> 16);
+    }
+    num = 1;
+    for _ in 0..N / 2 {
+        num = lcg(num);
+        rb.delete(&(num >> 16));
+        rb.insert(lcg(num) >> 16);
+    }
+    rb
+}
+
+fn time_searches(rb: &mut SlabRedBlack<u64, u32>) -> f64 {
+    let start = Instant::now();
+    let mut num = 7;
+    let mut found = 0;
+    for _ in 0..SEARCHES {
+        num = lcg(num);
+        if rb.search(black_box(&(num >> 16))).is_some() {
+            found += 1;
+        }
+    }
+    black_box(found);
+    start.elapsed().as_nanos() as f64 / SEARCHES as f64
+}
+
+fn main() {
+    let mut rb = churned();
+    let scattered = time_searches(&mut rb);
+    println!("{:<14} {:>7.1} ns/search", "scattered", scattered);
+
+    for &layout in &[Layout::InOrder, Layout::BreadthFirst, Layout::VanEmdeBoas] {
+        let start = Instant::now();
+        rb.relayout(layout);
+        let relayout_ms = start.elapsed().as_millis();
+        let ns = time_searches(&mut rb);
+        println!(
+            "{:<14} {:>7.1} ns/search  {:.2}x  (relayout took {} ms)",
+            format!("{:?}", layout),
+            ns,
+            scattered / ns,
+            relayout_ms
+        );
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * heap bytes per element for the slab node layouts
+ *
+ *     cargo bench --bench memory
+ *
+ * the baseline is the node the slab tree used before the color bit was packed
+ * into the parent link, a local copy below, held in a slab the way the old
+ * tree held it (sentinel in slot 0, every key inserted one by one)
+ */
+use red_black_tree::redblack::RedBlack;
+use red_black_tree::slab::SlabRedBlack;
+use slab::Slab;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// the slab node as it was, with usize links and a separate color flag
+#[allow(dead_code)]
+struct OldNode<T> {
+    parent: usize,
+    children: [usize; 2],
+    key: T,
+    red: bool,
+}
+
+fn report(name: &str, n: u32, used: usize) {
+    println!(
+        "{:<28} n={:<8} {:>10} bytes {:>6.1} bytes/key",
+        name,
+        n,
+        used,
+        used as f64 / n as f64
+    );
+}
+
+fn measure_old(n: u32) {
+    let before = LIVE.load(Ordering::SeqCst);
+    let mut slab = Slab::new();
+    let nil = slab.insert(OldNode {
+        parent: !0,
+        children: [!0, !0],
+        key: 0u32,
+        red: false,
+    });
+    for i in 0..n {
+        slab.insert(OldNode {
+            parent: nil,
+            children: [nil, nil],
+            key: i,
+            red: false,
+        });
+    }
+    report(
+        "old Node<u32> (bool color)",
+        n,
+        LIVE.load(Ordering::SeqCst) - before,
+    );
+    drop(slab);
+}
+
+fn measure<R: RedBlack<u32>>(name: &str, n: u32) {
+    let before = LIVE.load(Ordering::SeqCst);
+    let mut rb = R::new();
+    for i in 0..n {
+        rb.insert(i);
+    }
+    report(name, n, LIVE.load(Ordering::SeqCst) - before);
+    drop(rb);
+}
+
+fn main() {
+    for &n in &[30_000, 1_000_000] {
+        measure_old(n);
+        measure::<SlabRedBlack<u32, usize>>("SlabRedBlack<u32, usize>", n);
+        measure::<SlabRedBlack<u32, u32>>("SlabRedBlack<u32, u32>", n);
+        if n < 1 << 15 {
+            measure::<SlabRedBlack<u32, u16>>("SlabRedBlack<u32, u16>", n);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * array-of-structs SlabRedBlack against struct-of-arrays SoaRedBlack
+ *
+ *     cargo bench --bench soa
+ */
+use red_black_tree::redblack::RedBlack;
+use red_black_tree::slab::SlabRedBlack;
+use red_black_tree::soa::SoaRedBlack;
+use std::hint::black_box;
+use std::time::Instant;
+
+const N: u64 = 1_000_000;
+const OPS: u64 = 2_000_000;
+
+fn lcg(x: u64) -> u64 {
+    x.wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407)
+}
+
+fn filled<R: RedBlack<u64>>() -> R {
+    let mut rb = R::new();
+    let mut num = 1;
+    for _ in 0..N {
+        num = lcg(num);
+        rb.insert(num >> 16);
+    }
+    rb
+}
+
+fn search_heavy<R: RedBlack<u64>>(rb: &mut R) -> f64 {
+    let start = Instant::now();
+    let mut num = 1;
+    let mut found = 0;
+    for i in 0..OPS {
+        // every other search hits an existing key
+        num = lcg(num);
+        let key = if i % 2 == 0 { num >> 16 } else { num };
+        if rb.search(black_box(&key)).is_some() {
+            found += 1;
+        }
+    }
+    black_box(found);
+    start.elapsed().as_nanos() as f64 / OPS as f64
+}
+
+fn update_heavy<R: RedBlack<u64>>(rb: &mut R) -> f64 {
+    let start = Instant::now();
+    let mut num = 1;
+    for _ in 0..OPS / 2 {
+        num = lcg(num);
+        rb.delete(black_box(&(num >> 16)));
+        rb.insert(black_box(lcg(num) >> 16));
+    }
+    start.elapsed().as_nanos() as f64 / OPS as f64
+}
+
+fn run<R: RedBlack<u64>>(name: &str) {
+    let mut rb: R = filled();
+    let search = search_heavy(&mut rb);
+    let update = update_heavy(&mut rb);
+    println!(
+        "{:<24} search {:>7.1} ns/op   update {:>7.1} ns/op",
+        name, search, update
+    );
+}
+
+fn main() {
+    run::<SlabRedBlack<u64, u32>>("SlabRedBlack<u64, u32>");
+    run::<SoaRedBlack<u64, u32>>("SoaRedBlack<u64, u32>");
+    run::<SlabRedBlack<u64>>("SlabRedBlack<u64>");
+    run::<SoaRedBlack<u64>>("SoaRedBlack<u64>");
+}
@@ -0,0 +1,160 @@
+use crate::memory::ReserveError;
+use crate::store::{compact_index_nodes, IndexNode, NodeStore, NULL};
+use crate::tree::RedBlackTree;
+use std::mem;
+
+/*
+ * a plain Vec of nodes with an intrusive free-list
+ *
+ * vacant slots keep an uninitialized key and reuse their parent link as the
+ * index of the next vacant slot, so freeing and reusing a node is O(1)
+ * without a second allocation
+ */
+pub struct VecStore<T> {
+    nodes: Vec<IndexNode<T>>,
+    free_head: usize,
+    nil_sentinel: usize,
+}
+
+impl<T> VecStore<T> {
+    pub fn new() -> VecStore<T> {
+        // entry 0 is the nil sentinel
+        VecStore {
+            nodes: vec![IndexNode::nil_sentinel()],
+            free_head: NULL,
+            nil_sentinel: 0,
+        }
+    }
+}
+
+impl<T> Default for VecStore<T> {
+    fn default() -> VecStore<T> {
+        VecStore::new()
+    }
+}
+
+impl<T> NodeStore<T> for VecStore<T> {
+    type Link = usize;
+
+    fn nil(&self) -> usize {
+        self.nil_sentinel
+    }
+
+    fn alloc(&mut self, key: T) -> usize {
+        let node = IndexNode::new(key, self.nil_sentinel);
+        if self.free_head == NULL {
+            self.nodes.push(node);
+            return self.nodes.len() - 1;
+        }
+        let x = self.free_head;
+        self.free_head = self.nodes[x].parent;
+        self.nodes[x] = node;
+        x
+    }
+
+    fn free(&mut self, x: usize) -> T {
+        let mut vacant = IndexNode::nil_sentinel();
+        vacant.parent = self.free_head;
+        self.free_head = x;
+
+        let node = mem::replace(&mut self.nodes[x], vacant);
+        unsafe { node.key.assume_init() }
+    }
+
+    fn capacity(&self) -> usize {
+        self.nodes.capacity() - 1
+    }
+
+    fn slot_size(&self) -> usize {
+        mem::size_of::<IndexNode<T>>()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> Result<(), ReserveError> {
+        Ok(self.nodes.try_reserve(additional)?)
+    }
+
+    fn shrink_to_fit(&mut self, root: usize) -> usize {
+        // every vacant slot goes away, and the free-list with it
+        self.free_head = NULL;
+        compact_index_nodes(&mut self.nodes, root)
+    }
+
+    index_node_links!(nodes);
+}
+
+pub type VecRedBlack<T> = RedBlackTree<T, VecStore<T>>;
+
+/*
+ * a grow-only arena for build-once trees
+ *
+ * free hands the key back but never recycles the slot, so a tree that is
+ * mostly inserted into pays nothing for free-list bookkeeping; memory is only
+ * returned when the whole tree is dropped
+ */
+pub struct BumpStore<T> {
+    nodes: Vec<IndexNode<T>>,
+    nil_sentinel: usize,
+}
+
+impl<T> BumpStore<T> {
+    pub fn new() -> BumpStore<T> {
+        // entry 0 is the nil sentinel
+        BumpStore {
+            nodes: vec![IndexNode::nil_sentinel()],
+            nil_sentinel: 0,
+        }
+    }
+}
+
+impl<T> Default for BumpStore<T> {
+    fn default() -> BumpStore<T> {
+        BumpStore::new()
+    }
+}
+
+impl<T> NodeStore<T> for BumpStore<T> {
+    type Link = usize;
+
+    fn nil(&self) -> usize {
+        self.nil_sentinel
+    }
+
+    fn alloc(&mut self, key: T) -> usize {
+        self.nodes.push(IndexNode::new(key, self.nil_sentinel));
+        self.nodes.len() - 1
+    }
+
+    fn free(&mut self, x: usize) -> T {
+        // leave a dead slot behind, its key must never be read again
+        let node = mem::replace(&mut self.nodes[x], IndexNode::nil_sentinel());
+        unsafe { node.key.assume_init() }
+    }
+
+    fn capacity(&self) -> usize {
+        self.nodes.capacity() - 1
+    }
+
+    fn slot_size(&self) -> usize {
+        mem::size_of::<IndexNode<T>>()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> Result<(), ReserveError> {
+        Ok(self.nodes.try_reserve(additional)?)
+    }
+
+    fn shrink_to_fit(&mut self, root: usize) -> usize {
+        compact_index_nodes(&mut self.nodes, root)
+    }
+
+    index_node_links!(nodes);
+}
+
+pub type BumpRedBlack<T> = RedBlackTree<T, BumpStore<T>>;
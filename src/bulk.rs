@@ -0,0 +1,157 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::cmp::Ordering;
+use std::{error, fmt, vec};
+
+// why from_sorted refused its input, index is that of the offending key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    Unsorted { index: usize },
+    Duplicate { index: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Unsorted { index } => {
+                write!(f, "key {} sorts before the key preceding it", index)
+            }
+            BuildError::Duplicate { index } => {
+                write!(f, "key {} is equal to the key preceding it", index)
+            }
+        }
+    }
+}
+
+impl error::Error for BuildError {}
+
+// the deepest level of a midpoint-split tree of n nodes, when it isn't full
+pub(crate) fn red_depth(n: usize) -> usize {
+    (n + 1).ilog2() as usize
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    /*
+     * build a tree from strictly ascending keys in O(n), no rotations
+     *
+     * splitting at the midpoint keeps every nil leaf within one level of
+     * the others; the nodes on the bottom, partly filled level are red and
+     * all others black, so every path counts the same black nodes
+     */
+    pub fn from_sorted<I>(keys: I) -> Result<RedBlackTree<T, S>, BuildError>
+    where
+        I: IntoIterator<Item = T>,
+        S: Default,
+    {
+        RedBlackTree::from_sorted_in(S::default(), keys)
+    }
+
+    pub fn from_sorted_in<I>(store: S, keys: I) -> Result<RedBlackTree<T, S>, BuildError>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        RedBlackTree::build_from(store, keys.into_iter().collect(), true)
+    }
+
+    /*
+     * the same, but keeping runs of equal keys, the way a tree that had a
+     * key inserted twice holds it; for reading back what such a tree wrote
+     */
+    pub(crate) fn from_ascending(keys: Vec<T>) -> Result<RedBlackTree<T, S>, BuildError>
+    where
+        S: Default,
+    {
+        RedBlackTree::build_from(S::default(), keys, false)
+    }
+
+    fn build_from(store: S, keys: Vec<T>, strict: bool) -> Result<RedBlackTree<T, S>, BuildError> {
+        for (index, pair) in keys.windows(2).enumerate() {
+            // incomparable keys (NaN) count as unsorted too
+            match pair[0].partial_cmp(&pair[1]) {
+                Some(Ordering::Less) => {}
+                Some(Ordering::Equal) if !strict => {}
+                Some(Ordering::Equal) => return Err(BuildError::Duplicate { index: index + 1 }),
+                _ => return Err(BuildError::Unsorted { index: index + 1 }),
+            }
+        }
+
+        let mut rb = RedBlackTree::with_store(store);
+        let n = keys.len();
+        rb.reserve(n);
+        rb.root = rb.build(&mut keys.into_iter(), n, 0, red_depth(n));
+        rb.len = n;
+        Ok(rb)
+    }
+
+    // links up n keys in order, returning the subtree's root
+    fn build(
+        &mut self,
+        keys: &mut vec::IntoIter<T>,
+        n: usize,
+        depth: usize,
+        red: usize,
+    ) -> S::Link {
+        let nil = self.store.nil();
+        if n == 0 {
+            return nil;
+        }
+        let l = self.build(keys, n / 2, depth + 1, red);
+        let x = self.store.alloc(keys.next().unwrap());
+        let r = self.build(keys, n - 1 - n / 2, depth + 1, red);
+
+        self.store.set_red(x, depth == red);
+        for (dir, c) in [l, r].iter().enumerate() {
+            self.store.set_child(x, dir, *c);
+            if *c != nil {
+                self.store.set_parent(*c, x);
+            }
+        }
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::slab::SlabRedBlack;
+
+    #[test]
+    fn test_from_sorted() {
+        for n in 0..200 {
+            let rb: SlabRedBlack<usize> = RedBlackTree::from_sorted(0..n).unwrap();
+            rb.is_valid(); // will panic if it must
+            assert_eq!(
+                rb.iter().copied().collect::<Vec<_>>(),
+                (0..n).collect::<Vec<_>>()
+            );
+        }
+
+        // and it keeps working as a normal tree
+        let mut rb: PointerRedBlack<usize> = RedBlackTree::from_sorted(0..1000).unwrap();
+        for i in 1000..2000 {
+            rb.insert(i);
+        }
+        for i in 0..1500 {
+            rb.delete(&i);
+        }
+        rb.is_valid(); // will panic if it must
+        assert_eq!(rb.len(), 500);
+    }
+
+    #[test]
+    fn test_from_sorted_rejects() {
+        let rb: Result<SlabRedBlack<i32>, _> = RedBlackTree::from_sorted(vec![1, 2, 2, 3]);
+        assert_eq!(rb.err(), Some(BuildError::Duplicate { index: 2 }));
+
+        let rb: Result<SlabRedBlack<i32>, _> = RedBlackTree::from_sorted(vec![1, 3, 2]);
+        assert_eq!(rb.err(), Some(BuildError::Unsorted { index: 2 }));
+
+        let rb: Result<SlabRedBlack<f64>, _> = RedBlackTree::from_sorted(vec![1.0, f64::NAN]);
+        assert_eq!(rb.err(), Some(BuildError::Unsorted { index: 1 }));
+    }
+}
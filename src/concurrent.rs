@@ -0,0 +1,330 @@
+use crate::slab::SlabStore;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::cell::{Cell, UnsafeCell};
+use std::ops::{Deref, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+/*
+ * one writer, any number of readers that never wait for it (left-right)
+ *
+ * there are two copies of the tree: readers use one while the writer
+ * updates the other and logs what it did; publish swaps the two, waits
+ * until no reader is still inside the copy it just took away, and replays
+ * the log there so both copies agree again
+ *
+ * each reader has an epoch counter that's odd while it's reading; that's
+ * all a read costs, the writer is the one that waits. writes only show up
+ * for readers once published
+ */
+pub struct ConcurrentRedBlack<T, S: NodeStore<T> = SlabStore<T>> {
+    shared: Arc<Shared<T, S>>,
+    log: Vec<Op<T>>,
+}
+
+enum Op<T> {
+    Insert(T),
+    Delete(T),
+}
+
+struct Shared<T, S: NodeStore<T>> {
+    trees: [UnsafeCell<RedBlackTree<T, S>>; 2],
+    // the copy readers use, the writer has the other one
+    active: AtomicUsize,
+    // epochs of the live readers, only locked to add and sweep them
+    epochs: Mutex<Vec<Weak<AtomicUsize>>>,
+}
+
+// readers only ever get shared references to the copy they're in
+unsafe impl<T: Send + Sync, S: NodeStore<T> + Send + Sync> Sync for Shared<T, S> {}
+unsafe impl<T: Send + Sync, S: NodeStore<T> + Send + Sync> Send for Shared<T, S> {}
+
+// a reader handle, one per thread; clone it for another thread
+pub struct Reader<T, S: NodeStore<T> = SlabStore<T>> {
+    shared: Arc<Shared<T, S>>,
+    epoch: Arc<AtomicUsize>,
+    // nested reads only count once
+    depth: Cell<usize>,
+}
+
+// the tree as published when the read began, for as long as this lives
+pub struct ReadGuard<'a, T, S: NodeStore<T>> {
+    reader: &'a Reader<T, S>,
+    tree: &'a RedBlackTree<T, S>,
+}
+
+impl<T, S> ConcurrentRedBlack<T, S>
+where
+    T: PartialOrd + Clone,
+    S: NodeStore<T> + Default,
+{
+    pub fn new() -> Self {
+        let tree = || UnsafeCell::new(RedBlackTree::with_store(S::default()));
+        ConcurrentRedBlack {
+            shared: Arc::new(Shared {
+                trees: [tree(), tree()],
+                active: AtomicUsize::new(0),
+                epochs: Mutex::new(Vec::new()),
+            }),
+            log: Vec::new(),
+        }
+    }
+}
+
+impl<T, S> Default for ConcurrentRedBlack<T, S>
+where
+    T: PartialOrd + Clone,
+    S: NodeStore<T> + Default,
+{
+    fn default() -> Self {
+        ConcurrentRedBlack::new()
+    }
+}
+
+impl<T, S: NodeStore<T>> Shared<T, S> {
+    fn reader(shared: &Arc<Self>) -> Reader<T, S> {
+        let epoch = Arc::new(AtomicUsize::new(0));
+        let mut epochs = shared.epochs.lock().unwrap();
+        epochs.retain(|e| e.strong_count() > 0);
+        epochs.push(Arc::downgrade(&epoch));
+        Reader {
+            shared: shared.clone(),
+            epoch,
+            depth: Cell::new(0),
+        }
+    }
+}
+
+impl<T, S> ConcurrentRedBlack<T, S>
+where
+    T: PartialOrd + Clone,
+    S: NodeStore<T>,
+{
+    // the copy no reader can be in: the writer's own
+    fn writing(&mut self) -> &mut RedBlackTree<T, S> {
+        let side = self.shared.active.load(Ordering::SeqCst) ^ 1;
+        unsafe { &mut *self.shared.trees[side].get() }
+    }
+
+    pub fn insert(&mut self, key: T) {
+        self.writing().insert(key.clone());
+        self.log.push(Op::Insert(key));
+    }
+
+    pub fn delete(&mut self, key: &T) {
+        self.writing().delete(key);
+        self.log.push(Op::Delete(key.clone()));
+    }
+
+    // the writer sees its own writes, published or not
+    pub fn search(&self, key: &T) -> Option<&T> {
+        let side = self.shared.active.load(Ordering::SeqCst) ^ 1;
+        unsafe { (*self.shared.trees[side].get()).search(key) }
+    }
+
+    pub fn len(&self) -> usize {
+        let side = self.shared.active.load(Ordering::SeqCst) ^ 1;
+        unsafe { (*self.shared.trees[side].get()).len() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reader(&self) -> Reader<T, S> {
+        Shared::reader(&self.shared)
+    }
+
+    /*
+     * make every write so far visible to readers; waits for reads that
+     * started before it to finish, never for ones that start after
+     */
+    pub fn publish(&mut self) {
+        if self.log.is_empty() {
+            return;
+        }
+        let side = self.shared.active.load(Ordering::SeqCst);
+        self.shared.active.store(side ^ 1, Ordering::SeqCst);
+
+        // a reader whose epoch is odd now may have loaded the old side
+        let epochs: Vec<Arc<AtomicUsize>> = {
+            let epochs = self.shared.epochs.lock().unwrap();
+            epochs.iter().filter_map(Weak::upgrade).collect()
+        };
+        for epoch in &epochs {
+            let seen = epoch.load(Ordering::SeqCst);
+            if seen % 2 == 1 {
+                while epoch.load(Ordering::SeqCst) == seen {
+                    thread::yield_now();
+                }
+            }
+        }
+
+        let log = std::mem::take(&mut self.log);
+        let tree = self.writing();
+        for op in log {
+            match op {
+                Op::Insert(key) => tree.insert(key),
+                Op::Delete(key) => tree.delete(&key),
+            }
+        }
+    }
+}
+
+impl<T, S> Reader<T, S>
+where
+    T: PartialOrd,
+    S: NodeStore<T>,
+{
+    pub fn read(&self) -> ReadGuard<'_, T, S> {
+        let depth = self.depth.get();
+        if depth == 0 {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        self.depth.set(depth + 1);
+        let side = self.shared.active.load(Ordering::SeqCst);
+        ReadGuard {
+            reader: self,
+            tree: unsafe { &*self.shared.trees[side].get() },
+        }
+    }
+
+    pub fn search(&self, key: &T) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.read().search(key).cloned()
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.read().range(range).cloned().collect()
+    }
+}
+
+impl<T, S: NodeStore<T>> Clone for Reader<T, S> {
+    fn clone(&self) -> Self {
+        Shared::reader(&self.shared)
+    }
+}
+
+impl<'a, T, S: NodeStore<T>> Deref for ReadGuard<'a, T, S> {
+    type Target = RedBlackTree<T, S>;
+
+    fn deref(&self) -> &RedBlackTree<T, S> {
+        self.tree
+    }
+}
+
+impl<'a, T, S: NodeStore<T>> Drop for ReadGuard<'a, T, S> {
+    fn drop(&mut self) {
+        let depth = self.reader.depth.get() - 1;
+        self.reader.depth.set(depth);
+        if depth == 0 {
+            self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slab::SlabStore;
+    use crate::tree::workload;
+    use std::sync::atomic::AtomicBool;
+
+    fn is_sync<X: Sync>() {}
+    fn is_send<X: Send>() {}
+
+    #[test]
+    fn test_publish() {
+        is_sync::<ConcurrentRedBlack<u32>>();
+        is_send::<Reader<u32>>();
+
+        let mut rb: ConcurrentRedBlack<u32> = ConcurrentRedBlack::new();
+        let reader = rb.reader();
+        rb.insert(1);
+        rb.insert(2);
+        assert_eq!(rb.search(&1), Some(&1));
+        assert_eq!(reader.search(&1), None);
+        rb.publish();
+        assert_eq!(reader.range(..), vec![1, 2]);
+
+        // a read in progress keeps the tree it started with
+        let guard = reader.read();
+        rb.delete(&1);
+        rb.insert(3);
+        assert_eq!(guard.len(), 2);
+        let nested = reader.read();
+        assert_eq!(nested.len(), 2);
+        drop(nested);
+        drop(guard);
+        rb.publish();
+        assert_eq!(reader.range(..), vec![2, 3]);
+        rb.publish(); // nothing new
+        assert_eq!(rb.len(), 2);
+    }
+
+    /*
+     * the writer inserts 0.. in batches and then deletes them again, so
+     * every published tree holds some contiguous run of keys a multiple of
+     * the batch size long; readers check that they never see anything else
+     */
+    #[test]
+    fn test_stress() {
+        const BATCH: u32 = 50;
+        let total = workload(5_000) as u32;
+
+        let mut rb: ConcurrentRedBlack<u32, SlabStore<u32>> = ConcurrentRedBlack::new();
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = rb.reader();
+                let done = done.clone();
+                thread::spawn(move || {
+                    // at least one read each, however late the thread starts
+                    let mut reads = 0;
+                    loop {
+                        let tree = reader.read();
+                        let n = tree.len() as u32;
+                        assert_eq!(n % BATCH, 0);
+                        let keys: Vec<u32> = tree.iter().copied().collect();
+                        assert_eq!(keys.len() as u32, n);
+                        if let (Some(lo), Some(hi)) = (keys.first(), keys.last()) {
+                            assert_eq!(hi - lo + 1, n);
+                            assert_eq!(tree.search(&(lo + n / 2)), Some(&(lo + n / 2)));
+                            let mid = tree.range(lo + 1..=*hi).count() as u32;
+                            assert_eq!(mid, n - 1);
+                        }
+                        reads += 1;
+                        if done.load(Ordering::Relaxed) {
+                            break reads;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for batch in 0..total / BATCH {
+            for i in 0..BATCH {
+                rb.insert(batch * BATCH + i);
+            }
+            rb.publish();
+        }
+        for batch in 0..total / BATCH {
+            for i in 0..BATCH {
+                rb.delete(&(batch * BATCH + i));
+            }
+            rb.publish();
+        }
+        done.store(true, Ordering::Relaxed);
+        let reads: u32 = readers.into_iter().map(|r| r.join().unwrap()).sum();
+        assert!(reads >= 4);
+        assert!(rb.is_empty());
+        assert_eq!(rb.reader().range(..), Vec::<u32>::new());
+    }
+}
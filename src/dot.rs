@@ -0,0 +1,155 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::fmt::Debug;
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DotOptions {
+    // draw a small black NIL box for every empty child
+    pub sentinel: bool,
+    // add each node's store link (slab index, pointer) under its key
+    pub links: bool,
+}
+
+// quote a label for DOT, which only needs " and \ escaped
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    /*
+     * write the tree as a Graphviz digraph, keys rendered with Debug
+     *
+     *     dot -Tsvg tree.dot > tree.svg
+     */
+    pub fn to_dot<W: Write>(&self, w: &mut W) -> io::Result<()>
+    where
+        T: Debug,
+    {
+        self.to_dot_with(w, DotOptions::default(), |key| format!("{:?}", key))
+    }
+
+    // same as to_dot, with the label of every node built by `label`
+    pub fn to_dot_with<W, F>(&self, w: &mut W, options: DotOptions, label: F) -> io::Result<()>
+    where
+        W: Write,
+        F: Fn(&T) -> String,
+    {
+        let nil = self.store.nil();
+
+        writeln!(w, "digraph RedBlackTree {{")?;
+        writeln!(w, "    node [style=filled, fontcolor=white];")?;
+
+        // nodes are numbered in the order they're visited, n0 is the root
+        let mut next_id = 0;
+        let mut nil_id = 0;
+        let mut stack = Vec::new();
+        if self.root != nil {
+            stack.push((self.root, next_id));
+            next_id += 1;
+        }
+        while let Some((x, id)) = stack.pop() {
+            let mut text = escape(&self.store.with_key(x, &label));
+            if options.links {
+                text.push_str(&format!("\\n{}", escape(&format!("{:?}", x))));
+            }
+            let color = if self.store.is_red(x) { "red" } else { "black" };
+            writeln!(w, "    n{} [label=\"{}\", fillcolor={}];", id, text, color)?;
+
+            for dir in 0..2 {
+                let c = self.store.child(x, dir);
+                if c != nil {
+                    writeln!(w, "    n{} -> n{};", id, next_id)?;
+                    stack.push((c, next_id));
+                    next_id += 1;
+                } else if options.sentinel {
+                    writeln!(
+                        w,
+                        "    nil{} [label=\"NIL\", shape=box, fillcolor=black, fontsize=8];",
+                        nil_id
+                    )?;
+                    writeln!(w, "    n{} -> nil{};", id, nil_id)?;
+                    nil_id += 1;
+                }
+            }
+        }
+
+        writeln!(w, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    use super::*;
+
+    #[test]
+    fn test_to_dot() {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        rb.insert(5);
+        rb.insert(1);
+        rb.insert(8);
+
+        let mut out = Vec::new();
+        rb.to_dot(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "digraph RedBlackTree {
+    node [style=filled, fontcolor=white];
+    n0 [label=\"5\", fillcolor=black];
+    n0 -> n1;
+    n0 -> n2;
+    n2 [label=\"8\", fillcolor=red];
+    n1 [label=\"1\", fillcolor=red];
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_to_dot_options() {
+        let mut rb: SlabRedBlack<(&str, &str)> = SlabRedBlack::new();
+        rb.insert(("b", "say \"hi\""));
+
+        let options = DotOptions {
+            sentinel: true,
+            links: true,
+        };
+        let mut out = Vec::new();
+        rb.to_dot_with(&mut out, options, |(k, v)| format!("{} = {}", k, v))
+            .unwrap();
+        let dot = String::from_utf8(out).unwrap();
+
+        // slab index 1 under the key, both children drawn as NIL boxes
+        assert!(dot.contains("n0 [label=\"b = say \\\"hi\\\"\\n1\", fillcolor=black];"));
+        assert!(dot.contains("n0 -> nil0;"));
+        assert!(dot.contains("n0 -> nil1;"));
+    }
+
+    #[test]
+    fn test_to_dot_pointer() {
+        let mut rb: PointerRedBlack<i32> = PointerRedBlack::new();
+        let mut out = Vec::new();
+        rb.to_dot(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "digraph RedBlackTree {\n    node [style=filled, fontcolor=white];\n}\n"
+        );
+
+        for i in 0..100 {
+            rb.insert(i);
+        }
+        let mut out = Vec::new();
+        rb.to_dot(&mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert_eq!(dot.matches("fillcolor=").count(), 100);
+        assert_eq!(dot.matches(" -> ").count(), 99);
+    }
+}
@@ -0,0 +1,517 @@
+use crate::slab::SlabStore;
+use crate::snapshot::{SnapshotError, SnapshotKey};
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // fsync every record before applying it
+    Always,
+    // fsync once every n records, and on sync() / drop
+    Batched(usize),
+    // leave it to the OS; records still reach the file before they're applied
+    Never,
+}
+
+const INSERT: u8 = 1;
+const DELETE: u8 = 2;
+
+/*
+ * a tree whose every insert/delete is appended to a write-ahead log
+ * before it's applied, so it can be rebuilt after a crash
+ *
+ * the directory holds generation g as snapshot-g (absent for g = 0) and
+ * wal-g, the records since that snapshot; a record is
+ *
+ *     op   u8   INSERT or DELETE
+ *     len  u32  length of the encoded key
+ *     key  len bytes, SnapshotKey::encode
+ *     crc  u32  CRC-32 of op, len and key
+ *
+ * compaction writes snapshot-(g+1) and an empty wal-(g+1) before removing
+ * generation g, so a crash at any point leaves one complete generation:
+ * recovery takes the newest snapshot and replays its log on top
+ *
+ * an append that fails, in the write or in the sync after it, is cut back
+ * out of the log, so nothing acknowledged ever follows a torn record and
+ * nothing refused is replayed. if even the cut fails the tree stops taking
+ * updates until a compaction starts a fresh log
+ */
+pub struct DurableTree<T, S: NodeStore<T> = SlabStore<T>> {
+    tree: RedBlackTree<T, S>,
+    dir: PathBuf,
+    generation: u64,
+    wal: File,
+    policy: SyncPolicy,
+    unsynced: usize,
+    // the log couldn't be cut back after a failed append
+    failed: bool,
+    // tests: write this many bytes of the next record, then fail
+    #[cfg(test)]
+    short_write: Option<usize>,
+}
+
+fn snapshot_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("snapshot-{}", generation))
+}
+
+fn wal_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("wal-{}", generation))
+}
+
+// make renames and removals in dir durable too
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// the generation number of a snapshot-g or wal-g file name
+fn generation_of(name: &str) -> Option<(&str, u64)> {
+    let (kind, generation) = name.split_once('-')?;
+    Some((kind, generation.parse().ok()?))
+}
+
+impl<T, S> DurableTree<T, S>
+where
+    T: std::cmp::PartialOrd + SnapshotKey,
+    S: NodeStore<T> + Default,
+{
+    // recovers whatever dir holds, or starts an empty tree there
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        policy: SyncPolicy,
+    ) -> Result<DurableTree<T, S>, SnapshotError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut generation = 0;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(("snapshot", g)) = name.to_str().and_then(generation_of) {
+                generation = generation.max(g);
+            }
+        }
+
+        let mut tree = if generation == 0 {
+            RedBlackTree::with_store(S::default())
+        } else {
+            let file = File::open(snapshot_path(&dir, generation))?;
+            RedBlackTree::load_snapshot(io::BufReader::new(file))?
+        };
+
+        let mut wal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(wal_path(&dir, generation))?;
+        let good = replay(&mut tree, &mut wal)?;
+
+        // cut off a torn tail so new records follow the last good one
+        if good < wal.metadata()?.len() {
+            wal.set_len(good)?;
+            wal.sync_all()?;
+        }
+        wal.seek(SeekFrom::Start(good))?;
+
+        // leftovers of a compaction that crashed halfway
+        remove_older(&dir, generation)?;
+
+        Ok(DurableTree {
+            tree,
+            dir,
+            generation,
+            wal,
+            policy,
+            unsynced: 0,
+            failed: false,
+            #[cfg(test)]
+            short_write: None,
+        })
+    }
+
+    pub fn insert(&mut self, key: T) -> io::Result<()> {
+        self.append(INSERT, &key)?;
+        self.tree.insert(key);
+        Ok(())
+    }
+
+    // deleting a key that isn't there isn't logged
+    pub fn delete(&mut self, key: &T) -> io::Result<()> {
+        if self.tree.search(key).is_some() {
+            self.append(DELETE, key)?;
+            self.tree.delete(key);
+        }
+        Ok(())
+    }
+
+    pub fn search(&self, key: &T) -> Option<&T> {
+        self.tree.search(key)
+    }
+
+    pub fn tree(&self) -> &RedBlackTree<T, S> {
+        &self.tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // fsync whatever has been logged since the last one
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.wal.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // fold the log into a fresh snapshot and start an empty one
+    pub fn compact(&mut self) -> io::Result<()> {
+        let next = self.generation + 1;
+
+        let tmp = self.dir.join(format!("snapshot-{}.tmp", next));
+        let mut file = io::BufWriter::new(File::create(&tmp)?);
+        self.tree.save_snapshot(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(&tmp, snapshot_path(&self.dir, next))?;
+
+        let wal = File::create(wal_path(&self.dir, next))?;
+        wal.sync_all()?;
+        sync_dir(&self.dir)?;
+
+        self.wal = wal;
+        self.generation = next;
+        self.unsynced = 0;
+        self.failed = false;
+        remove_older(&self.dir, next)
+    }
+
+    fn append(&mut self, op: u8, key: &T) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other(
+                "write-ahead log is torn, compact to start a new one",
+            ));
+        }
+        let mut record = vec![op, 0, 0, 0, 0];
+        key.encode(&mut record)?;
+        let len = (record.len() - 5) as u32;
+        record[1..5].copy_from_slice(&len.to_le_bytes());
+        let crc = crate::snapshot::crc32(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+
+        let start = self.wal.stream_position()?;
+        if let Err(e) = self.log(&record) {
+            let cut = self.wal.set_len(start);
+            if cut
+                .and_then(|()| self.wal.seek(SeekFrom::Start(start)))
+                .is_err()
+            {
+                self.failed = true;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, record: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(n) = self.short_write.take() {
+            self.wal.write_all(&record[..n])?;
+            return Err(io::Error::other("short write"));
+        }
+        self.wal.write_all(record)?;
+
+        self.unsynced += 1;
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Batched(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/*
+ * apply every intact record in the log, returning where the last one
+ * ends; anything after it is a torn write (or garbage) and is dropped
+ */
+fn replay<T, S>(tree: &mut RedBlackTree<T, S>, wal: &mut File) -> Result<u64, SnapshotError>
+where
+    T: std::cmp::PartialOrd + SnapshotKey,
+    S: NodeStore<T>,
+{
+    let mut log = Vec::new();
+    wal.read_to_end(&mut log)?;
+
+    let mut good = 0;
+    while let Some(header) = log.get(good..good + 5) {
+        let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let end = good + 5 + len;
+        let record = match log.get(good..end + 4) {
+            Some(record) => record,
+            None => break,
+        };
+        let stored = u32::from_le_bytes([
+            record[5 + len],
+            record[6 + len],
+            record[7 + len],
+            record[8 + len],
+        ]);
+        if stored != crate::snapshot::crc32(&record[..5 + len]) {
+            break;
+        }
+
+        // the checksum held, so an unknown op or undecodable key is real corruption
+        let op = record[0];
+        if op != INSERT && op != DELETE {
+            return Err(SnapshotError::UnknownOp(op));
+        }
+        let mut bytes = &record[5..5 + len];
+        let key = T::decode(&mut bytes)?;
+        if op == INSERT {
+            tree.insert(key);
+        } else {
+            tree.delete(&key);
+        }
+        good = end + 4;
+    }
+    Ok(good as u64)
+}
+
+// only ever our own files: snapshots and logs of older generations, and
+// snapshot-g.tmp left by a compaction that didn't finish
+fn remove_older(dir: &Path, generation: u64) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let stale = match name.to_str() {
+            Some(name) => match name.strip_suffix(".tmp") {
+                Some(tmp) => matches!(generation_of(tmp), Some(("snapshot", _))),
+                None => {
+                    matches!(generation_of(name), Some(("snapshot" | "wal", g)) if g < generation)
+                }
+            },
+            None => false,
+        };
+        if stale {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    sync_dir(dir)
+}
+
+impl<T, S: NodeStore<T>> Drop for DurableTree<T, S> {
+    fn drop(&mut self) {
+        if self.unsynced > 0 {
+            let _ = self.wal.sync_data();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory per test under the system temp dir
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn keys(db: &DurableTree<u32>) -> Vec<u32> {
+        db.tree().iter().copied().collect()
+    }
+
+    #[test]
+    fn test_durable_recovery() {
+        let dir = scratch("recovery");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Always).unwrap();
+            for i in 0..100 {
+                db.insert(i).unwrap();
+            }
+            for i in 0..50 {
+                db.delete(&(i * 2)).unwrap();
+            }
+            db.delete(&1000).unwrap();
+        }
+
+        let db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        db.tree().is_valid(); // will panic if it must
+        assert_eq!(keys(&db), (0..50).map(|i| i * 2 + 1).collect::<Vec<_>>());
+
+        // 150 records of 1 + 4 + 4 + 4 bytes, the missing key wasn't logged
+        assert_eq!(fs::metadata(wal_path(&dir, 0)).unwrap().len(), 150 * 13);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_torn_tail() {
+        let dir = scratch("torn");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Batched(8)).unwrap();
+            for i in 0..10 {
+                db.insert(i).unwrap();
+            }
+        }
+
+        // half a record, as if the process died mid-write
+        let wal = wal_path(&dir, 0);
+        let mut file = OpenOptions::new().append(true).open(&wal).unwrap();
+        file.write_all(&[INSERT, 4, 0, 0, 0, 42]).unwrap();
+        drop(file);
+
+        // and a whole record whose checksum doesn't match
+        let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(keys(&db), (0..10).collect::<Vec<_>>());
+        assert_eq!(fs::metadata(&wal).unwrap().len(), 10 * 13);
+        db.insert(10).unwrap();
+        drop(db);
+
+        let mut bytes = fs::read(&wal).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&wal, &bytes).unwrap();
+
+        let db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Always).unwrap();
+        assert_eq!(keys(&db), (0..10).collect::<Vec<_>>());
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_failed_append() {
+        let dir = scratch("failed");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Always).unwrap();
+            for i in 0..5 {
+                db.insert(i).unwrap();
+            }
+            // torn mid-record, then written whole but not synced
+            db.short_write = Some(6);
+            assert!(db.insert(100).is_err());
+            db.short_write = Some(13);
+            assert!(db.insert(101).is_err());
+            assert_eq!(db.search(&100), None);
+            for i in 5..10 {
+                db.insert(i).unwrap();
+            }
+        }
+
+        // the refused records are gone and everything after them survives
+        let db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(keys(&db), (0..10).collect::<Vec<_>>());
+        assert_eq!(fs::metadata(wal_path(&dir, 0)).unwrap().len(), 10 * 13);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_unknown_op() {
+        let dir = scratch("unknown-op");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+            db.insert(1).unwrap();
+        }
+
+        // a well-formed record with a checksum that holds, but op 7
+        let mut record = vec![7, 4, 0, 0, 0, 2, 0, 0, 0];
+        let crc = crate::snapshot::crc32(&record);
+        record.extend_from_slice(&crc.to_le_bytes());
+        let wal = wal_path(&dir, 0);
+        let mut file = OpenOptions::new().append(true).open(&wal).unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+
+        assert!(matches!(
+            DurableTree::<u32>::open(&dir, SyncPolicy::Never).err(),
+            Some(SnapshotError::UnknownOp(7))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_compact() {
+        let dir = scratch("compact");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+            for i in 0..100 {
+                db.insert(i).unwrap();
+            }
+            db.compact().unwrap();
+            for i in 0..10 {
+                db.delete(&i).unwrap();
+            }
+        }
+        assert!(!wal_path(&dir, 0).exists());
+        assert!(snapshot_path(&dir, 1).exists());
+        assert_eq!(fs::metadata(wal_path(&dir, 1)).unwrap().len(), 10 * 13);
+
+        // a compaction that died after writing its snapshot: the newest one wins
+        let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(keys(&db), (10..100).collect::<Vec<_>>());
+        db.compact().unwrap();
+        fs::write(dir.join("snapshot-3.tmp"), b"partial").unwrap();
+        // files that only look like ours are left alone
+        for name in &["backup-1", "wal-x", "notes.tmp", "wal-1.tmp"] {
+            fs::write(dir.join(name), b"mine").unwrap();
+        }
+        fs::copy(snapshot_path(&dir, 2), snapshot_path(&dir, 1)).unwrap();
+        drop(db);
+
+        let db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(keys(&db), (10..100).collect::<Vec<_>>());
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "backup-1",
+                "notes.tmp",
+                "snapshot-2",
+                "wal-1.tmp",
+                "wal-2",
+                "wal-x"
+            ]
+        );
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_duplicate_keys() {
+        let dir = scratch("duplicates");
+        {
+            let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+            db.insert(1).unwrap();
+            db.insert(1).unwrap();
+            db.insert(2).unwrap();
+            db.compact().unwrap();
+            db.insert(2).unwrap();
+        }
+
+        // the snapshot and the log both bring their copies back
+        let mut db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        db.tree().is_valid(); // will panic if it must
+        assert_eq!(keys(&db), vec![1, 1, 2, 2]);
+        db.delete(&1).unwrap();
+        db.compact().unwrap();
+        drop(db);
+
+        let db: DurableTree<u32> = DurableTree::open(&dir, SyncPolicy::Never).unwrap();
+        assert_eq!(keys(&db), vec![1, 2, 2]);
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
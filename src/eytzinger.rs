@@ -0,0 +1,131 @@
+/*
+ * index math for the Eytzinger layout: a sorted set stored as an implicit
+ * complete binary tree in breadth-first order, 1-based, so the children of
+ * k are 2k and 2k + 1 and a search is a run of predictable shifts and adds
+ *
+ * positions run 1..=n, 0 stands for "none"
+ */
+
+// nodes in the subtree rooted at k
+pub(crate) fn subtree_size(k: usize, n: usize) -> usize {
+    let (mut lo, mut hi, mut size) = (k, k, 0);
+    while lo <= n {
+        size += hi.min(n) - lo + 1;
+        lo *= 2;
+        hi = 2 * hi + 1;
+    }
+    size
+}
+
+// how many keys sort before the one at position k
+pub(crate) fn rank_of(mut k: usize, n: usize) -> usize {
+    let mut rank = subtree_size(2 * k, n);
+    while k > 1 {
+        // a right child comes after its parent and the parent's left subtree
+        if k & 1 == 1 {
+            rank += subtree_size(k - 1, n) + 1;
+        }
+        k >>= 1;
+    }
+    rank
+}
+
+// position of the key with the given rank, which must be below n
+pub(crate) fn position_of(mut rank: usize, n: usize) -> usize {
+    let mut k = 1;
+    loop {
+        let left = subtree_size(2 * k, n);
+        if rank < left {
+            k *= 2;
+        } else if rank == left {
+            return k;
+        } else {
+            rank -= left + 1;
+            k = 2 * k + 1;
+        }
+    }
+}
+
+// the in-order successor of k
+pub(crate) fn next(mut k: usize, n: usize) -> usize {
+    if 2 * k < n {
+        k = 2 * k + 1;
+        while 2 * k <= n {
+            k *= 2;
+        }
+        return k;
+    }
+    // climb while k is a right child, then once more
+    k >>= k.trailing_ones();
+    k >> 1
+}
+
+// the in-order predecessor of k
+pub(crate) fn prev(mut k: usize, n: usize) -> usize {
+    if 2 * k <= n {
+        k *= 2;
+        while 2 * k < n {
+            k = 2 * k + 1;
+        }
+        return k;
+    }
+    // climb while k is a left child, then once more
+    k >>= k.trailing_zeros();
+    k >> 1
+}
+
+/*
+ * first position whose key isn't `less`, 0 if every key is; the descent
+ * never branches on the comparison, and the trailing ones of the final k
+ * count the right turns taken since the answer
+ */
+pub(crate) fn lower_bound<F: Fn(usize) -> bool>(n: usize, less: F) -> usize {
+    let mut k = 1;
+    while k <= n {
+        k = 2 * k + less(k) as usize;
+    }
+    k >> (k.trailing_ones() + 1)
+}
+
+// position of every rank, in rank order
+pub(crate) fn positions(n: usize) -> Vec<usize> {
+    let mut order = Vec::with_capacity(n);
+    if n > 0 {
+        let mut k = position_of(0, n);
+        while k != 0 {
+            order.push(k);
+            k = next(k, n);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eytzinger_math() {
+        for n in 0..100 {
+            let order = positions(n);
+            assert_eq!(order.len(), n);
+            for (rank, k) in order.iter().enumerate() {
+                assert_eq!(rank_of(*k, n), rank);
+                assert_eq!(position_of(rank, n), *k);
+                let before = if rank == 0 { 0 } else { order[rank - 1] };
+                assert_eq!(prev(*k, n), before);
+            }
+
+            // keys are their own ranks, so the lower bound of x is rank x
+            let mut keys = vec![0; n + 1];
+            for (rank, k) in order.iter().enumerate() {
+                keys[*k] = rank;
+            }
+            for x in 0..=n {
+                let k = lower_bound(n, |k| keys[k] < x);
+                let expected = order.get(x).copied().unwrap_or(0);
+                assert_eq!(k, expected);
+            }
+        }
+    }
+}
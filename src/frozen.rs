@@ -0,0 +1,533 @@
+use crate::eytzinger;
+use crate::paged::PageKey;
+use crate::snapshot::crc32;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::{error, fmt};
+
+/*
+ * image layout, all integers little-endian:
+ *
+ *     magic     4 bytes  "RBTF"
+ *     version   u16
+ *     layout    u8       FrozenLayout
+ *     reserved  u8
+ *     key size  u32      PageKey::SIZE
+ *     root      u32      slab layout only
+ *     count     u64
+ *     crc       u32      CRC-32 (IEEE) of the header before it
+ *     reserved  4 bytes
+ *     nodes     count records
+ *
+ * slab records are [left u32][right u32][key], numbered in key order so a
+ * node's index is also its rank, with NO_NODE for a missing child;
+ * eytzinger records are just keys, position k of the layout at index k - 1
+ */
+const MAGIC: [u8; 4] = *b"RBTF";
+const VERSION: u16 = 1;
+const HEADER: usize = 32;
+const CRC_AT: usize = 24;
+const NO_NODE: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrozenLayout {
+    // the tree's own shape, compacted into u32 links
+    Slab,
+    // a breadth-first implicit tree, no links at all
+    Eytzinger,
+}
+
+#[derive(Debug)]
+pub enum FrozenError {
+    Io(io::Error),
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownLayout(u8),
+    // the image was frozen from keys of another size than T's
+    KeySize { expected: usize, found: usize },
+    // the header doesn't match its checksum; the nodes aren't covered
+    ChecksumMismatch { stored: u32, computed: u32 },
+    // a slab link points past the last node
+    BadLink { node: usize },
+}
+
+impl fmt::Display for FrozenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrozenError::Io(e) => write!(f, "frozen image i/o failed: {}", e),
+            FrozenError::Truncated => write!(f, "frozen image is truncated"),
+            FrozenError::BadMagic => write!(f, "not a frozen image (bad magic number)"),
+            FrozenError::UnsupportedVersion(v) => {
+                write!(f, "unsupported frozen image version {}", v)
+            }
+            FrozenError::UnknownLayout(l) => write!(f, "unknown frozen layout {}", l),
+            FrozenError::KeySize { expected, found } => write!(
+                f,
+                "frozen image holds {}-byte keys, expected {}",
+                found, expected
+            ),
+            FrozenError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "frozen header checksum mismatch: stored {:08x}, computed {:08x}",
+                stored, computed
+            ),
+            FrozenError::BadLink { node } => write!(f, "node {} links out of the image", node),
+        }
+    }
+}
+
+impl error::Error for FrozenError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FrozenError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrozenError {
+    fn from(e: io::Error) -> FrozenError {
+        FrozenError::Io(e)
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd + PageKey,
+    S: NodeStore<T>,
+{
+    // write a flat, pointer-free image of the tree for FrozenTree::open
+    pub fn freeze_to_file<P: AsRef<Path>>(&self, path: P, layout: FrozenLayout) -> io::Result<()> {
+        assert!(self.len < NO_NODE as usize, "too many keys for u32 links");
+        let mut w = BufWriter::new(File::create(path)?);
+        let record = match layout {
+            FrozenLayout::Slab => 8 + T::SIZE,
+            FrozenLayout::Eytzinger => T::SIZE,
+        };
+
+        let mut nodes = vec![0; self.len * record];
+        let mut root = NO_NODE;
+        match layout {
+            FrozenLayout::Slab => {
+                let mut next = 0;
+                root = self.freeze_node(self.root, &mut next, &mut nodes);
+            }
+            FrozenLayout::Eytzinger => {
+                let positions = eytzinger::positions(self.len);
+                for (key, k) in self.iter().zip(positions) {
+                    key.write(&mut nodes[(k - 1) * record..k * record]);
+                }
+            }
+        }
+
+        let mut header = Vec::with_capacity(HEADER);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&[layout as u8, 0]);
+        header.extend_from_slice(&(T::SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&root.to_le_bytes());
+        header.extend_from_slice(&(self.len as u64).to_le_bytes());
+        let crc = crc32(&header);
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&[0; 4]);
+        w.write_all(&header)?;
+        w.write_all(&nodes)?;
+        w.into_inner()?.sync_all()
+    }
+
+    // lays out x's subtree in key order, returning the index x got
+    fn freeze_node(&self, x: S::Link, next: &mut usize, nodes: &mut [u8]) -> u32 {
+        if x == self.store.nil() {
+            return NO_NODE;
+        }
+        let record = 8 + T::SIZE;
+        let left = self.freeze_node(self.store.child(x, 0), next, nodes);
+        let i = *next;
+        *next += 1;
+        let right = self.freeze_node(self.store.child(x, 1), next, nodes);
+
+        let out = &mut nodes[i * record..(i + 1) * record];
+        out[0..4].copy_from_slice(&left.to_le_bytes());
+        out[4..8].copy_from_slice(&right.to_le_bytes());
+        self.store.with_key(x, |key| key.write(&mut out[8..]));
+        i as u32
+    }
+}
+
+/*
+ * a frozen image mapped straight from its file: queries read the nodes in
+ * place, nothing is deserialized up front, and every process mapping the
+ * same file shares its pages
+ *
+ * opening is O(1) whatever the size: it checks the header against its
+ * checksum and the file against the length the header gives, and nothing
+ * else. slab links are bounds-checked as queries follow them, and a query
+ * that meets one pointing out of the image panics
+ *
+ * the file must not be changed while it's mapped
+ */
+pub struct FrozenTree<T> {
+    map: Mmap,
+    layout: FrozenLayout,
+    root: u32,
+    len: usize,
+    _key: PhantomData<T>,
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+impl<T: PartialOrd + PageKey> FrozenTree<T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FrozenTree<T>, FrozenError> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER {
+            return Err(FrozenError::Truncated);
+        }
+        if map[0..4] != MAGIC {
+            return Err(FrozenError::BadMagic);
+        }
+        let version = u16::from_le_bytes([map[4], map[5]]);
+        if version != VERSION {
+            return Err(FrozenError::UnsupportedVersion(version));
+        }
+        let stored = u32_at(&map, CRC_AT);
+        let computed = crc32(&map[..CRC_AT]);
+        if stored != computed {
+            return Err(FrozenError::ChecksumMismatch { stored, computed });
+        }
+        let layout = match map[6] {
+            0 => FrozenLayout::Slab,
+            1 => FrozenLayout::Eytzinger,
+            other => return Err(FrozenError::UnknownLayout(other)),
+        };
+        let key_size = u32_at(&map, 8) as usize;
+        if key_size != T::SIZE {
+            return Err(FrozenError::KeySize {
+                expected: T::SIZE,
+                found: key_size,
+            });
+        }
+        let root = u32_at(&map, 12);
+        let mut len = [0; 8];
+        len.copy_from_slice(&map[16..24]);
+        let len = u64::from_le_bytes(len);
+
+        let mut frozen = FrozenTree {
+            map,
+            layout,
+            root,
+            len: 0,
+            _key: PhantomData,
+        };
+        let size = (len as u128) * frozen.record() as u128 + HEADER as u128;
+        if (frozen.map.len() as u128) < size {
+            return Err(FrozenError::Truncated);
+        }
+        frozen.len = len as usize;
+
+        // the other links are checked when a query follows them
+        if layout == FrozenLayout::Slab
+            && (!frozen.in_bounds(root) || (root == NO_NODE) != (frozen.len == 0))
+        {
+            return Err(FrozenError::BadLink {
+                node: root as usize,
+            });
+        }
+        Ok(frozen)
+    }
+
+    pub fn layout(&self) -> FrozenLayout {
+        self.layout
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, key: &T) -> bool {
+        self.search(key).is_some()
+    }
+
+    pub fn search(&self, key: &T) -> Option<T> {
+        let rank = self.lower(key, false);
+        if rank == self.len {
+            return None;
+        }
+        let found = self.key_of_rank(rank);
+        if found == *key {
+            Some(found)
+        } else {
+            None
+        }
+    }
+
+    // number of keys smaller than key
+    pub fn rank(&self, key: &T) -> usize {
+        self.lower(key, false)
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> FrozenRange<'_, T> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.lower(key, false),
+            Bound::Excluded(key) => self.lower(key, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.lower(key, true),
+            Bound::Excluded(key) => self.lower(key, false),
+            Bound::Unbounded => self.len,
+        };
+        let remaining = end.saturating_sub(start);
+        let position = match self.layout {
+            FrozenLayout::Slab => start,
+            _ if remaining == 0 => 0,
+            FrozenLayout::Eytzinger => eytzinger::position_of(start, self.len),
+        };
+        FrozenRange {
+            tree: self,
+            position,
+            remaining,
+        }
+    }
+
+    fn record(&self) -> usize {
+        match self.layout {
+            FrozenLayout::Slab => 8 + T::SIZE,
+            FrozenLayout::Eytzinger => T::SIZE,
+        }
+    }
+
+    // for the slab layout this is node i, for eytzinger position i + 1
+    fn key_at(&self, i: usize) -> T {
+        let record = self.record();
+        let at = HEADER + i * record + record - T::SIZE;
+        T::read(&self.map[at..at + T::SIZE])
+    }
+
+    fn in_bounds(&self, link: u32) -> bool {
+        link == NO_NODE || (link as usize) < self.len
+    }
+
+    // panics rather than walk off the image, see FrozenTree
+    fn link(&self, i: usize, dir: usize) -> u32 {
+        let link = u32_at(&self.map, HEADER + i * self.record() + 4 * dir);
+        if !self.in_bounds(link) {
+            panic!("{}", FrozenError::BadLink { node: i });
+        }
+        link
+    }
+
+    fn key_of_rank(&self, rank: usize) -> T {
+        match self.layout {
+            FrozenLayout::Slab => self.key_at(rank),
+            FrozenLayout::Eytzinger => self.key_at(eytzinger::position_of(rank, self.len) - 1),
+        }
+    }
+
+    // rank of the first key not below key (strict: not below or equal to it)
+    fn lower(&self, key: &T, strict: bool) -> usize {
+        let before = |k: &T| if strict { *k <= *key } else { *k < *key };
+        match self.layout {
+            FrozenLayout::Slab => {
+                // indices are ranks, so the last left turn is the answer;
+                // a valid tree is never deeper than its node count
+                let mut best = self.len;
+                let mut x = self.root;
+                for _ in 0..self.len {
+                    if x == NO_NODE {
+                        break;
+                    }
+                    let dir = if before(&self.key_at(x as usize)) {
+                        1
+                    } else {
+                        best = x as usize;
+                        0
+                    };
+                    x = self.link(x as usize, dir);
+                }
+                best
+            }
+            FrozenLayout::Eytzinger => {
+                let k = eytzinger::lower_bound(self.len, |k| before(&self.key_at(k - 1)));
+                if k == 0 {
+                    self.len
+                } else {
+                    eytzinger::rank_of(k, self.len)
+                }
+            }
+        }
+    }
+}
+
+// keys of a FrozenTree::range, in ascending order
+pub struct FrozenRange<'a, T> {
+    tree: &'a FrozenTree<T>,
+    // slab index or eytzinger position of the next key
+    position: usize,
+    remaining: usize,
+}
+
+impl<'a, T: PartialOrd + PageKey> Iterator for FrozenRange<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let tree = self.tree;
+        match tree.layout {
+            FrozenLayout::Slab => {
+                self.position += 1;
+                Some(tree.key_at(self.position - 1))
+            }
+            FrozenLayout::Eytzinger => {
+                let key = tree.key_at(self.position - 1);
+                self.position = eytzinger::next(self.position, tree.len);
+                Some(key)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_frozen_queries() {
+        let mut rb: SlabRedBlack<u32> = SlabRedBlack::new();
+        for i in 0..1000 {
+            rb.insert(i * 7919 % 1000 * 3);
+        }
+
+        for layout in &[FrozenLayout::Slab, FrozenLayout::Eytzinger] {
+            let path = scratch(&format!("frozen-{:?}", layout));
+            rb.freeze_to_file(&path, *layout).unwrap();
+            let frozen: FrozenTree<u32> = FrozenTree::open(&path).unwrap();
+            assert_eq!(frozen.layout(), *layout);
+            assert_eq!(frozen.len(), 1000);
+
+            for x in 0..3003 {
+                assert_eq!(frozen.contains(&x), x % 3 == 0 && x < 3000);
+                assert_eq!(frozen.rank(&x), (x as usize).div_ceil(3).min(1000));
+            }
+            assert_eq!(frozen.search(&27), Some(27));
+            assert_eq!(
+                frozen.range(10..=21).collect::<Vec<_>>(),
+                vec![12, 15, 18, 21]
+            );
+            assert_eq!(
+                frozen
+                    .range((Bound::Excluded(12), Bound::Excluded(18)))
+                    .collect::<Vec<_>>(),
+                vec![15]
+            );
+            assert_eq!(
+                frozen.range(2990..).collect::<Vec<_>>(),
+                vec![2991, 2994, 2997]
+            );
+            assert_eq!(frozen.range(..).count(), 1000);
+            assert_eq!(
+                frozen
+                    .range((Bound::Included(20), Bound::Excluded(10)))
+                    .count(),
+                0
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_frozen_empty_and_pointer() {
+        let path = scratch("frozen-empty");
+        let rb: PointerRedBlack<i64> = PointerRedBlack::new();
+        rb.freeze_to_file(&path, FrozenLayout::Slab).unwrap();
+        let frozen: FrozenTree<i64> = FrozenTree::open(&path).unwrap();
+        assert!(frozen.is_empty());
+        assert!(!frozen.contains(&1));
+        assert_eq!(frozen.range(..).next(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_frozen_rejects() {
+        let path = scratch("frozen-bad");
+        let rb: SlabRedBlack<u32> = RedBlackTree::from_sorted(0..100).unwrap();
+        rb.freeze_to_file(&path, FrozenLayout::Slab).unwrap();
+
+        assert!(matches!(
+            FrozenTree::<u64>::open(&path).err(),
+            Some(FrozenError::KeySize {
+                expected: 8,
+                found: 4
+            })
+        ));
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            FrozenTree::<u32>::open(&path).err(),
+            Some(FrozenError::Truncated)
+        ));
+
+        bytes.push(0);
+        bytes[16] = 99;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            FrozenTree::<u32>::open(&path).err(),
+            Some(FrozenError::ChecksumMismatch { .. })
+        ));
+
+        bytes[0] = b'X';
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            FrozenTree::<u32>::open(&path).err(),
+            Some(FrozenError::BadMagic)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "node 0 links out of the image")]
+    fn test_frozen_bad_link() {
+        let path = scratch("frozen-link");
+        let rb: SlabRedBlack<u32> = RedBlackTree::from_sorted(0..100).unwrap();
+        rb.freeze_to_file(&path, FrozenLayout::Slab).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER] = 200;
+        std::fs::write(&path, &bytes).unwrap();
+
+        // only the header is checked up front; node 0 is met looking for 0
+        let frozen: FrozenTree<u32> = FrozenTree::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(frozen.contains(&50));
+        frozen.contains(&0);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::eytzinger;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::ops::{Bound, RangeBounds};
+
+/*
+ * an immutable sorted set in Eytzinger order: the keys of a complete
+ * binary tree laid out breadth-first, position k at keys[k - 1]
+ *
+ * a search touches the same few top levels every time, which stay in
+ * cache, and each step picks the next position with arithmetic instead of
+ * a branch; 16k..16k + 15, the descendants four levels down, sit next to
+ * each other, so they're prefetched a few steps ahead
+ */
+pub struct FrozenSet<T> {
+    keys: Vec<T>,
+}
+
+#[inline(always)]
+fn prefetch<T>(key: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch(key as *const i8, _MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = key;
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd + Clone,
+    S: NodeStore<T>,
+{
+    // copy the keys into a FrozenSet, keeping one of each run of equal keys
+    pub fn freeze(&self) -> FrozenSet<T> {
+        let mut sorted: Vec<T> = Vec::with_capacity(self.len);
+        for key in self.iter() {
+            if sorted.last() != Some(key) {
+                sorted.push(key.clone());
+            }
+        }
+        FrozenSet::from_sorted_vec(sorted)
+    }
+}
+
+impl<T: PartialOrd> FrozenSet<T> {
+    fn from_sorted_vec(sorted: Vec<T>) -> FrozenSet<T> {
+        let n = sorted.len();
+        let mut slots: Vec<Option<T>> = (0..n).map(|_| None).collect();
+        for (key, k) in sorted.into_iter().zip(eytzinger::positions(n)) {
+            slots[k - 1] = Some(key);
+        }
+        FrozenSet {
+            keys: slots.into_iter().map(Option::unwrap).collect(),
+        }
+    }
+
+    // back into a mutable tree, on any backend, in O(n)
+    pub fn thaw<S: NodeStore<T> + Default>(self) -> RedBlackTree<T, S> {
+        let n = self.keys.len();
+        let mut slots: Vec<Option<T>> = self.keys.into_iter().map(Some).collect();
+        let sorted = eytzinger::positions(n)
+            .into_iter()
+            .map(|k| slots[k - 1].take().unwrap());
+        match RedBlackTree::from_sorted(sorted) {
+            Ok(rb) => rb,
+            Err(_) => unreachable!("a frozen set is strictly ascending"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &T) -> bool {
+        self.ceiling(key) == Some(key)
+    }
+
+    // smallest key >= key
+    pub fn ceiling(&self, key: &T) -> Option<&T> {
+        self.get(self.lower(key, false))
+    }
+
+    // largest key <= key
+    pub fn floor(&self, key: &T) -> Option<&T> {
+        let n = self.len();
+        let above = self.lower(key, true);
+        let k = if above == 0 {
+            // every key is <= key, so the answer is the largest one
+            if n == 0 {
+                0
+            } else {
+                eytzinger::position_of(n - 1, n)
+            }
+        } else {
+            eytzinger::prev(above, n)
+        };
+        self.get(k)
+    }
+
+    pub fn iter(&self) -> Range<'_, T> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T> {
+        let n = self.len();
+        let rank = |k: usize| if k == 0 { n } else { eytzinger::rank_of(k, n) };
+        let (k, start) = match range.start_bound() {
+            Bound::Included(key) => {
+                let k = self.lower(key, false);
+                (k, rank(k))
+            }
+            Bound::Excluded(key) => {
+                let k = self.lower(key, true);
+                (k, rank(k))
+            }
+            Bound::Unbounded if n == 0 => (0, 0),
+            Bound::Unbounded => (eytzinger::position_of(0, n), 0),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => rank(self.lower(key, true)),
+            Bound::Excluded(key) => rank(self.lower(key, false)),
+            Bound::Unbounded => n,
+        };
+        Range {
+            set: self,
+            k,
+            remaining: end.saturating_sub(start),
+        }
+    }
+
+    fn get(&self, k: usize) -> Option<&T> {
+        if k == 0 {
+            None
+        } else {
+            Some(&self.keys[k - 1])
+        }
+    }
+
+    // position of the first key >= key (> key if strict), 0 for none
+    fn lower(&self, key: &T, strict: bool) -> usize {
+        let n = self.keys.len();
+        let base = self.keys.as_ptr();
+        let mut k = 1;
+        while k <= n {
+            prefetch(base.wrapping_add(16 * k - 1));
+            let x = &self.keys[k - 1];
+            let before = if strict { *x <= *key } else { *x < *key };
+            k = 2 * k + before as usize;
+        }
+        k >> (k.trailing_ones() + 1)
+    }
+}
+
+// keys of a FrozenSet in ascending order
+pub struct Range<'a, T> {
+    set: &'a FrozenSet<T>,
+    k: usize,
+    remaining: usize,
+}
+
+impl<'a, T: PartialOrd> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let key = &self.set.keys[self.k - 1];
+        self.k = eytzinger::next(self.k, self.set.len());
+        Some(key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    #[test]
+    fn test_frozen_set() {
+        let mut rb: PointerRedBlack<i32> = PointerRedBlack::new();
+        for i in 0..500 {
+            rb.insert(i * 7919 % 500 * 2);
+        }
+        rb.insert(10); // duplicates collapse into one key
+        let set = rb.freeze();
+        assert_eq!(set.len(), 500);
+
+        for x in -1..1001 {
+            assert_eq!(set.contains(&x), x >= 0 && x % 2 == 0 && x < 1000);
+            let floor = if x < 0 {
+                None
+            } else {
+                Some((x / 2 * 2).min(998))
+            };
+            assert_eq!(set.floor(&x).copied(), floor);
+            let ceiling = if x > 998 { None } else { Some((x + 1) / 2 * 2) };
+            assert_eq!(set.ceiling(&x).copied(), ceiling.map(|c: i32| c.max(0)));
+        }
+
+        assert_eq!(
+            set.range(5..=12).copied().collect::<Vec<_>>(),
+            vec![6, 8, 10, 12]
+        );
+        assert_eq!(set.range(..4).copied().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(
+            set.range(995..).copied().collect::<Vec<_>>(),
+            vec![996, 998]
+        );
+        assert_eq!(set.iter().count(), 500);
+        assert!(set.iter().zip(set.iter().skip(1)).all(|(a, b)| a < b));
+    }
+
+    #[test]
+    fn test_thaw() {
+        let rb: SlabRedBlack<u32> = RedBlackTree::from_sorted(0..100).unwrap();
+        let set = rb.freeze();
+        let mut back: PointerRedBlack<u32> = set.thaw();
+        back.is_valid(); // will panic if it must
+        back.insert(100);
+        assert_eq!(
+            back.iter().copied().collect::<Vec<_>>(),
+            (0..=100).collect::<Vec<_>>()
+        );
+
+        let empty: SlabRedBlack<u32> = SlabRedBlack::new();
+        let set = empty.freeze();
+        assert!(set.is_empty());
+        assert_eq!(set.floor(&1), None);
+        assert_eq!(set.iter().next(), None);
+    }
+}
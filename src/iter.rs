@@ -0,0 +1,226 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::ops::{Bound, RangeBounds, RangeFull};
+
+// keys in ascending order
+pub struct Iter<'a, T, S: NodeStore<T>> {
+    links: Links<'a, T, S>,
+}
+
+impl<'a, T, S: NodeStore<T>> Iterator for Iter<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let x = self.links.next()?;
+        Some(self.links.tree.store.key(x))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.links.size_hint()
+    }
+}
+
+impl<'a, T, S: NodeStore<T>> ExactSizeIterator for Iter<'a, T, S> {}
+
+/*
+ * the nodes in key order, with the path down to the next one on a stack;
+ * for walks that only look at each key in passing (see with_key)
+ */
+pub(crate) struct Links<'a, T, S: NodeStore<T>> {
+    tree: &'a RedBlackTree<T, S>,
+    stack: Vec<S::Link>,
+    remaining: usize,
+}
+
+impl<'a, T, S: NodeStore<T>> Links<'a, T, S> {
+    fn push_left(&mut self, mut x: S::Link) {
+        let nil = self.tree.store.nil();
+        while x != nil {
+            self.stack.push(x);
+            x = self.tree.store.child(x, 0);
+        }
+    }
+}
+
+impl<'a, T, S: NodeStore<T>> Iterator for Links<'a, T, S> {
+    type Item = S::Link;
+
+    fn next(&mut self) -> Option<S::Link> {
+        let x = self.stack.pop()?;
+        self.push_left(self.tree.store.child(x, 1));
+        self.remaining -= 1;
+        Some(x)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    pub fn iter(&self) -> Iter<'_, T, S> {
+        Iter {
+            links: self.links(),
+        }
+    }
+
+    pub(crate) fn links(&self) -> Links<'_, T, S> {
+        let mut links = Links {
+            tree: self,
+            stack: Vec::new(),
+            remaining: self.len,
+        };
+        links.push_left(self.root);
+        links
+    }
+}
+
+// keys within a range, ascending; the bounds are kept to stop at the end
+pub struct Range<'a, T, S: NodeStore<T>, R> {
+    tree: &'a RedBlackTree<T, S>,
+    stack: Vec<S::Link>,
+    range: R,
+}
+
+impl<'a, T, S, R> Iterator for Range<'a, T, S, R>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+    R: RangeBounds<T>,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let store = &self.tree.store;
+        let x = self.stack.pop()?;
+        let key = store.key(x);
+        let within = match self.range.end_bound() {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        if !within {
+            self.stack.clear();
+            return None;
+        }
+        let mut c = store.child(x, 1);
+        while c != store.nil() {
+            self.stack.push(c);
+            c = store.child(c, 0);
+        }
+        Some(key)
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    // keys within range in ascending order, e.g. tree.range(10..20)
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T, S, R> {
+        let before = |key: &T| match range.start_bound() {
+            Bound::Included(start) => key < start,
+            Bound::Excluded(start) => key <= start,
+            Bound::Unbounded => false,
+        };
+        let stack = self.seek(before);
+        Range {
+            tree: self,
+            stack,
+            range,
+        }
+    }
+
+    // like range, with the start on something other than a whole key
+    pub(crate) fn range_by<F: Fn(&T) -> bool>(&self, before: F) -> Range<'_, T, S, RangeFull> {
+        Range {
+            tree: self,
+            stack: self.seek(before),
+            range: ..,
+        }
+    }
+
+    // the path to the first key that isn't `before`, skipping those that are
+    fn seek<F: Fn(&T) -> bool>(&self, before: F) -> Vec<S::Link> {
+        let mut stack = Vec::new();
+        let mut x = self.root;
+        while x != self.store.nil() {
+            if self.store.with_key(x, &before) {
+                x = self.store.child(x, 1);
+            } else {
+                stack.push(x);
+                x = self.store.child(x, 0);
+            }
+        }
+        stack
+    }
+}
+
+impl<'a, T, S> IntoIterator for &'a RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, S>;
+
+    fn into_iter(self) -> Iter<'a, T, S> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    #[test]
+    fn test_iter_in_order() {
+        let mut slab: SlabRedBlack<u32> = SlabRedBlack::new();
+        let mut pointer: PointerRedBlack<u32> = PointerRedBlack::new();
+        assert_eq!(slab.iter().next(), None);
+
+        for i in 0..1000 {
+            slab.insert(i * 7919 % 1000);
+            pointer.insert(i * 7919 % 1000);
+        }
+        for i in (0..1000).step_by(2) {
+            slab.delete(&i);
+            pointer.delete(&i);
+        }
+
+        let expected: Vec<u32> = (1..1000).step_by(2).collect();
+        assert_eq!(slab.iter().len(), 500);
+        assert_eq!(slab.iter().copied().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            (&pointer).into_iter().copied().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let mut rb: SlabRedBlack<u32> = SlabRedBlack::new();
+        assert_eq!(rb.range(..).next(), None);
+        for i in 0..100 {
+            rb.insert(i * 37 % 100 * 2);
+        }
+        rb.insert(10);
+
+        let keys = |v: Vec<&u32>| v.into_iter().copied().collect::<Vec<_>>();
+        assert_eq!(keys(rb.range(5..=12).collect()), vec![6, 8, 10, 10, 12]);
+        assert_eq!(keys(rb.range(5..12).collect()), vec![6, 8, 10, 10]);
+        assert_eq!(keys(rb.range(..4).collect()), vec![0, 2]);
+        assert_eq!(keys(rb.range(195..).collect()), vec![196, 198]);
+        assert_eq!(rb.range(..).count(), 101);
+        assert_eq!(rb.range(300..).next(), None);
+        let excluded = (std::ops::Bound::Excluded(10), std::ops::Bound::Included(14));
+        assert_eq!(keys(rb.range(excluded).collect()), vec![12, 14]);
+    }
+}
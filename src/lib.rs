@@ -1,3 +1,33 @@
+#[macro_use]
+mod store;
+
+pub mod arena;
+pub mod bulk;
+pub mod concurrent;
+pub mod dot;
+pub mod durable;
+mod eytzinger;
+pub mod frozen;
+pub mod frozenset;
+pub mod iter;
+pub mod memory;
+pub mod observe;
+pub mod paged;
+pub mod parallel;
+pub mod persistent;
 pub mod pointer;
+pub mod pretty;
 pub mod redblack;
+pub mod seqlock;
+#[cfg(feature = "serde")]
+pub mod serial;
+pub mod sharded;
 pub mod slab;
+pub mod snapshot;
+pub mod soa;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod transaction;
+pub mod tree;
+pub mod validate;
+pub mod versioned;
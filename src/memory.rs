@@ -0,0 +1,98 @@
+use std::collections::TryReserveError;
+use std::{error, fmt, mem};
+
+/*
+ * heap bytes a key owns beyond its own size_of, so that memory_usage can
+ * account for String / Vec keys and not just the nodes holding them
+ */
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+no_heap!(u8, u16, u32, u64, u128, usize);
+no_heap!(i8, i16, i32, i64, i128, isize);
+no_heap!(f32, f64, bool, char, ());
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+// bytes held by a tree, as reported by RedBlackTree::memory_usage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    // slots holding live nodes, the nil sentinel included
+    pub nodes: usize,
+    // allocated slots with no node in them
+    pub vacant: usize,
+    // heap allocations owned by the keys themselves
+    pub keys: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.nodes + self.vacant + self.keys
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReserveError {
+    // more nodes than the store can address (index width or isize::MAX bytes)
+    CapacityOverflow,
+    // the allocator refused the request
+    Alloc(TryReserveError),
+}
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReserveError::CapacityOverflow => write!(f, "capacity overflow"),
+            ReserveError::Alloc(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for ReserveError {}
+
+impl From<TryReserveError> for ReserveError {
+    fn from(e: TryReserveError) -> ReserveError {
+        ReserveError::Alloc(e)
+    }
+}
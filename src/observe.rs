@@ -0,0 +1,158 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+
+/*
+ * one step of rebalancing, as the CLRS insert/delete fixups take it
+ *
+ * the tree emits these with N = &T, the key of the node the step acts on;
+ * cases are numbered as in CLRS, and a case event comes before the
+ * rotations and recolorings that make it up
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeEvent<N> {
+    // node goes down in direction dir (0 = left rotation), its child comes up
+    Rotate { node: N, dir: usize },
+    Recolor { node: N, red: bool },
+    InsertCase(u8),
+    DeleteCase(u8),
+    // node is unlinked from the tree by delete; it's the deleted key's
+    // successor when the deleted node had two children
+    Splice { node: N },
+}
+
+impl<N> TreeEvent<N> {
+    pub fn map<M, F: FnOnce(N) -> M>(self, f: F) -> TreeEvent<M> {
+        match self {
+            TreeEvent::Rotate { node, dir } => TreeEvent::Rotate { node: f(node), dir },
+            TreeEvent::Recolor { node, red } => TreeEvent::Recolor { node: f(node), red },
+            TreeEvent::InsertCase(case) => TreeEvent::InsertCase(case),
+            TreeEvent::DeleteCase(case) => TreeEvent::DeleteCase(case),
+            TreeEvent::Splice { node } => TreeEvent::Splice { node: f(node) },
+        }
+    }
+}
+
+pub(crate) type Observer<T> = dyn FnMut(TreeEvent<&T>) + Send + Sync;
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    // observer is called synchronously for every event, replacing any earlier one
+    pub fn set_observer<F>(&mut self, observer: F)
+    where
+        F: FnMut(TreeEvent<&T>) + Send + Sync + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    pub(crate) fn observe(&mut self, event: TreeEvent<S::Link>) {
+        if let Some(observer) = &mut self.observer {
+            let store = &self.store;
+            observer(event.map(|x| store.key(x)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+    use std::sync::{Arc, Mutex};
+
+    fn record<S: NodeStore<i32>>(rb: &mut RedBlackTree<i32, S>) -> Arc<Mutex<Vec<TreeEvent<i32>>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        rb.set_observer(move |event| sink.lock().unwrap().push(event.map(|key| *key)));
+        events
+    }
+
+    #[test]
+    fn test_insert_events() {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        let events = record(&mut rb);
+        rb.insert(1);
+        rb.insert(2);
+        rb.insert(3);
+
+        // the root is blackened, then 1-2-3 is a right-leaning line: case 3
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                TreeEvent::Recolor {
+                    node: 1,
+                    red: false
+                },
+                TreeEvent::InsertCase(3),
+                TreeEvent::Recolor {
+                    node: 2,
+                    red: false
+                },
+                TreeEvent::Recolor { node: 1, red: true },
+                TreeEvent::Rotate { node: 1, dir: 0 },
+            ]
+        );
+
+        rb.clear_observer();
+        rb.insert(4);
+        assert_eq!(events.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_delete_events() {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        for i in 1..=3 {
+            rb.insert(i);
+        }
+        let events = record(&mut rb);
+
+        // 2 has two children, so its successor 3 is spliced out in its place,
+        // then 3 goes for real and its red child takes over as the black root
+        rb.delete(&2);
+        rb.delete(&3);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                TreeEvent::Splice { node: 3 },
+                TreeEvent::Splice { node: 3 },
+                TreeEvent::Recolor {
+                    node: 1,
+                    red: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let mut slab: SlabRedBlack<i32> = SlabRedBlack::new();
+        let mut pointer: PointerRedBlack<i32> = PointerRedBlack::new();
+        let slab_events = record(&mut slab);
+        let pointer_events = record(&mut pointer);
+
+        for i in 0..2000 {
+            slab.insert(i * 7919 % 2003);
+            pointer.insert(i * 7919 % 2003);
+        }
+        for i in 0..1000 {
+            slab.delete(&(i * 31 % 2003));
+            pointer.delete(&(i * 31 % 2003));
+        }
+
+        let slab_events = slab_events.lock().unwrap();
+        assert_eq!(*slab_events, *pointer_events.lock().unwrap());
+        for case in 1..=4 {
+            assert!(slab_events.contains(&TreeEvent::DeleteCase(case)));
+        }
+        for case in 1..=3 {
+            assert!(slab_events.contains(&TreeEvent::InsertCase(case)));
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::{fmt, mem};
+
+/*
+ * a key of fixed encoded size, so that node records tile a page evenly
+ */
+pub trait PageKey: Sized {
+    const SIZE: usize;
+
+    fn write(&self, out: &mut [u8]);
+    fn read(bytes: &[u8]) -> Self;
+}
+
+macro_rules! page_int {
+    ($($t:ty),*) => {
+        $(
+            impl PageKey for $t {
+                const SIZE: usize = mem::size_of::<$t>();
+
+                fn write(&self, out: &mut [u8]) {
+                    out.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn read(bytes: &[u8]) -> $t {
+                    let mut le = [0; mem::size_of::<$t>()];
+                    le.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(le)
+                }
+            }
+        )*
+    };
+}
+
+page_int!(u8, u16, u32, u64, u128, usize);
+page_int!(i8, i16, i32, i64, i128, isize);
+
+// a node's address: which page of the file, and which record in it
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PageAddr {
+    pub page: u32,
+    pub slot: u16,
+}
+
+impl fmt::Debug for PageAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.page, self.slot)
+    }
+}
+
+impl PageAddr {
+    fn to_bytes(self) -> [u8; 8] {
+        ((self.page as u64) << 16 | self.slot as u64).to_le_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> PageAddr {
+        let mut le = [0; 8];
+        le.copy_from_slice(bytes);
+        let packed = u64::from_le_bytes(le);
+        PageAddr {
+            page: (packed >> 16) as u32,
+            slot: packed as u16,
+        }
+    }
+}
+
+// page 0 slot 0 is never handed out, it stands for the nil sentinel
+const NIL: PageAddr = PageAddr { page: 0, slot: 0 };
+
+const LIVE: u8 = 1;
+const RED: u8 = 2;
+
+// flags, parent, left and right child, then the key
+const HEADER: usize = 1 + 3 * 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PagedOptions {
+    // bytes per page, in memory and on disk
+    pub page_size: usize,
+    // pages the buffer pool keeps before it evicts the least recently used
+    pub pool_pages: usize,
+}
+
+impl Default for PagedOptions {
+    fn default() -> PagedOptions {
+        PagedOptions {
+            page_size: 4096,
+            pool_pages: 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub cached: usize,
+    // copies of keys handed out by reference, kept until the next &mut call
+    pub kept: usize,
+    pub hits: u64,
+    // pages read from the file
+    pub reads: u64,
+    // dirty pages written back, on eviction or flush
+    pub writes: u64,
+}
+
+struct Slot<T> {
+    key: mem::MaybeUninit<T>,
+    parent: PageAddr,
+    children: [PageAddr; 2],
+    red: bool,
+    live: bool,
+}
+
+struct Page<T> {
+    slots: Vec<Slot<T>>,
+    dirty: bool,
+}
+
+impl<T> Drop for Page<T> {
+    fn drop(&mut self) {
+        // keys are decoded copies of what's on disk, every live one is owned here
+        for slot in self.slots.iter_mut().filter(|slot| slot.live) {
+            unsafe { slot.key.assume_init_drop() };
+        }
+    }
+}
+
+struct Cached<T> {
+    page: *mut Page<T>,
+    used: u64,
+    // with_key calls looking into the page right now; it stays until they end
+    pins: usize,
+}
+
+struct Pool<T> {
+    file: File,
+    // pages are boxed and handed around as raw pointers, see PagedStore
+    pages: HashMap<u32, Cached<T>>,
+    lru: BTreeMap<u64, u32>,
+    tick: u64,
+    stats: PoolStats,
+}
+
+/*
+ * nodes live in fixed-size pages of a file, cached in an LRU buffer pool
+ *
+ * the pool evicts as soon as it's over pool_pages, on reads through &self
+ * too, so a read-only workload stays within it. no reference into a page
+ * outlives the call that made it: links and colors are copied out, and
+ * with_key pins the page only while its closure runs. the one thing the
+ * tree keeps is the &T from key(), for search results and iterators, so
+ * key() hands out a boxed copy of the key instead, kept until the next
+ * &mut call (trim, which the tree makes before every operation); the tree
+ * itself compares through with_key and copies nothing
+ *
+ * dirty pages are written back when evicted or on flush(); the file is
+ * scratch space for one store, it's truncated when the store is created
+ */
+pub struct PagedStore<T> {
+    pool: RefCell<Pool<T>>,
+    // boxes never move, so a &T into one lasts until it's removed under &mut
+    kept: RefCell<HashMap<PageAddr, Box<T>>>,
+    options: PagedOptions,
+    per_page: usize,
+    // slots ever handed out, numbered across pages
+    next: u64,
+    free: Vec<PageAddr>,
+    nil_parent: PageAddr,
+}
+
+impl<T: PageKey> PagedStore<T> {
+    pub fn create<P: AsRef<Path>>(path: P, options: PagedOptions) -> io::Result<PagedStore<T>> {
+        let per_page = options.page_size / (HEADER + T::SIZE);
+        assert!(per_page > 0, "page size is too small for one node");
+        assert!(per_page <= u16::MAX as usize + 1, "too many nodes per page");
+        assert!(
+            options.pool_pages > 0,
+            "buffer pool needs at least one page"
+        );
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(PagedStore {
+            pool: RefCell::new(Pool {
+                file,
+                pages: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                stats: PoolStats::default(),
+            }),
+            kept: RefCell::new(HashMap::new()),
+            options,
+            per_page,
+            next: 1,
+            free: Vec::new(),
+            nil_parent: NIL,
+        })
+    }
+
+    // write every dirty page back and sync the file
+    pub fn flush(&mut self) -> io::Result<()> {
+        let pool = self.pool.get_mut();
+        let mut dirty: Vec<_> = pool
+            .pages
+            .iter()
+            .filter(|(_, cached)| unsafe { (*cached.page).dirty })
+            .map(|(page, cached)| (*page, cached.page))
+            .collect();
+        dirty.sort_by_key(|(page, _)| *page);
+        for (page, p) in dirty {
+            self.write_page(page, unsafe { &mut *p })?;
+        }
+        self.pool.get_mut().file.sync_data()
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        let pool = self.pool.borrow();
+        PoolStats {
+            cached: pool.pages.len(),
+            kept: self.kept.borrow().len(),
+            ..pool.stats
+        }
+    }
+
+    fn record(&self) -> usize {
+        HEADER + T::SIZE
+    }
+
+    fn write_page(&self, page: u32, p: &mut Page<T>) -> io::Result<()> {
+        let record = self.record();
+        let mut bytes = vec![0; self.options.page_size];
+        for (slot, out) in p.slots.iter().zip(bytes.chunks_mut(record)) {
+            if !slot.live {
+                continue;
+            }
+            out[0] = LIVE | if slot.red { RED } else { 0 };
+            out[1..9].copy_from_slice(&slot.parent.to_bytes());
+            out[9..17].copy_from_slice(&slot.children[0].to_bytes());
+            out[17..25].copy_from_slice(&slot.children[1].to_bytes());
+            unsafe { slot.key.assume_init_ref() }.write(&mut out[HEADER..]);
+        }
+
+        let mut pool = self.pool.borrow_mut();
+        let offset = page as u64 * self.options.page_size as u64;
+        pool.file.seek(SeekFrom::Start(offset))?;
+        pool.file.write_all(&bytes)?;
+        pool.stats.writes += 1;
+        p.dirty = false;
+        Ok(())
+    }
+
+    fn read_page(&self, page: u32) -> io::Result<Page<T>> {
+        let mut pool = self.pool.borrow_mut();
+        let offset = page as u64 * self.options.page_size as u64;
+        let mut bytes = vec![0; self.options.page_size];
+
+        // a page that was never written back reads as all vacant slots
+        if offset < pool.file.metadata()?.len() {
+            pool.file.seek(SeekFrom::Start(offset))?;
+            pool.file.read_exact(&mut bytes)?;
+            pool.stats.reads += 1;
+        }
+
+        let slots = bytes
+            .chunks(self.record())
+            .take(self.per_page)
+            .map(|rec| {
+                let live = rec[0] & LIVE != 0;
+                Slot {
+                    key: if live {
+                        mem::MaybeUninit::new(T::read(&rec[HEADER..]))
+                    } else {
+                        mem::MaybeUninit::uninit()
+                    },
+                    parent: PageAddr::from_bytes(&rec[1..9]),
+                    children: [
+                        PageAddr::from_bytes(&rec[9..17]),
+                        PageAddr::from_bytes(&rec[17..25]),
+                    ],
+                    red: rec[0] & RED != 0,
+                    live,
+                }
+            })
+            .collect();
+        Ok(Page {
+            slots,
+            dirty: false,
+        })
+    }
+
+    // the slot behind x, faulting its page in; valid until the next slot()
+    fn slot(&self, x: PageAddr) -> *mut Slot<T> {
+        let cached = self.pool.borrow().pages.get(&x.page).map(|c| c.page);
+        let page = match cached {
+            Some(page) => {
+                self.pool.borrow_mut().stats.hits += 1;
+                page
+            }
+            None => {
+                let page = match self.read_page(x.page) {
+                    Ok(page) => page,
+                    Err(e) => panic!("paged store: reading page {} failed: {}", x.page, e),
+                };
+                let page = Box::into_raw(Box::new(page));
+                self.pool.borrow_mut().pages.insert(
+                    x.page,
+                    Cached {
+                        page,
+                        used: 0,
+                        pins: 0,
+                    },
+                );
+                page
+            }
+        };
+
+        {
+            let mut pool = self.pool.borrow_mut();
+            let pool = &mut *pool;
+            pool.tick += 1;
+            let cached = pool.pages.get_mut(&x.page).unwrap();
+            pool.lru.remove(&cached.used);
+            cached.used = pool.tick;
+            pool.lru.insert(pool.tick, x.page);
+        }
+        self.evict(Some(x.page));
+
+        unsafe { (*page).slots.as_mut_ptr().add(x.slot as usize) }
+    }
+
+    fn slot_mut(&mut self, x: PageAddr) -> &mut Slot<T> {
+        // the key may be about to change, nothing can still hold the copy
+        self.kept.get_mut().remove(&x);
+        let slot = self.slot(x);
+        let page = self.pool.get_mut().pages[&x.page].page;
+        unsafe {
+            (*page).dirty = true;
+            &mut *slot
+        }
+    }
+
+    /*
+     * evict least recently used pages, writing dirty ones back, until the
+     * pool is down to pool_pages; pinned pages and `keep`, the one being
+     * faulted in, are passed over
+     */
+    fn evict(&self, keep: Option<u32>) {
+        loop {
+            let victim = {
+                let pool = self.pool.borrow();
+                if pool.pages.len() <= self.options.pool_pages {
+                    return;
+                }
+                pool.lru
+                    .values()
+                    .find(|page| Some(**page) != keep && pool.pages[*page].pins == 0)
+                    .copied()
+            };
+            let page = match victim {
+                Some(page) => page,
+                None => return,
+            };
+            let cached = {
+                let mut pool = self.pool.borrow_mut();
+                let cached = pool.pages.remove(&page).unwrap();
+                pool.lru.remove(&cached.used);
+                cached
+            };
+            let mut p = unsafe { Box::from_raw(cached.page) };
+            if p.dirty {
+                if let Err(e) = self.write_page(page, &mut p) {
+                    panic!("paged store: writing page {} failed: {}", page, e);
+                }
+            }
+        }
+    }
+
+    fn pin(&self, page: u32, pinned: bool) {
+        let mut pool = self.pool.borrow_mut();
+        let cached = pool.pages.get_mut(&page).unwrap();
+        if pinned {
+            cached.pins += 1;
+        } else {
+            cached.pins -= 1;
+        }
+    }
+}
+
+impl<T: PageKey> NodeStore<T> for PagedStore<T> {
+    type Link = PageAddr;
+
+    fn nil(&self) -> PageAddr {
+        NIL
+    }
+
+    fn alloc(&mut self, key: T) -> PageAddr {
+        let x = self.free.pop().unwrap_or_else(|| {
+            let n = self.next;
+            self.next += 1;
+            let page = n / self.per_page as u64;
+            assert!(page <= u32::MAX as u64, "paged store is full");
+            PageAddr {
+                page: page as u32,
+                slot: (n % self.per_page as u64) as u16,
+            }
+        });
+        let slot = self.slot_mut(x);
+        slot.key = mem::MaybeUninit::new(key);
+        slot.parent = NIL;
+        slot.children = [NIL, NIL];
+        slot.red = false;
+        slot.live = true;
+        x
+    }
+
+    fn free(&mut self, x: PageAddr) -> T {
+        let slot = self.slot_mut(x);
+        slot.live = false;
+        let key = mem::replace(&mut slot.key, mem::MaybeUninit::uninit());
+        self.free.push(x);
+        unsafe { key.assume_init() }
+    }
+
+    fn parent(&self, x: PageAddr) -> PageAddr {
+        if x == NIL {
+            return self.nil_parent;
+        }
+        unsafe { (*self.slot(x)).parent }
+    }
+
+    fn set_parent(&mut self, x: PageAddr, parent: PageAddr) {
+        if x == NIL {
+            self.nil_parent = parent;
+        } else {
+            self.slot_mut(x).parent = parent;
+        }
+    }
+
+    fn child(&self, x: PageAddr, dir: usize) -> PageAddr {
+        if x == NIL {
+            return NIL;
+        }
+        unsafe { (*self.slot(x)).children[dir] }
+    }
+
+    fn set_child(&mut self, x: PageAddr, dir: usize, child: PageAddr) {
+        debug_assert!(x != NIL);
+        self.slot_mut(x).children[dir] = child;
+    }
+
+    fn is_red(&self, x: PageAddr) -> bool {
+        x != NIL && unsafe { (*self.slot(x)).red }
+    }
+
+    fn set_red(&mut self, x: PageAddr, red: bool) {
+        if x == NIL {
+            debug_assert!(!red);
+            return;
+        }
+        self.slot_mut(x).red = red;
+    }
+
+    // a copy, see PagedStore; the same one for as long as it's kept
+    fn key(&self, x: PageAddr) -> &T {
+        debug_assert!(x != NIL);
+        if let Some(key) = self.kept.borrow().get(&x) {
+            return unsafe { &*(&**key as *const T) };
+        }
+        let copy = self.with_key(x, |key| {
+            let mut bytes = vec![0; T::SIZE];
+            key.write(&mut bytes);
+            T::read(&bytes)
+        });
+        let mut kept = self.kept.borrow_mut();
+        let key: &T = kept.entry(x).or_insert_with(|| Box::new(copy));
+        unsafe { &*(key as *const T) }
+    }
+
+    fn key_mut(&mut self, x: PageAddr) -> &mut T {
+        debug_assert!(x != NIL);
+        unsafe { self.slot_mut(x).key.assume_init_mut() }
+    }
+
+    fn with_key<R, F: FnOnce(&T) -> R>(&self, x: PageAddr, f: F) -> R {
+        debug_assert!(x != NIL);
+        // unpins even if f panics, so the page can go again
+        struct Pin<'a, T: PageKey>(&'a PagedStore<T>, u32);
+        impl<'a, T: PageKey> Drop for Pin<'a, T> {
+            fn drop(&mut self) {
+                self.0.pin(self.1, false);
+            }
+        }
+
+        let slot = self.slot(x);
+        self.pin(x.page, true);
+        let _pin = Pin(self, x.page);
+        f(unsafe { (*slot).key.assume_init_ref() })
+    }
+
+    // slots in the pages handed out so far, less the sentinel's
+    fn capacity(&self) -> usize {
+        let pages = (self.next as usize).div_ceil(self.per_page);
+        pages * self.per_page - 1
+    }
+
+    fn slot_size(&self) -> usize {
+        self.record()
+    }
+
+    // with &mut, nothing can hold a kept key any more
+    fn trim(&mut self) {
+        self.kept.get_mut().clear();
+        self.evict(None);
+    }
+}
+
+impl<T> Drop for PagedStore<T> {
+    fn drop(&mut self) {
+        for (_, cached) in self.pool.get_mut().pages.drain() {
+            drop(unsafe { Box::from_raw(cached.page) });
+        }
+    }
+}
+
+pub type PagedRedBlack<T> = RedBlackTree<T, PagedStore<T>>;
+
+impl<T: PageKey + PartialOrd> RedBlackTree<T, PagedStore<T>> {
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a paranoid check faults every page in again through a pool of a few
+    const N: u32 = if cfg!(feature = "paranoid") {
+        250
+    } else {
+        10000
+    };
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_paged_larger_than_pool() {
+        let path = scratch("paged");
+        let options = PagedOptions {
+            page_size: 512,
+            pool_pages: 4,
+        };
+        let store: PagedStore<u64> = PagedStore::create(&path, options).unwrap();
+        let mut rb = RedBlackTree::with_store(store);
+
+        // 15 nodes a page, so many times the pool of 4
+        let n = N as u64;
+        for i in 0..n {
+            rb.insert(i * 7919 % n);
+        }
+        for i in 0..n / 2 {
+            rb.delete(&(i * 2));
+        }
+        rb.is_valid(); // will panic if it must
+
+        let stats = rb.store.pool_stats();
+        assert!(stats.reads > 0 && stats.writes > 0);
+        for i in 0..n {
+            let found = rb.search(&i).copied();
+            let expected = if i % 2 == 1 { Some(i) } else { None };
+            assert_eq!(found, expected);
+        }
+
+        // flushing leaves nothing dirty, so a second one writes nothing
+        rb.flush().unwrap();
+        let writes = rb.store.pool_stats().writes;
+        rb.flush().unwrap();
+        assert_eq!(rb.store.pool_stats().writes, writes);
+        assert!(rb.memory_usage().nodes > 0);
+        drop(rb);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_paged_pool_bounded() {
+        let path = scratch("paged-pool");
+        let options = PagedOptions {
+            page_size: 256,
+            pool_pages: 2,
+        };
+        let store: PagedStore<u32> = PagedStore::create(&path, options).unwrap();
+        let mut rb = RedBlackTree::with_store(store);
+        let n = N / 10;
+        for i in 0..n {
+            rb.insert(i);
+        }
+        rb.delete(&0);
+
+        // reads through &self evict too, so nothing gets past the pool
+        assert!(rb.store.pool_stats().cached <= 2);
+        rb.validate().unwrap();
+        for i in 0..n {
+            let expected = if i == 0 { None } else { Some(i) };
+            assert_eq!(rb.search(&i).copied(), expected);
+            assert!(rb.store.pool_stats().cached <= 2);
+        }
+        assert_eq!(rb.iter().count(), n as usize - 1);
+        assert!(rb.store.pool_stats().cached <= 2);
+
+        // only the keys handed out by reference are kept, until a &mut call
+        assert_eq!(rb.store.pool_stats().kept, n as usize - 1);
+        rb.insert(0);
+        assert_eq!(rb.store.pool_stats().kept, 0);
+        assert_eq!(format!("{:?}", rb.store.nil()), "0:0");
+        drop(rb);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,522 @@
+use crate::bulk::{red_depth, BuildError};
+use crate::persistent::{
+    balance, black_height, del, is_red, node, open, paint, size, Link, PersistentRedBlack,
+};
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::cmp::Ordering;
+use std::thread;
+
+// subtrees smaller than this are done on the thread that got them
+pub const PAR_THRESHOLD: usize = 1 << 12;
+
+/*
+ * parallel bulk operations, divide and conquer over split and join
+ * ("Just Join for Parallel Ordered Sets", Blelloch, Ferizovic and Sun)
+ *
+ * join(l, k, r) links two trees and a key between them in O(log n) by
+ * walking down the spine of the taller one, split(t, k) cuts a tree at a
+ * key in O(log n) with a join per level, and everything else is one of
+ * those per node of one input: union splits the other tree at the root
+ * and unions the halves in parallel, and so on. the trees are persistent,
+ * so the two halves share nothing mutable and can go to two threads
+ *
+ * the set operations expect trees without duplicate keys
+ *
+ * what runs in parallel: union, intersection, retain and for_each on
+ * PersistentRedBlack, whose two halves at each level go to two threads,
+ * and par_from_sorted, which checks the order in parallel chunks and then
+ * builds the halves the same way.
+ * the mutable trees only get par_for_each: a set operation on them would
+ * have to copy both trees in and the result out on one thread, O(n + m)
+ * sequential work that costs more than a plain merge saves, so they're
+ * left out; build PersistentRedBlacks with par_from_sorted to combine
+ * big sets
+ *
+ * only the top log2(cores) levels of the recursion fork, so there are
+ * about as many threads as cores however big the input is
+ */
+
+// how far a subproblem may still fork
+#[derive(Clone, Copy, Debug)]
+struct Par {
+    // subproblems smaller than this are done on the thread that got them
+    threshold: usize,
+    // levels of the recursion below this one that may still fork
+    forks: u32,
+}
+
+impl Par {
+    fn new() -> Par {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Par {
+            threshold: PAR_THRESHOLD,
+            forks: cores.next_power_of_two().trailing_zeros(),
+        }
+    }
+
+    // run both, the first on another thread if there's enough work for one
+    // and forks to spare
+    fn fork<A, B, FA, FB>(self, work: usize, a: FA, b: FB) -> (A, B)
+    where
+        A: Send,
+        FA: FnOnce(Par) -> A + Send,
+        FB: FnOnce(Par) -> B,
+    {
+        if work < self.threshold || self.forks == 0 {
+            return (a(self), b(self));
+        }
+        let next = Par {
+            forks: self.forks - 1,
+            ..self
+        };
+        thread::scope(|s| {
+            let a = s.spawn(move || a(next));
+            let b = b(next);
+            (a.join().unwrap(), b)
+        })
+    }
+}
+
+// l is the taller tree, with black root and height hl > hr
+fn join_right<T: Clone>(l: &Link<T>, hl: usize, key: T, r: &Link<T>, hr: usize) -> Link<T> {
+    if hl == hr && !is_red(l) {
+        return node(true, l.clone(), key, r.clone());
+    }
+    let n = open(l);
+    if n.red {
+        // a red node's children have its black height
+        let right = join_right(&n.right, hl, key, r, hr);
+        node(true, n.left.clone(), n.key.clone(), right)
+    } else {
+        let right = join_right(&n.right, hl - 1, key, r, hr);
+        balance(n.left.clone(), n.key.clone(), right)
+    }
+}
+
+fn join_left<T: Clone>(l: &Link<T>, hl: usize, key: T, r: &Link<T>, hr: usize) -> Link<T> {
+    if hl == hr && !is_red(r) {
+        return node(true, l.clone(), key, r.clone());
+    }
+    let n = open(r);
+    if n.red {
+        let left = join_left(l, hl, key, &n.left, hr);
+        node(true, left, n.key.clone(), n.right.clone())
+    } else {
+        let left = join_left(l, hl, key, &n.left, hr - 1);
+        balance(left, n.key.clone(), n.right.clone())
+    }
+}
+
+// every key of l < key < every key of r
+fn join<T: Clone>(l: &Link<T>, key: T, r: &Link<T>) -> Link<T> {
+    let (l, r) = (paint(l, false), paint(r, false));
+    let (hl, hr) = (black_height(&l), black_height(&r));
+    let joined = match hl.cmp(&hr) {
+        Ordering::Greater => join_right(&l, hl, key, &r, hr),
+        Ordering::Less => join_left(&l, hl, key, &r, hr),
+        Ordering::Equal => return node(false, l, key, r),
+    };
+    // balance leaves at most a red root with black children; results are
+    // always black-rooted so they can be handed out as trees
+    paint(&joined, false)
+}
+
+// join without a key in the middle: the smallest of r takes its place
+fn join2<T: PartialOrd + Clone>(l: &Link<T>, r: &Link<T>) -> Link<T> {
+    let mut x = match r {
+        None => return l.clone(),
+        Some(n) => n,
+    };
+    while let Some(n) = &x.left {
+        x = n;
+    }
+    let min = x.key.clone();
+    let rest = del(&paint(r, false), &min);
+    join(l, min, &rest)
+}
+
+// the keys below key, key itself if it's there, and the keys above it
+fn split<T: PartialOrd + Clone>(t: &Link<T>, key: &T) -> (Link<T>, Option<T>, Link<T>) {
+    let n = match t {
+        None => return (None, None, None),
+        Some(n) => n,
+    };
+    if *key < n.key {
+        let (l, found, r) = split(&n.left, key);
+        (l, found, join(&r, n.key.clone(), &n.right))
+    } else if n.key < *key {
+        let (l, found, r) = split(&n.right, key);
+        (join(&n.left, n.key.clone(), &l), found, r)
+    } else {
+        (n.left.clone(), Some(n.key.clone()), n.right.clone())
+    }
+}
+
+fn union<T>(a: &Link<T>, b: &Link<T>, par: Par) -> Link<T>
+where
+    T: PartialOrd + Clone + Send + Sync,
+{
+    let n = match (a, b) {
+        (None, _) => return b.clone(),
+        (_, None) => return a.clone(),
+        (Some(n), _) => n,
+    };
+    let (l, _, r) = split(b, &n.key);
+    let work = size(a) + size(b);
+    let (left, right) = par.fork(
+        work,
+        |par| union(&n.left, &l, par),
+        |par| union(&n.right, &r, par),
+    );
+    join(&left, n.key.clone(), &right)
+}
+
+fn intersection<T>(a: &Link<T>, b: &Link<T>, par: Par) -> Link<T>
+where
+    T: PartialOrd + Clone + Send + Sync,
+{
+    let n = match (a, b) {
+        (None, _) | (_, None) => return None,
+        (Some(n), _) => n,
+    };
+    let (l, found, r) = split(b, &n.key);
+    let work = size(a) + size(b);
+    let (left, right) = par.fork(
+        work,
+        |par| intersection(&n.left, &l, par),
+        |par| intersection(&n.right, &r, par),
+    );
+    match found {
+        Some(_) => join(&left, n.key.clone(), &right),
+        None => join2(&left, &right),
+    }
+}
+
+fn retain<T, F>(t: &Link<T>, f: &F, par: Par) -> Link<T>
+where
+    T: PartialOrd + Clone + Send + Sync,
+    F: Fn(&T) -> bool + Sync,
+{
+    let n = t.as_ref()?;
+    let (left, right) = par.fork(
+        n.len,
+        |par| retain(&n.left, f, par),
+        |par| retain(&n.right, f, par),
+    );
+    if f(&n.key) {
+        join(&left, n.key.clone(), &right)
+    } else {
+        join2(&left, &right)
+    }
+}
+
+fn for_each<T: Send + Sync, F: Fn(&T) + Sync>(t: &Link<T>, f: &F, par: Par) {
+    if let Some(n) = t {
+        par.fork(
+            n.len,
+            |par| for_each(&n.left, f, par),
+            |par| for_each(&n.right, f, par),
+        );
+        f(&n.key);
+    }
+}
+
+// the same shape bulk.rs builds: midpoint splits, the partial level red
+fn build<T: Clone + Send + Sync>(keys: &[T], depth: usize, red: usize, par: Par) -> Link<T> {
+    if keys.is_empty() {
+        return None;
+    }
+    let mid = keys.len() / 2;
+    let (left, right) = par.fork(
+        keys.len(),
+        |par| build(&keys[..mid], depth + 1, red, par),
+        |par| build(&keys[mid + 1..], depth + 1, red, par),
+    );
+    node(depth == red, left, keys[mid].clone(), right)
+}
+
+// strictly ascending, or the first key out of order
+fn check_sorted<T: PartialOrd + Sync>(keys: &[T], par: Par) -> Result<(), BuildError> {
+    // one chunk per thread fork would give, each overlapping the next by a key
+    let chunk = keys
+        .len()
+        .div_ceil(1 << par.forks)
+        .max(par.threshold)
+        .max(2);
+    let check = |start: usize| -> Result<(), BuildError> {
+        let end = (start + chunk + 1).min(keys.len());
+        for (i, pair) in keys[start..end].windows(2).enumerate() {
+            let index = start + i + 1;
+            match pair[0].partial_cmp(&pair[1]) {
+                Some(Ordering::Less) => {}
+                Some(Ordering::Equal) => return Err(BuildError::Duplicate { index }),
+                _ => return Err(BuildError::Unsorted { index }),
+            }
+        }
+        Ok(())
+    };
+    let starts: Vec<usize> = (0..keys.len()).step_by(chunk).collect();
+    let results: Vec<Result<(), BuildError>> = if starts.len() > 1 {
+        thread::scope(|s| {
+            let handles: Vec<_> = starts
+                .iter()
+                .map(|&start| s.spawn(move || check(start)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    } else {
+        starts.iter().map(|&start| check(start)).collect()
+    };
+    // the first offending key wins, as in from_sorted
+    results.into_iter().collect()
+}
+
+fn from_sorted<T>(keys: &[T], par: Par) -> Result<Link<T>, BuildError>
+where
+    T: PartialOrd + Clone + Send + Sync,
+{
+    check_sorted(keys, par)?;
+    Ok(build(keys, 0, red_depth(keys.len()), par))
+}
+
+impl<T> PersistentRedBlack<T>
+where
+    T: PartialOrd + Clone + Send + Sync,
+{
+    // every key of either tree; other's copy of a shared key is dropped
+    pub fn par_union(&self, other: &Self) -> Self {
+        PersistentRedBlack {
+            root: union(&self.root, &other.root, Par::new()),
+        }
+    }
+
+    pub fn par_intersection(&self, other: &Self) -> Self {
+        PersistentRedBlack {
+            root: intersection(&self.root, &other.root, Par::new()),
+        }
+    }
+
+    // the keys f is true for, as a new version
+    pub fn par_retain<F: Fn(&T) -> bool + Sync>(&self, f: F) -> Self {
+        PersistentRedBlack {
+            root: retain(&self.root, &f, Par::new()),
+        }
+    }
+
+    // f sees every key once, in no particular order
+    pub fn par_for_each<F: Fn(&T) + Sync>(&self, f: F) {
+        for_each(&self.root, &f, Par::new());
+    }
+
+    // from strictly ascending keys in O(n) work, like from_sorted
+    pub fn par_from_sorted(keys: &[T]) -> Result<Self, BuildError> {
+        Ok(PersistentRedBlack {
+            root: from_sorted(keys, Par::new())?,
+        })
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: PartialOrd + Sync,
+    S: NodeStore<T> + Sync,
+    S::Link: Send + Sync,
+{
+    /*
+     * f sees every key once, in no particular order; the store is only
+     * read, so disjoint subtrees go to different threads. nodes here don't
+     * know their subtree sizes, so each level is guessed at half the last
+     */
+    pub fn par_for_each<F: Fn(&T) + Sync>(&self, f: F) {
+        self.par_for_each_(self.root, self.len, &f, Par::new());
+    }
+
+    fn par_for_each_<F: Fn(&T) + Sync>(&self, x: S::Link, work: usize, f: &F, par: Par) {
+        if x == self.store.nil() {
+            return;
+        }
+        let (l, r) = (self.store.child(x, 0), self.store.child(x, 1));
+        par.fork(
+            work,
+            move |par| self.par_for_each_(l, work / 2, f, par),
+            |par| self.par_for_each_(r, work / 2, f, par),
+        );
+        f(self.store.key(x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slab::SlabRedBlack;
+    use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+    // a small threshold and a few levels of forks, so the tests really fork
+    const PAR: Par = Par {
+        threshold: 64,
+        forks: 4,
+    };
+
+    fn keys(t: &Link<u32>) -> Vec<u32> {
+        PersistentRedBlack { root: t.clone() }
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    // black height, checking colors, order and sizes along the way
+    fn check(x: &Link<u32>) -> usize {
+        let n = match x {
+            None => return 1,
+            Some(n) => n,
+        };
+        if n.red {
+            assert!(!is_red(&n.left) && !is_red(&n.right));
+        }
+        assert_eq!(n.len, size(&n.left) + size(&n.right) + 1);
+        let l = check(&n.left);
+        assert_eq!(l, check(&n.right), "black heights differ under {}", n.key);
+        l + if n.red { 0 } else { 1 }
+    }
+
+    fn is_valid(x: &Link<u32>) {
+        assert!(!is_red(x));
+        check(x);
+        let k = keys(x);
+        assert!(k.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    fn set(keys: impl Iterator<Item = u32>) -> Link<u32> {
+        keys.fold(PersistentRedBlack::new(), |t, k| t.insert(k))
+            .root
+    }
+
+    #[test]
+    fn test_join_and_split() {
+        for (nl, nr) in [(0, 0), (0, 5), (5, 0), (1, 300), (300, 1), (100, 120)] {
+            let l = set(0..nl);
+            let r = set(nl + 1..nl + 1 + nr);
+            let t = join(&l, nl, &r);
+            is_valid(&t);
+            assert_eq!(keys(&t), (0..nl + 1 + nr).collect::<Vec<_>>());
+
+            let (a, found, b) = split(&t, &(nl / 2));
+            is_valid(&paint(&a, false));
+            is_valid(&paint(&b, false));
+            assert_eq!(found, Some(nl / 2));
+            assert_eq!(keys(&a), (0..nl / 2).collect::<Vec<_>>());
+
+            let t = join2(&a, &b);
+            is_valid(&t);
+            assert_eq!(size(&t), nl as usize + nr as usize);
+        }
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = set((0..3000).map(|i| i * 2));
+        let b = set((0..3000).map(|i| i * 3));
+
+        let u = union(&a, &b, PAR);
+        is_valid(&u);
+        let expected: Vec<u32> = (0..9000)
+            .filter(|k| (k % 2 == 0 && *k < 6000) || k % 3 == 0)
+            .collect();
+        assert_eq!(keys(&u), expected);
+
+        let i = intersection(&a, &b, PAR);
+        is_valid(&i);
+        assert_eq!(keys(&i), (0..6000).step_by(6).collect::<Vec<_>>());
+
+        let r = retain(&u, &|k: &u32| k % 5 == 0, PAR);
+        is_valid(&r);
+        let expected: Vec<u32> = expected.into_iter().filter(|k| k % 5 == 0).collect();
+        assert_eq!(keys(&r), expected);
+
+        assert_eq!(union(&a, &None, PAR).as_ref().map(|n| n.len), Some(3000));
+        assert!(intersection(&None, &b, PAR).is_none());
+
+        // the public versions agree
+        let (pa, pb) = (
+            PersistentRedBlack { root: a },
+            PersistentRedBlack { root: b },
+        );
+        assert_eq!(pa.par_union(&pb).len(), size(&u));
+        assert_eq!(pa.par_intersection(&pb).len(), 1000);
+        assert_eq!(pa.par_retain(|k| *k < 10).len(), 5);
+    }
+
+    #[test]
+    fn test_par_from_sorted() {
+        for n in [0, 1, 2, 3, 100, 5000] {
+            let keys: Vec<u32> = (0..n).collect();
+            let t = from_sorted(&keys, PAR).unwrap();
+            is_valid(&t);
+            assert_eq!(size(&t), n as usize);
+        }
+        let mut keys: Vec<u32> = (0..1000).collect();
+        keys[700] = 699;
+        assert_eq!(
+            from_sorted(&keys, PAR).err(),
+            Some(BuildError::Duplicate { index: 700 })
+        );
+        keys[700] = 5;
+        assert_eq!(
+            from_sorted(&keys, PAR).err(),
+            Some(BuildError::Unsorted { index: 700 })
+        );
+        assert!(PersistentRedBlack::par_from_sorted(&[3, 2]).is_err());
+    }
+
+    // only the top levels fork, however many keys there are
+    #[test]
+    fn test_bounded_threads() {
+        let threads = std::sync::Mutex::new(std::collections::HashSet::new());
+        let t = from_sorted(&(0..100_000).collect::<Vec<u32>>(), PAR).unwrap();
+        for_each(
+            &t,
+            &|_: &u32| {
+                threads.lock().unwrap().insert(thread::current().id());
+            },
+            PAR,
+        );
+        let threads = threads.into_inner().unwrap().len();
+        assert!(
+            threads > 1 && threads <= 1 << PAR.forks,
+            "{} threads",
+            threads
+        );
+    }
+
+    #[test]
+    fn test_par_for_each() {
+        let sum = AtomicU64::new(0);
+        let t = from_sorted(&(0..5000).collect::<Vec<u32>>(), PAR).unwrap();
+        for_each(
+            &t,
+            &|k: &u32| {
+                sum.fetch_add(*k as u64, AtomicOrdering::Relaxed);
+            },
+            PAR,
+        );
+        assert_eq!(sum.load(AtomicOrdering::Relaxed), 4999 * 5000 / 2);
+
+        let rb: SlabRedBlack<u32> = RedBlackTree::from_sorted(0..5000).unwrap();
+        sum.store(0, AtomicOrdering::Relaxed);
+        rb.par_for_each_(
+            rb.root,
+            rb.len(),
+            &|k: &u32| {
+                sum.fetch_add(*k as u64, AtomicOrdering::Relaxed);
+            },
+            PAR,
+        );
+        assert_eq!(sum.load(AtomicOrdering::Relaxed), 4999 * 5000 / 2);
+        let count = AtomicU64::new(0);
+        rb.par_for_each(|_| {
+            count.fetch_add(1, AtomicOrdering::Relaxed);
+        });
+        assert_eq!(count.load(AtomicOrdering::Relaxed), 5000);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::pretty::{self, Drawn, PrettyOptions};
+use crate::redblack::RedBlack;
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::sync::Arc;
+
+/*
+ * an immutable red-black tree: insert and delete copy the path from the
+ * root down to the change and share every other subtree with the version
+ * they started from, so old versions stay valid for as long as someone
+ * holds them and a clone is just a reference count
+ *
+ * there are no parent links, a node can have many parents across
+ * versions; the rebalancing is Okasaki's for insert and Kahrs' for delete,
+ * both written as rebuilding the nodes on the way back up
+ */
+pub struct PersistentRedBlack<T> {
+    pub(crate) root: Link<T>,
+}
+
+pub(crate) type Link<T> = Option<Arc<Node<T>>>;
+
+pub(crate) struct Node<T> {
+    pub(crate) red: bool,
+    pub(crate) left: Link<T>,
+    pub(crate) key: T,
+    pub(crate) right: Link<T>,
+    // keys in this subtree
+    pub(crate) len: usize,
+}
+
+pub(crate) fn node<T>(red: bool, left: Link<T>, key: T, right: Link<T>) -> Link<T> {
+    let len = size(&left) + size(&right) + 1;
+    Some(Arc::new(Node {
+        red,
+        left,
+        key,
+        right,
+        len,
+    }))
+}
+
+pub(crate) fn size<T>(x: &Link<T>) -> usize {
+    x.as_ref().map_or(0, |n| n.len)
+}
+
+// black nodes from the root down to nil, counting the root
+pub(crate) fn black_height<T>(mut x: &Link<T>) -> usize {
+    let mut h = 0;
+    while let Some(n) = x {
+        if !n.red {
+            h += 1;
+        }
+        x = &n.left;
+    }
+    h
+}
+
+pub(crate) fn is_red<T>(x: &Link<T>) -> bool {
+    x.as_ref().is_some_and(|n| n.red)
+}
+
+fn is_black_node<T>(x: &Link<T>) -> bool {
+    x.as_ref().is_some_and(|n| !n.red)
+}
+
+// the same node painted red or black, copied only if that changes it
+pub(crate) fn paint<T: Clone>(x: &Link<T>, red: bool) -> Link<T> {
+    match x {
+        Some(n) if n.red != red => node(red, n.left.clone(), n.key.clone(), n.right.clone()),
+        _ => x.clone(),
+    }
+}
+
+// the two children and key of a node that's known to be there
+pub(crate) fn open<T>(x: &Link<T>) -> &Node<T> {
+    x.as_deref().expect("red-black invariant broken")
+}
+
+/*
+ * a black node with children l and r, rebuilt as a red node with two black
+ * children if one of them is red with a red child (or both are red); the
+ * four red-red shapes all come out the same
+ */
+pub(crate) fn balance<T: Clone>(l: Link<T>, key: T, r: Link<T>) -> Link<T> {
+    if is_red(&l) && is_red(&r) {
+        return node(true, paint(&l, false), key, paint(&r, false));
+    }
+    if is_red(&l) {
+        let n = open(&l);
+        if is_red(&n.left) {
+            let a = open(&n.left);
+            return node(
+                true,
+                node(false, a.left.clone(), a.key.clone(), a.right.clone()),
+                n.key.clone(),
+                node(false, n.right.clone(), key, r),
+            );
+        }
+        if is_red(&n.right) {
+            let b = open(&n.right);
+            return node(
+                true,
+                node(false, n.left.clone(), n.key.clone(), b.left.clone()),
+                b.key.clone(),
+                node(false, b.right.clone(), key, r),
+            );
+        }
+    }
+    if is_red(&r) {
+        let n = open(&r);
+        if is_red(&n.right) {
+            let c = open(&n.right);
+            return node(
+                true,
+                node(false, l, key, n.left.clone()),
+                n.key.clone(),
+                node(false, c.left.clone(), c.key.clone(), c.right.clone()),
+            );
+        }
+        if is_red(&n.left) {
+            let b = open(&n.left);
+            return node(
+                true,
+                node(false, l, key, b.left.clone()),
+                b.key.clone(),
+                node(false, b.right.clone(), n.key.clone(), n.right.clone()),
+            );
+        }
+    }
+    node(false, l, key, r)
+}
+
+// equal keys go right, after the ones already there, as in tree.rs
+fn ins<T: PartialOrd + Clone>(x: &Link<T>, key: T) -> Link<T> {
+    let n = match x {
+        None => return node(true, None, key, None),
+        Some(n) => n,
+    };
+    let (left, right) = if key < n.key {
+        (ins(&n.left, key), n.right.clone())
+    } else {
+        (n.left.clone(), ins(&n.right, key))
+    };
+    if n.red {
+        node(true, left, n.key.clone(), right)
+    } else {
+        balance(left, n.key.clone(), right)
+    }
+}
+
+// a black node turned red to take one off its black height
+fn sub1<T: Clone>(x: &Link<T>) -> Link<T> {
+    assert!(is_black_node(x), "red-black invariant broken");
+    paint(x, true)
+}
+
+// l lost one black from its height, r didn't
+fn bal_left<T: Clone>(l: Link<T>, key: T, r: Link<T>) -> Link<T> {
+    if is_red(&l) {
+        return node(true, paint(&l, false), key, r);
+    }
+    if is_black_node(&r) {
+        return balance(l, key, paint(&r, true));
+    }
+    let n = open(&r);
+    let m = open(&n.left);
+    node(
+        true,
+        node(false, l, key, m.left.clone()),
+        m.key.clone(),
+        balance(m.right.clone(), n.key.clone(), sub1(&n.right)),
+    )
+}
+
+// the mirror image: r lost one black
+fn bal_right<T: Clone>(l: Link<T>, key: T, r: Link<T>) -> Link<T> {
+    if is_red(&r) {
+        return node(true, l, key, paint(&r, false));
+    }
+    if is_black_node(&l) {
+        return balance(paint(&l, true), key, r);
+    }
+    let n = open(&l);
+    let m = open(&n.right);
+    node(
+        true,
+        balance(sub1(&n.left), n.key.clone(), m.left.clone()),
+        m.key.clone(),
+        node(false, m.right.clone(), key, r),
+    )
+}
+
+// append two subtrees of equal black height, every key of l before r's
+fn append<T: Clone>(l: &Link<T>, r: &Link<T>) -> Link<T> {
+    let (a, b) = match (l, r) {
+        (None, _) => return r.clone(),
+        (_, None) => return l.clone(),
+        (Some(a), Some(b)) => (a, b),
+    };
+    match (a.red, b.red) {
+        (true, true) => {
+            let mid = append(&a.right, &b.left);
+            if is_red(&mid) {
+                let m = open(&mid);
+                node(
+                    true,
+                    node(true, a.left.clone(), a.key.clone(), m.left.clone()),
+                    m.key.clone(),
+                    node(true, m.right.clone(), b.key.clone(), b.right.clone()),
+                )
+            } else {
+                let right = node(true, mid, b.key.clone(), b.right.clone());
+                node(true, a.left.clone(), a.key.clone(), right)
+            }
+        }
+        (false, false) => {
+            let mid = append(&a.right, &b.left);
+            if is_red(&mid) {
+                let m = open(&mid);
+                node(
+                    true,
+                    node(false, a.left.clone(), a.key.clone(), m.left.clone()),
+                    m.key.clone(),
+                    node(false, m.right.clone(), b.key.clone(), b.right.clone()),
+                )
+            } else {
+                let right = node(false, mid, b.key.clone(), b.right.clone());
+                bal_left(a.left.clone(), a.key.clone(), right)
+            }
+        }
+        (false, true) => node(true, append(l, &b.left), b.key.clone(), b.right.clone()),
+        (true, false) => node(true, a.left.clone(), a.key.clone(), append(&a.right, r)),
+    }
+}
+
+/*
+ * only called with a key that's in the tree: coming back up from a black
+ * subtree, bal_left and bal_right assume it lost one black
+ */
+pub(crate) fn del<T: PartialOrd + Clone>(x: &Link<T>, key: &T) -> Link<T> {
+    let n = open(x);
+    if *key < n.key {
+        if is_black_node(&n.left) {
+            bal_left(del(&n.left, key), n.key.clone(), n.right.clone())
+        } else {
+            node(true, del(&n.left, key), n.key.clone(), n.right.clone())
+        }
+    } else if n.key < *key {
+        if is_black_node(&n.right) {
+            bal_right(n.left.clone(), n.key.clone(), del(&n.right, key))
+        } else {
+            node(true, n.left.clone(), n.key.clone(), del(&n.right, key))
+        }
+    } else {
+        append(&n.left, &n.right)
+    }
+}
+
+impl<T> Clone for PersistentRedBlack<T> {
+    fn clone(&self) -> Self {
+        PersistentRedBlack {
+            root: self.root.clone(),
+        }
+    }
+}
+
+impl<T> Default for PersistentRedBlack<T> {
+    fn default() -> Self {
+        PersistentRedBlack { root: None }
+    }
+}
+
+impl<T: PartialOrd + Clone> PersistentRedBlack<T> {
+    pub fn new() -> Self {
+        PersistentRedBlack::default()
+    }
+
+    pub fn len(&self) -> usize {
+        size(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    // a new version with key added; self is left as it was
+    #[must_use]
+    pub fn insert(&self, key: T) -> Self {
+        PersistentRedBlack {
+            root: paint(&ins(&self.root, key), false),
+        }
+    }
+
+    // a new version without one copy of key, or a clone if it isn't there
+    #[must_use]
+    pub fn delete(&self, key: &T) -> Self {
+        if self.search(key).is_none() {
+            return self.clone();
+        }
+        let root = del(&self.root, key);
+        PersistentRedBlack {
+            root: paint(&root, false),
+        }
+    }
+
+    pub fn search(&self, key: &T) -> Option<&T> {
+        let mut x = &self.root;
+        while let Some(n) = x {
+            if *key < n.key {
+                x = &n.left;
+            } else if n.key < *key {
+                x = &n.right;
+            } else {
+                return Some(&n.key);
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> Range<'_, T, RangeFull> {
+        self.range(..)
+    }
+
+    // keys within range in ascending order, e.g. tree.range(10..20)
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T, R> {
+        let before = |key: &T| match range.start_bound() {
+            Bound::Included(start) => *key < *start,
+            Bound::Excluded(start) => *key <= *start,
+            Bound::Unbounded => false,
+        };
+        let stack = self.seek(before);
+        Range { stack, range }
+    }
+
+    // the path down to the first key that isn't `before`, for a Range
+    fn seek<F: Fn(&T) -> bool>(&self, before: F) -> Vec<&Node<T>> {
+        let mut stack = Vec::new();
+        let mut x = &self.root;
+        while let Some(n) = x {
+            if before(&n.key) {
+                x = &n.right;
+            } else {
+                stack.push(&**n);
+                x = &n.left;
+            }
+        }
+        stack
+    }
+
+    /*
+     * search by something other than a whole key, e.g. the key half of a
+     * key-value pair; cmp orders a key in the tree against the one wanted
+     */
+    pub(crate) fn find<F: Fn(&T) -> Ordering>(&self, cmp: F) -> Option<&T> {
+        let mut x = &self.root;
+        while let Some(n) = x {
+            match cmp(&n.key) {
+                Ordering::Greater => x = &n.left,
+                Ordering::Less => x = &n.right,
+                Ordering::Equal => return Some(&n.key),
+            }
+        }
+        None
+    }
+
+    // like range, with the bounds on something other than a whole key
+    pub(crate) fn range_by<F: Fn(&T) -> bool>(&self, before: F) -> Range<'_, T, RangeFull> {
+        Range {
+            stack: self.seek(before),
+            range: ..,
+        }
+    }
+
+    // whether two versions are the very same tree, without looking at keys
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+
+    fn draw(x: &Link<T>, depth: usize, options: &PrettyOptions) -> Option<(Drawn, usize, usize)>
+    where
+        T: Debug,
+    {
+        let n = x.as_ref()?;
+        let child = |c: &Link<T>| match c {
+            None => (None, 0, 0),
+            // nodes know their size, so a hidden subtree costs one path
+            Some(_) if Drawn::cut(depth, options) => {
+                (Some(Drawn::Truncated), size(c), black_height(c))
+            }
+            Some(_) => Self::draw(c, depth + 1, options)
+                .map_or((None, 0, 0), |(drawn, size, bh)| (Some(drawn), size, bh)),
+        };
+        Some(Drawn::node(
+            &n.key,
+            n.red,
+            [child(&n.left), child(&n.right)],
+        ))
+    }
+}
+
+// keys of one version in ascending order, up to the end of the range
+pub struct Range<'a, T, R> {
+    stack: Vec<&'a Node<T>>,
+    range: R,
+}
+
+impl<'a, T: PartialOrd, R: RangeBounds<T>> Iterator for Range<'a, T, R> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let n = self.stack.pop()?;
+        let within = match self.range.end_bound() {
+            Bound::Included(end) => n.key <= *end,
+            Bound::Excluded(end) => n.key < *end,
+            Bound::Unbounded => true,
+        };
+        if !within {
+            self.stack.clear();
+            return None;
+        }
+        let mut x = &n.right;
+        while let Some(c) = x {
+            self.stack.push(c);
+            x = &c.left;
+        }
+        Some(&n.key)
+    }
+}
+
+impl<'a, T: PartialOrd + Clone> IntoIterator for &'a PersistentRedBlack<T> {
+    type Item = &'a T;
+    type IntoIter = Range<'a, T, RangeFull>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// the mutable interface moves self on to the next version
+impl<T: PartialOrd + Clone> RedBlack<T> for PersistentRedBlack<T> {
+    fn new() -> Self {
+        PersistentRedBlack::default()
+    }
+
+    fn insert(&mut self, key: T) {
+        *self = PersistentRedBlack::insert(self, key);
+    }
+
+    fn delete(&mut self, key: &T) {
+        *self = PersistentRedBlack::delete(self, key);
+    }
+
+    fn search(&mut self, key: &T) -> Option<&T> {
+        PersistentRedBlack::search(self, key)
+    }
+
+    fn pretty_with(&self, options: PrettyOptions) -> String
+    where
+        T: Debug,
+    {
+        pretty::render(Self::draw(&self.root, 0, &options).map(|d| d.0), &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // black height, checking colors and order along the way
+    fn check<T: PartialOrd + Copy + Debug>(x: &Link<T>, lo: Option<T>, hi: Option<T>) -> usize {
+        let n = match x {
+            None => return 1,
+            Some(n) => n,
+        };
+        assert!(lo.is_none_or(|lo| lo <= n.key), "{:?} out of order", n.key);
+        assert!(hi.is_none_or(|hi| n.key <= hi), "{:?} out of order", n.key);
+        if n.red {
+            assert!(
+                !is_red(&n.left) && !is_red(&n.right),
+                "red {:?} has a red child",
+                n.key
+            );
+        }
+        assert_eq!(n.len, size(&n.left) + size(&n.right) + 1);
+        let l = check(&n.left, lo, Some(n.key));
+        let r = check(&n.right, Some(n.key), hi);
+        assert_eq!(l, r, "black heights differ under {:?}", n.key);
+        l + if n.red { 0 } else { 1 }
+    }
+
+    fn is_valid<T: PartialOrd + Copy + Debug>(rb: &PersistentRedBlack<T>) {
+        assert!(!is_red(&rb.root));
+        check(&rb.root, None, None);
+        assert_eq!(rb.iter().count(), rb.len());
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut versions = vec![PersistentRedBlack::new()];
+        for i in 0..200u32 {
+            let next = versions.last().unwrap().insert(i * 37 % 200);
+            versions.push(next);
+        }
+        for i in (0..200u32).step_by(3) {
+            let next = versions.last().unwrap().delete(&(i * 11 % 200));
+            versions.push(next);
+        }
+
+        // every version still holds exactly what it held when it was made
+        let mut keys = Vec::new();
+        for (v, rb) in versions.iter().enumerate() {
+            is_valid(rb);
+            if v > 0 && v <= 200 {
+                keys.push((v as u32 - 1) * 37 % 200);
+                keys.sort_unstable();
+            } else if v > 200 {
+                let gone = (3 * (v as u32 - 201)) * 11 % 200;
+                keys.retain(|k| *k != gone);
+            }
+            assert_eq!(rb.iter().copied().collect::<Vec<_>>(), keys);
+        }
+    }
+
+    #[test]
+    fn test_shared_and_missing() {
+        let rb: PersistentRedBlack<i32> =
+            (0..100).fold(PersistentRedBlack::new(), |rb, i| rb.insert(i));
+        let copy = rb.clone();
+        assert!(copy.ptr_eq(&rb));
+        let same = rb.delete(&1000);
+        assert!(same.ptr_eq(&rb));
+        assert_eq!(same.len(), 100);
+
+        let next = rb.insert(50);
+        assert!(!next.ptr_eq(&rb));
+        assert_eq!(
+            next.range(49..=51).copied().collect::<Vec<_>>(),
+            vec![49, 50, 50, 51]
+        );
+        assert_eq!(
+            rb.range(49..=51).copied().collect::<Vec<_>>(),
+            vec![49, 50, 51]
+        );
+        assert_eq!(rb.range(..3).count(), 3);
+        assert_eq!(rb.range(98..).copied().collect::<Vec<_>>(), vec![98, 99]);
+        is_valid(&next);
+    }
+
+    #[test]
+    fn test_as_red_black() {
+        let mut rb: PersistentRedBlack<u32> = RedBlack::new();
+        for i in 0..2000 {
+            RedBlack::insert(&mut rb, i * 7919 % 2000);
+        }
+        is_valid(&rb);
+        for i in 0..1000 {
+            RedBlack::delete(&mut rb, &(i * 13 % 2000));
+            is_valid(&rb);
+        }
+        assert_eq!(rb.len(), 1000);
+        let deleted: Vec<u32> = (0..1000).map(|i| i * 13 % 2000).collect();
+        for i in 0..2000 {
+            let found = RedBlack::search(&mut rb, &i).copied();
+            assert_eq!(found.is_none(), deleted.contains(&i));
+        }
+        assert_eq!(
+            PersistentRedBlack::new().insert(1).pretty(),
+            "1 B n=1 bh=1\n"
+        );
+    }
+}
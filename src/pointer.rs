@@ -1,340 +1,242 @@
-use crate::redblack::RedBlack;
-use std::{mem, ptr};
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::alloc::{self, Layout};
+use std::ptr::{self, NonNull};
+use std::{fmt, mem};
+
+/// Modelled on the unstable `std::alloc::Allocator`: hand out blocks for a
+/// layout, take them back later.
+///
+/// # Safety
+///
+/// The store writes a node straight into whatever `allocate` returns, so the
+/// block must be valid and suitably aligned for `layout` until it is passed
+/// back to `deallocate`.
+pub unsafe trait NodeAllocator {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `allocate` on this allocator with the same
+    /// `layout`, and must not be used afterwards.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
 
-#[cfg(test)]
-use std::collections::VecDeque;
+// the global allocator, i.e. what Box uses
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+unsafe impl NodeAllocator for Global {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { alloc::alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        alloc::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+// like std, an allocator can be lent to several stores
+unsafe impl<A: NodeAllocator + ?Sized> NodeAllocator for &A {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+}
 
 struct Node<T> {
     parent: *mut Node<T>,
     children: [*mut Node<T>; 2],
-    key: T,
+    key: mem::MaybeUninit<T>,
     red: bool,
 }
 
-fn new_node_ptr<T>(key: T, nil_sentinel: *mut Node<T>) -> *mut Node<T> {
-    // use Box to allocate nodes on the heap
-    let node = Node::new(key, nil_sentinel);
-    Box::into_raw(Box::new(node))
-}
-
 impl<T> Node<T> {
     fn new(key: T, nil_sentinel: *mut Node<T>) -> Node<T> {
         Node {
             parent: nil_sentinel,
             children: [nil_sentinel, nil_sentinel],
-            key,
+            key: mem::MaybeUninit::new(key),
             red: false,
         }
     }
 
-    unsafe fn nil_sentinel() -> *mut Node<T> {
-        new_node_ptr(
-            mem::MaybeUninit::<T>::uninit().assume_init(),
-            ptr::null_mut(),
-        )
+    fn nil_sentinel() -> Node<T> {
+        Node {
+            parent: ptr::null_mut(),
+            children: [ptr::null_mut(), ptr::null_mut()],
+            key: mem::MaybeUninit::uninit(),
+            red: false,
+        }
     }
 }
 
-pub struct PointerRedBlack<T> {
-    root: *mut Node<T>,
-    nil_sentinel: *mut Node<T>,
+/*
+ * opaque link type of PointerStore, it can only be made by the store itself
+ */
+pub struct NodePtr<T>(*mut Node<T>);
+
+impl<T> Clone for NodePtr<T> {
+    fn clone(&self) -> NodePtr<T> {
+        *self
+    }
 }
 
-impl<T> PointerRedBlack<T>
-where
-    T: std::cmp::PartialOrd,
-{
-    unsafe fn rotate(&mut self, x: *mut Node<T>, dir: usize) {
-        let y = (*x).children[dir ^ 1];
-        (*x).children[dir ^ 1] = (*y).children[dir];
-        if (*y).children[dir] != self.nil_sentinel {
-            (*(*y).children[dir]).parent = x;
-        }
-        (*y).parent = (*x).parent;
-        if (*x).parent == self.nil_sentinel {
-            self.root = y;
-        } else {
-            let sib_dir = if (*(*x).parent).children[0] == x {
-                0
-            } else {
-                1
-            };
-            (*(*x).parent).children[sib_dir] = y;
-        }
-        (*y).children[dir] = x;
-        (*x).parent = y;
+impl<T> Copy for NodePtr<T> {}
+
+impl<T> PartialEq for NodePtr<T> {
+    fn eq(&self, other: &NodePtr<T>) -> bool {
+        self.0 == other.0
     }
+}
 
-    unsafe fn tree_minimum(&mut self, mut x: *mut Node<T>) -> *mut Node<T> {
-        let mut l = (*x).children[0];
-        while l != self.nil_sentinel {
-            x = l;
-            l = (*x).children[0];
-        }
-        x
+impl<T> fmt::Debug for NodePtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:p}", self.0)
     }
+}
 
-    unsafe fn tree_successor(&mut self, mut x: *mut Node<T>) -> *mut Node<T> {
-        if (*x).children[1] != self.nil_sentinel {
-            return self.tree_minimum(x);
-        }
-        let mut y = (*x).parent;
-        while y != self.nil_sentinel && x == (*y).children[1] {
-            x = y;
-            y = (*y).parent;
-        }
-        y
-    }
-
-    unsafe fn insert_fixup(&mut self, mut z: *mut Node<T>) {
-        while (*(*z).parent).red {
-            let dir = if (*(*(*z).parent).parent).children[0] == (*z).parent {
-                1
-            } else {
-                0
-            };
-
-            let y = (*(*(*z).parent).parent).children[dir];
-
-            if (*y).red {
-                (*(*z).parent).red = false;
-                (*y).red = false;
-                (*(*(*z).parent).parent).red = true;
-                z = (*(*z).parent).parent;
-            } else {
-                // y is black, or nil sentinel
-                if z == (*(*z).parent).children[dir] {
-                    z = (*z).parent;
-                    self.rotate(z, dir ^ 1);
-                }
-                (*(*z).parent).red = false;
-                (*(*(*z).parent).parent).red = true;
-                self.rotate((*(*z).parent).parent, dir);
-            }
-        }
+/*
+ * every link handed out by this store is a live allocation from `allocator`
+ * until it is passed to free, which is what makes the derefs below sound
+ */
+pub struct PointerStore<T, A: NodeAllocator = Global> {
+    nil_sentinel: *mut Node<T>,
+    allocator: A,
+    live: usize,
+}
 
-        // blacken the root
-        (*self.root).red = false;
-    }
-
-    unsafe fn delete_fixup(&mut self, mut x: *mut Node<T>) {
-        while x != self.root && !(*x).red {
-            let dir = if x == (*(*x).parent).children[0] {
-                1
-            } else {
-                0
-            };
-            let mut w = (*(*x).parent).children[dir];
-            if (*w).red {
-                (*w).red = false;
-                (*(*x).parent).red = true;
-                self.rotate((*x).parent, dir ^ 1);
-                w = (*(*x).parent).children[dir];
-            }
-            let wl = (*w).children[0];
-            let wr = (*w).children[1];
-            if !(*wl).red && !(*wr).red {
-                (*w).red = true;
-                x = (*x).parent;
-            } else {
-                let mut wc = (*w).children[dir]; // w child i care about
-                let wo = (*w).children[dir ^ 1]; // w other child
-                if !(*wc).red {
-                    (*wo).red = false;
-                    (*w).red = true;
-                    self.rotate(w, dir);
-                    w = (*(*x).parent).children[dir];
-
-                    // recompute wc after the rotation of w
-                    wc = (*w).children[dir];
-                }
-                (*w).red = (*(*x).parent).red;
-                (*(*x).parent).red = false;
-                (*wc).red = false;
-                self.rotate((*x).parent, dir ^ 1);
-                x = self.root
-            }
-        }
+impl<T> PointerStore<T> {
+    pub fn new() -> PointerStore<T> {
+        PointerStore::with_allocator(Global)
+    }
+}
 
-        // blacken x
-        (*x).red = false;
+impl<T> Default for PointerStore<T> {
+    fn default() -> PointerStore<T> {
+        PointerStore::new()
     }
+}
 
-    unsafe fn search_(&mut self, key: &T) -> Option<*mut Node<T>> {
-        let mut curr = self.root;
+impl<T, A: NodeAllocator> PointerStore<T, A> {
+    pub fn with_allocator(allocator: A) -> PointerStore<T, A> {
+        let mut store = PointerStore {
+            nil_sentinel: ptr::null_mut(),
+            allocator,
+            live: 0,
+        };
+        store.nil_sentinel = store.new_node_ptr(Node::nil_sentinel());
+        store
+    }
 
-        while curr != self.nil_sentinel {
-            if (*curr).key == *key {
-                return Some(curr);
-            }
-            let direction = if (*curr).key < *key { 1 } else { 0 };
-            curr = (*curr).children[direction];
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    fn new_node_ptr(&self, node: Node<T>) -> *mut Node<T> {
+        let layout = Layout::new::<Node<T>>();
+        let x = match self.allocator.allocate(layout) {
+            Some(block) => block.as_ptr() as *mut Node<T>,
+            None => alloc::handle_alloc_error(layout),
+        };
+        unsafe {
+            x.write(node);
         }
-        None
-    }
-
-    #[cfg(test)]
-    unsafe fn is_valid(&self) {
-        /*
-         * properties
-         * - root property: root is black
-         * - leaf nodes (NULL) are black (pointless here given my sentinel is a NULL, not a real node)
-         * - red property: children of a red node are black
-         * - simple path from node to descendant leaf contains same number of black nodes
-         */
-        unsafe fn verify_black_height<T>(rb: &PointerRedBlack<T>, x: *mut Node<T>) -> i32 {
-            if x == rb.nil_sentinel {
-                return 0;
-            }
-            let left_height = verify_black_height(rb, (*x).children[0]);
-            let right_height = verify_black_height(rb, (*x).children[1]);
+        x
+    }
 
-            assert!(
-                left_height != -1 && right_height != -1 && left_height == right_height,
-                "red-black properties have been violated!"
-            );
+    unsafe fn release_node_ptr(&self, x: *mut Node<T>) -> Node<T> {
+        let node = x.read();
+        self.allocator.deallocate(
+            NonNull::new_unchecked(x as *mut u8),
+            Layout::new::<Node<T>>(),
+        );
+        node
+    }
+}
 
-            let add = if (*x).red { 0 } else { 1 };
-            left_height + add
+impl<T, A: NodeAllocator> Drop for PointerStore<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            self.release_node_ptr(self.nil_sentinel);
         }
+    }
+}
 
-        unsafe fn verify_children_color<T>(rb: &PointerRedBlack<T>) -> bool {
-            if rb.root == rb.nil_sentinel {
-                return true;
-            }
-            let mut queue: VecDeque<*mut Node<T>> = VecDeque::new();
-            queue.push_front(rb.root);
-
-            while !queue.is_empty() {
-                let curr = queue.pop_front().unwrap();
-                if curr == rb.nil_sentinel {
-                    break;
-                }
-
-                let l = (*curr).children[0];
-                let r = (*curr).children[1];
-
-                // red node must not have red children
-                if (*curr).red {
-                    assert!(!(*l).red && !(*r).red, "red node has red children");
-                }
-
-                if l != rb.nil_sentinel {
-                    queue.push_back(l);
-                }
-                if r != rb.nil_sentinel {
-                    queue.push_back(r);
-                }
-            }
-            true
-        }
+impl<T, A: NodeAllocator> NodeStore<T> for PointerStore<T, A> {
+    type Link = NodePtr<T>;
 
-        assert!(!(*self.root).red); // root is black
-        verify_children_color(self);
-        verify_black_height(self, self.root);
+    fn nil(&self) -> NodePtr<T> {
+        NodePtr(self.nil_sentinel)
     }
-}
 
-impl<T> RedBlack<T> for PointerRedBlack<T>
-where
-    T: std::cmp::PartialOrd,
-{
-    fn new() -> PointerRedBlack<T> {
-        let mut rb = PointerRedBlack {
-            root: ptr::null_mut(),
-            nil_sentinel: ptr::null_mut(),
-        };
+    fn alloc(&mut self, key: T) -> NodePtr<T> {
+        self.live += 1;
+        NodePtr(self.new_node_ptr(Node::new(key, self.nil_sentinel)))
+    }
 
+    fn free(&mut self, x: NodePtr<T>) -> T {
+        self.live -= 1;
         unsafe {
-            let nil_sentinel = Node::nil_sentinel();
-            rb.nil_sentinel = nil_sentinel;
-            rb.root = nil_sentinel;
+            let node = self.release_node_ptr(x.0);
+            node.key.assume_init()
         }
-        rb
     }
 
-    fn search(&mut self, key: &T) -> Option<&T> {
-        unsafe {
-            if let Some(found_node) = self.search_(key) {
-                return Some(&(*found_node).key);
-            }
-            None
-        }
+    fn parent(&self, x: NodePtr<T>) -> NodePtr<T> {
+        unsafe { NodePtr((*x.0).parent) }
     }
 
-    fn delete(&mut self, key: &T) {
-        unsafe {
-            let z = match self.search_(key) {
-                Some(found_node) => found_node,
-                None => {
-                    return;
-                }
-            };
-
-            let y =
-                if (*z).children[0] == self.nil_sentinel || (*z).children[1] == self.nil_sentinel {
-                    z
-                } else {
-                    self.tree_successor(z)
-                };
-
-            let dir = if (*y).children[0] != self.nil_sentinel {
-                0
-            } else {
-                1
-            };
-            let x = (*y).children[dir];
-
-            let yp = (*y).parent;
-
-            (*x).parent = yp;
-
-            if yp == self.nil_sentinel {
-                self.root = x;
-            } else {
-                let dir = if y == (*yp).children[0] { 0 } else { 1 };
-                (*yp).children[dir] = x;
-            }
+    fn set_parent(&mut self, x: NodePtr<T>, parent: NodePtr<T>) {
+        unsafe { (*x.0).parent = parent.0 }
+    }
 
-            if y != z {
-                mem::swap(&mut (*z).key, &mut (*y).key);
-            }
-            if !(*y).red {
-                self.delete_fixup(x);
-            }
-        }
+    fn child(&self, x: NodePtr<T>, dir: usize) -> NodePtr<T> {
+        unsafe { NodePtr((*x.0).children[dir]) }
     }
 
-    fn insert(&mut self, key: T) {
-        unsafe {
-            let mut z = new_node_ptr(key, self.nil_sentinel);
+    fn set_child(&mut self, x: NodePtr<T>, dir: usize, child: NodePtr<T>) {
+        unsafe { (*x.0).children[dir] = child.0 }
+    }
 
-            let mut y = self.nil_sentinel;
-            let mut x = self.root;
+    fn is_red(&self, x: NodePtr<T>) -> bool {
+        unsafe { (*x.0).red }
+    }
 
-            while x != self.nil_sentinel {
-                y = x;
-                let dir = if (*z).key < (*x).key { 0 } else { 1 };
-                x = (*x).children[dir];
-            }
+    fn set_red(&mut self, x: NodePtr<T>, red: bool) {
+        unsafe { (*x.0).red = red }
+    }
 
-            (*z).parent = y;
-            if y == self.nil_sentinel {
-                self.root = z;
-            } else {
-                let dir = if (*z).key < (*y).key { 0 } else { 1 };
-                (*y).children[dir] = z;
-            }
+    fn key(&self, x: NodePtr<T>) -> &T {
+        debug_assert!(x.0 != self.nil_sentinel);
+        unsafe { &*(*x.0).key.as_ptr() }
+    }
 
-            (*z).red = true;
-            self.insert_fixup(z);
-        }
+    fn key_mut(&mut self, x: NodePtr<T>) -> &mut T {
+        debug_assert!(x.0 != self.nil_sentinel);
+        unsafe { &mut *(*x.0).key.as_mut_ptr() }
+    }
+
+    // every node is its own allocation, so there is never a spare slot
+    fn capacity(&self) -> usize {
+        self.live
+    }
+
+    fn slot_size(&self) -> usize {
+        mem::size_of::<Node<T>>()
     }
 }
 
+pub type PointerRedBlack<T> = RedBlackTree<T, PointerStore<T>>;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::redblack::RedBlack;
+    use crate::tree::workload;
 
     #[test]
     fn test_basic_insert() {
@@ -348,9 +250,7 @@ mod tests {
         assert_eq!(rb.search(&6), Some(&6));
         assert_eq!(rb.search(&7), Some(&7));
 
-        unsafe {
-            rb.is_valid(); // will panic if it must
-        }
+        rb.is_valid(); // will panic if it must
     }
 
     #[test]
@@ -358,76 +258,80 @@ mod tests {
         let mut num = 1u32;
         let mut rb: PointerRedBlack<u32> = PointerRedBlack::new();
 
-        for _ in 0..1000000 {
+        for _ in 0..workload(1000000) {
             num = num.wrapping_mul(17).wrapping_add(255);
             rb.insert(num);
         }
 
-        unsafe {
-            rb.is_valid(); // will panic if it must
-        }
+        rb.is_valid(); // will panic if it must
     }
 
     #[test]
     fn test_many_insert_some_delete() {
         let mut rb: PointerRedBlack<i32> = PointerRedBlack::new();
+        let n = workload(1000000) as i32;
 
-        for i in 500000..1000000 {
+        for i in n / 2..n {
             rb.insert(i);
-            rb.insert(1000000 - i);
+            rb.insert(n - i);
         }
 
-        assert_eq!(rb.search(&5), Some(&5));
-        assert_eq!(rb.search(&50), Some(&50));
-        assert_eq!(rb.search(&500), Some(&500));
-        assert_eq!(rb.search(&5000), Some(&5000));
-        assert_eq!(rb.search(&50000), Some(&50000));
-        assert_eq!(rb.search(&500000), Some(&500000));
-
-        unsafe {
-            rb.is_valid(); // will panic if it must
+        // as many of these as the workload reaches
+        let probes: Vec<i32> = [5, 50, 500, 5000, 50000, 500000]
+            .iter()
+            .copied()
+            .filter(|key| *key <= n / 2)
+            .collect();
+        for key in &probes {
+            assert_eq!(rb.search(key), Some(key));
         }
 
-        rb.delete(&5); // the spliced-out node doesn't necessarily have to be the deleted one
-        unsafe {
-            rb.is_valid(); // will panic if it must
-        }
-        rb.delete(&5);
-        assert_eq!(rb.search(&5), None);
+        rb.is_valid(); // will panic if it must
 
-        rb.delete(&50);
-        unsafe {
+        for key in &probes {
+            rb.delete(key); // the spliced-out node doesn't necessarily have to be the deleted one
             rb.is_valid(); // will panic if it must
+            rb.delete(key);
+            assert_eq!(rb.search(key), None);
         }
-        rb.delete(&50);
-        assert_eq!(rb.search(&50), None);
+    }
 
-        rb.delete(&500);
-        unsafe {
-            rb.is_valid(); // will panic if it must
+    #[test]
+    fn test_custom_allocator() {
+        use std::cell::Cell;
+
+        #[derive(Default)]
+        struct Counting {
+            live: Cell<usize>,
         }
-        rb.delete(&500);
-        assert_eq!(rb.search(&500), None);
 
-        rb.delete(&5000);
-        unsafe {
-            rb.is_valid(); // will panic if it must
+        unsafe impl NodeAllocator for Counting {
+            fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+                self.live.set(self.live.get() + 1);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.live.set(self.live.get() - 1);
+                Global.deallocate(ptr, layout)
+            }
         }
-        rb.delete(&5000);
-        assert_eq!(rb.search(&5000), None);
 
-        rb.delete(&50000);
-        unsafe {
-            rb.is_valid(); // will panic if it must
+        let counting = Counting::default();
+        let mut rb = RedBlackTree::with_store(PointerStore::with_allocator(&counting));
+
+        for i in 0..1000 {
+            rb.insert(i);
         }
-        rb.delete(&50000);
-        assert_eq!(rb.search(&50000), None);
+        assert_eq!(counting.live.get(), 1001); // every key plus the nil sentinel
 
-        rb.delete(&500000);
-        unsafe {
-            rb.is_valid(); // will panic if it must
+        for i in 0..500 {
+            rb.delete(&i);
         }
-        rb.delete(&500000);
-        assert_eq!(rb.search(&500000), None);
+        assert_eq!(counting.live.get(), 501);
+        rb.is_valid(); // will panic if it must
+
+        drop(rb);
+        assert_eq!(counting.live.get(), 0);
     }
 }
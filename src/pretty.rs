@@ -0,0 +1,365 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::fmt::Debug;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrettyStyle {
+    // root on the left, right subtree above and left subtree below it
+    Sideways,
+    // root on top, one text row per level
+    TopDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrettyOptions {
+    pub style: PrettyStyle,
+    // levels below this are collapsed into a "..." marker
+    pub max_depth: Option<usize>,
+    // box-drawing characters instead of plain ASCII for the branches
+    pub unicode: bool,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> PrettyOptions {
+        PrettyOptions {
+            style: PrettyStyle::Sideways,
+            max_depth: Some(6),
+            unicode: true,
+        }
+    }
+}
+
+// what gets drawn for one node: its label, or a marker for a cut-off subtree
+pub(crate) enum Drawn {
+    Node {
+        label: String,
+        children: Box<[Option<Drawn>; 2]>,
+    },
+    Truncated,
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    // render the tree for a terminal, see Drawn::node for the labels
+    pub fn pretty_with(&self, options: PrettyOptions) -> String
+    where
+        T: Debug,
+    {
+        render(self.draw(self.root, 0, &options).map(|d| d.0), &options)
+    }
+
+    // returns the drawing of x with its subtree size and black height
+    fn draw(
+        &self,
+        x: S::Link,
+        depth: usize,
+        options: &PrettyOptions,
+    ) -> Option<(Drawn, usize, usize)>
+    where
+        T: Debug,
+    {
+        if x == self.store.nil() {
+            return None;
+        }
+        let (left, l_size, l_bh) = self.draw_child(x, 0, depth, options);
+        let (right, r_size, _) = self.draw_child(x, 1, depth, options);
+
+        let red = self.store.is_red(x);
+        let children = [(left, l_size, l_bh), (right, r_size, 0)];
+        Some(
+            self.store
+                .with_key(x, |key| Drawn::node(key, red, children)),
+        )
+    }
+
+    fn draw_child(
+        &self,
+        x: S::Link,
+        dir: usize,
+        depth: usize,
+        options: &PrettyOptions,
+    ) -> (Option<Drawn>, usize, usize)
+    where
+        T: Debug,
+    {
+        let c = self.store.child(x, dir);
+        if c == self.store.nil() {
+            (None, 0, 0)
+        } else if Drawn::cut(depth, options) {
+            let (size, bh) = self.measure(c);
+            (Some(Drawn::Truncated), size, bh)
+        } else {
+            self.draw(c, depth + 1, options)
+                .map_or((None, 0, 0), |(drawn, size, bh)| (Some(drawn), size, bh))
+        }
+    }
+
+    // size and black height of a hidden subtree, without drawing any of it
+    fn measure(&self, x: S::Link) -> (usize, usize) {
+        if x == self.store.nil() {
+            return (0, 0);
+        }
+        let (l_size, l_bh) = self.measure(self.store.child(x, 0));
+        let (r_size, _) = self.measure(self.store.child(x, 1));
+        let black = if self.store.is_red(x) { 0 } else { 1 };
+        (l_size + r_size + 1, l_bh + black)
+    }
+}
+
+impl Drawn {
+    /*
+     * a node labelled with its key, color (R/B), subtree size n and black
+     * height bh, e.g. `5 B n=3 bh=2`; children come with their own size
+     * and black height, returned alongside the node
+     */
+    pub(crate) fn node<T: Debug>(
+        key: &T,
+        red: bool,
+        children: [(Option<Drawn>, usize, usize); 2],
+    ) -> (Drawn, usize, usize) {
+        let [(left, l_size, l_bh), (right, r_size, _)] = children;
+        let size = l_size + r_size + 1;
+        let bh = l_bh + if red { 0 } else { 1 };
+        let label = format!(
+            "{:?} {} n={} bh={}",
+            key,
+            if red { 'R' } else { 'B' },
+            size,
+            bh
+        );
+        let drawn = Drawn::Node {
+            label,
+            children: Box::new([left, right]),
+        };
+        (drawn, size, bh)
+    }
+
+    /*
+     * whether the children of a node at depth are collapsed to "..."; a
+     * collapsed subtree is never drawn, only measured, since sizes and
+     * black heights still count the hidden levels
+     */
+    pub(crate) fn cut(depth: usize, options: &PrettyOptions) -> bool {
+        options.max_depth.is_some_and(|max| depth + 1 >= max)
+    }
+}
+
+pub(crate) fn render(root: Option<Drawn>, options: &PrettyOptions) -> String {
+    let drawn = match root {
+        Some(drawn) => drawn,
+        None => return String::from("(empty)\n"),
+    };
+    let mut out = String::new();
+    match options.style {
+        PrettyStyle::Sideways => sideways(&drawn, "", None, options.unicode, &mut out),
+        PrettyStyle::TopDown => top_down(&drawn, options.unicode, &mut out),
+    }
+    out
+}
+
+fn label(drawn: &Drawn) -> &str {
+    match drawn {
+        Drawn::Node { label, .. } => label,
+        Drawn::Truncated => "...",
+    }
+}
+
+fn children(drawn: &Drawn) -> [Option<&Drawn>; 2] {
+    match drawn {
+        Drawn::Node { children, .. } => [children[0].as_ref(), children[1].as_ref()],
+        Drawn::Truncated => [None, None],
+    }
+}
+
+/*
+ *         ┌── 9 R n=1 bh=0
+ *     ┌── 8 B n=2 bh=1
+ * 5 B n=4 bh=2
+ *     └── 1 B n=1 bh=1
+ */
+fn sideways(drawn: &Drawn, prefix: &str, dir: Option<usize>, unicode: bool, out: &mut String) {
+    let (bar, up, down) = if unicode {
+        ("│   ", "┌── ", "└── ")
+    } else {
+        ("|   ", "/-- ", "\\-- ")
+    };
+    let [left, right] = children(drawn);
+
+    // the bar continues past this node towards the sibling on the other side
+    if let Some(right) = right {
+        let pad = if dir == Some(0) { bar } else { "    " };
+        sideways(right, &format!("{}{}", prefix, pad), Some(1), unicode, out);
+    }
+
+    out.push_str(prefix);
+    match dir {
+        Some(0) => out.push_str(down),
+        Some(_) => out.push_str(up),
+        None => {}
+    }
+    out.push_str(label(drawn));
+    out.push('\n');
+
+    if let Some(left) = left {
+        let pad = if dir == Some(1) { bar } else { "    " };
+        sideways(left, &format!("{}{}", prefix, pad), Some(0), unicode, out);
+    }
+}
+
+/*
+ *               5 B n=3 bh=1
+ *       ┌─────────────┴─────────────┐
+ * 1 R n=1 bh=0                8 R n=1 bh=0
+ *
+ * every node gets its own columns, in key order, so labels never overlap
+ */
+fn top_down(drawn: &Drawn, unicode: bool, out: &mut String) {
+    struct Placed {
+        depth: usize,
+        start: usize,
+        width: usize,
+        parent: Option<usize>,
+    }
+
+    fn place<'a>(
+        drawn: &'a Drawn,
+        depth: usize,
+        parent: Option<usize>,
+        column: &mut usize,
+        placed: &mut Vec<(Placed, &'a str)>,
+    ) {
+        let [left, right] = children(drawn);
+        let me = placed.len();
+        let text = label(drawn);
+        placed.push((
+            Placed {
+                depth,
+                start: 0,
+                width: text.chars().count(),
+                parent,
+            },
+            text,
+        ));
+        if let Some(left) = left {
+            place(left, depth + 1, Some(me), column, placed);
+        }
+        placed[me].0.start = *column;
+        *column += placed[me].0.width + 2;
+        if let Some(right) = right {
+            place(right, depth + 1, Some(me), column, placed);
+        }
+    }
+
+    let mut placed = Vec::new();
+    place(drawn, 0, None, &mut 0, &mut placed);
+    let (h_line, left_corner, right_corner, tee) = if unicode {
+        ('─', '┌', '┐', '┴')
+    } else {
+        ('-', '+', '+', '+')
+    };
+
+    let depth = placed.iter().map(|(p, _)| p.depth).max().unwrap_or(0);
+    let width = placed
+        .iter()
+        .map(|(p, _)| p.start + p.width)
+        .max()
+        .unwrap_or(0);
+    let center = |p: &Placed| p.start + p.width / 2;
+
+    for level in 0..=depth {
+        // connectors from the parents one level up down to this level
+        if level > 0 {
+            let mut row = vec![' '; width];
+            for (p, _) in placed.iter().filter(|(p, _)| p.depth == level) {
+                let from = center(&placed[p.parent.unwrap()].0);
+                let to = center(p);
+                let (lo, hi) = (from.min(to), from.max(to));
+                for cell in row.iter_mut().take(hi).skip(lo + 1) {
+                    *cell = h_line;
+                }
+                row[to] = if to < from { left_corner } else { right_corner };
+                row[from] = tee;
+            }
+            out.push_str(row.iter().collect::<String>().trim_end());
+            out.push('\n');
+        }
+
+        let mut row = vec![' '; width];
+        for (p, text) in placed.iter().filter(|(p, _)| p.depth == level) {
+            for (i, c) in text.chars().enumerate() {
+                row[p.start + i] = c;
+            }
+        }
+        out.push_str(row.iter().collect::<String>().trim_end());
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    fn small() -> SlabRedBlack<i32> {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        for i in &[5, 1, 8, 9] {
+            rb.insert(*i);
+        }
+        rb
+    }
+
+    #[test]
+    fn test_pretty_sideways() {
+        assert_eq!(
+            small().pretty(),
+            "        ┌── 9 R n=1 bh=0
+    ┌── 8 B n=2 bh=1
+5 B n=4 bh=2
+    └── 1 B n=1 bh=1
+"
+        );
+    }
+
+    #[test]
+    fn test_pretty_top_down_ascii() {
+        let options = PrettyOptions {
+            style: PrettyStyle::TopDown,
+            max_depth: None,
+            unicode: false,
+        };
+        assert_eq!(
+            small().pretty_with(options),
+            "              5 B n=4 bh=2
+      +-------------+-------------+
+1 B n=1 bh=1                8 B n=2 bh=1
+                                  +-------------+
+                                          9 R n=1 bh=0
+"
+        );
+    }
+
+    #[test]
+    fn test_pretty_max_depth() {
+        let mut rb: PointerRedBlack<i32> = PointerRedBlack::new();
+        assert_eq!(rb.pretty(), "(empty)\n");
+
+        for i in 0..1000 {
+            rb.insert(i);
+        }
+        let options = PrettyOptions {
+            max_depth: Some(2),
+            ..PrettyOptions::default()
+        };
+        let text = rb.pretty_with(options);
+
+        // root, its two children, and four cut-off grandchildren
+        assert_eq!(text.lines().count(), 7);
+        assert_eq!(text.matches("...").count(), 4);
+        assert!(text.contains("n=1000"));
+    }
+}
@@ -1,6 +1,25 @@
+use crate::pretty::PrettyOptions;
+use std::fmt::Debug;
+
 pub trait RedBlack<T> {
     fn new() -> Self;
     fn insert(&mut self, key: T);
     fn delete(&mut self, key: &T);
     fn search(&mut self, key: &T) -> Option<&T>;
+
+    // terminal rendering, see pretty.rs; types that can't show their
+    // nodes keep this placeholder
+    fn pretty_with(&self, _options: PrettyOptions) -> String
+    where
+        T: Debug,
+    {
+        format!("({} has no tree view)\n", std::any::type_name::<Self>())
+    }
+
+    fn pretty(&self) -> String
+    where
+        T: Debug,
+    {
+        self.pretty_with(PrettyOptions::default())
+    }
 }
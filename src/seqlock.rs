@@ -0,0 +1,434 @@
+use crate::memory::ReserveError;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{mem, ptr, thread};
+
+/// Keys that can be read while they're being written: `Copy`, and every
+/// bit pattern is a valid value, so a torn read is just a wrong key.
+///
+/// # Safety
+///
+/// Only implement this for plain-data types of at most 16 bytes with no
+/// invalid bit patterns, no padding and no pointers (no `bool`, `char`,
+/// enums or references).
+pub unsafe trait SeqlockKey: Copy + PartialOrd {}
+
+macro_rules! seqlock_key {
+    ($($t:ty),*) => {
+        $(unsafe impl SeqlockKey for $t {})*
+    };
+}
+
+seqlock_key!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+/*
+ * a red-black tree that readers search without taking any lock (seqlock)
+ *
+ * writers take the tree's mutex and bump `seq` to odd before an insert or
+ * delete and back to even after it, so every rotation and recolor of the
+ * fixup happens while it's odd. a reader notes seq, walks the tree, and
+ * keeps the answer only if seq was even and is unchanged; otherwise a
+ * write overlapped the walk and it tries again
+ *
+ * every word a reader looks at is an atomic: links and color in AtomicU32s,
+ * keys bit-cast into two AtomicU64s. a walk that overlaps a write is no
+ * data race, it reads a mix of old and new values, and all the mix can do
+ * before it's thrown away is end the walk early:
+ *  - the nodes are allocated once, up front, and never move; a freed node
+ *    keeps its slot, so every link indexes a real node
+ *  - keys are SeqlockKey, so one read half before and half after a write
+ *    is still some valid key
+ *  - a walk longer than any red-black tree can be is treated as torn, so
+ *    a cycle seen mid-rotation can't trap a reader
+ *
+ * a panic in the middle of a write leaves seq odd and the tree perhaps
+ * half-linked, so it poisons the tree: later writes panic, and so do
+ * searches, rather than wait forever for seq to come back even
+ */
+pub struct SeqlockRedBlack<T: SeqlockKey> {
+    tree: Mutex<RedBlackTree<T, SeqlockStore<T>>>,
+    // the nodes the tree's store links, and its root, for readers
+    nodes: Arc<[Node]>,
+    root: AtomicU32,
+    seq: AtomicUsize,
+    len: AtomicUsize,
+    capacity: usize,
+}
+
+const POISONED: &str = "seqlock tree poisoned by a panic mid-write";
+
+// the top bit of a parent link holds the node color
+const RED: u32 = 1 << 31;
+const NIL: usize = 0;
+
+#[derive(Default)]
+struct Node {
+    parent: AtomicU32,
+    children: [AtomicU32; 2],
+    key: Words,
+}
+
+// a key's bytes, zero-padded; aligned for any SeqlockKey
+#[derive(Default)]
+#[repr(C, align(16))]
+struct Words([AtomicU64; 2]);
+
+impl Words {
+    fn load<T: SeqlockKey>(&self) -> T {
+        let words = [
+            self.0[0].load(Ordering::Relaxed),
+            self.0[1].load(Ordering::Relaxed),
+        ];
+        unsafe { ptr::read_unaligned(words.as_ptr() as *const T) }
+    }
+
+    fn store<T: SeqlockKey>(&self, key: T) {
+        let mut words = [0u64; 2];
+        unsafe { ptr::write_unaligned(words.as_mut_ptr() as *mut T, key) };
+        self.0[0].store(words[0], Ordering::Relaxed);
+        self.0[1].store(words[1], Ordering::Relaxed);
+    }
+}
+
+/*
+ * the writer's side of the nodes: slot 0 is the nil sentinel, freed slots
+ * are handed out again before fresh ones
+ */
+struct SeqlockStore<T> {
+    nodes: Arc<[Node]>,
+    free: Vec<usize>,
+    next: usize,
+    _key: PhantomData<T>,
+}
+
+impl<T: SeqlockKey> NodeStore<T> for SeqlockStore<T> {
+    type Link = usize;
+
+    fn nil(&self) -> usize {
+        NIL
+    }
+
+    fn alloc(&mut self, key: T) -> usize {
+        let x = match self.free.pop() {
+            Some(x) => x,
+            None => {
+                assert!(self.next < self.nodes.len(), "seqlock tree is full");
+                self.next += 1;
+                self.next - 1
+            }
+        };
+        let node = &self.nodes[x];
+        node.parent.store(NIL as u32, Ordering::Relaxed);
+        node.children[0].store(NIL as u32, Ordering::Relaxed);
+        node.children[1].store(NIL as u32, Ordering::Relaxed);
+        node.key.store(key);
+        x
+    }
+
+    // the slot keeps its last links and key, readers may still be on it
+    fn free(&mut self, x: usize) -> T {
+        self.free.push(x);
+        self.nodes[x].key.load()
+    }
+
+    fn parent(&self, x: usize) -> usize {
+        (self.nodes[x].parent.load(Ordering::Relaxed) & !RED) as usize
+    }
+
+    fn set_parent(&mut self, x: usize, parent: usize) {
+        let color = self.nodes[x].parent.load(Ordering::Relaxed) & RED;
+        self.nodes[x]
+            .parent
+            .store(color | parent as u32, Ordering::Relaxed);
+    }
+
+    fn child(&self, x: usize, dir: usize) -> usize {
+        self.nodes[x].children[dir].load(Ordering::Relaxed) as usize
+    }
+
+    fn set_child(&mut self, x: usize, dir: usize, child: usize) {
+        self.nodes[x].children[dir].store(child as u32, Ordering::Relaxed);
+    }
+
+    fn is_red(&self, x: usize) -> bool {
+        self.nodes[x].parent.load(Ordering::Relaxed) & RED != 0
+    }
+
+    fn set_red(&mut self, x: usize, red: bool) {
+        let color = if red { RED } else { 0 };
+        let parent = self.parent(x) as u32;
+        self.nodes[x]
+            .parent
+            .store(color | parent, Ordering::Relaxed);
+    }
+
+    // readers only ever load the words, so a plain shared read is no race
+    fn key(&self, x: usize) -> &T {
+        debug_assert!(x != NIL);
+        unsafe { &*(self.nodes[x].key.0.as_ptr() as *const T) }
+    }
+
+    // a plain write would race the readers' loads; keys only move in
+    // splice_out, which stores them atomically
+    fn key_mut(&mut self, _x: usize) -> &mut T {
+        unreachable!("seqlock keys are only written atomically")
+    }
+
+    fn splice_out(&mut self, y: usize, z: usize) {
+        let key = self.free(y);
+        if y != z {
+            self.nodes[z].key.store(key);
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    fn slot_size(&self) -> usize {
+        mem::size_of::<Node>()
+    }
+}
+
+impl<T: SeqlockKey> SeqlockRedBlack<T> {
+    // room for exactly `capacity` keys, it can't grow once readers are in
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(
+            mem::size_of::<T>() <= mem::size_of::<Words>(),
+            "key too big"
+        );
+        assert!(capacity < RED as usize, "too many keys for u32 links");
+        let nodes: Arc<[Node]> = (0..=capacity).map(|_| Node::default()).collect();
+        let store = SeqlockStore {
+            nodes: nodes.clone(),
+            free: Vec::new(),
+            next: 1,
+            _key: PhantomData,
+        };
+        SeqlockRedBlack {
+            tree: Mutex::new(RedBlackTree::with_store(store)),
+            nodes,
+            root: AtomicU32::new(NIL as u32),
+            seq: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // run one write with seq odd
+    fn write<R, F: FnOnce(&mut RedBlackTree<T, SeqlockStore<T>>) -> R>(&self, f: F) -> R {
+        let mut tree = self.tree.lock().expect(POISONED);
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        // the odd seq is visible before any of the writes below
+        fence(Ordering::Release);
+        let result = f(&mut tree);
+        self.root.store(tree.root as u32, Ordering::Relaxed);
+        self.len.store(tree.len(), Ordering::Relaxed);
+        self.seq.store(seq + 2, Ordering::Release);
+        result
+    }
+
+    // fails once the tree holds as many keys as it has room for
+    pub fn insert(&self, key: T) -> Result<(), ReserveError> {
+        self.write(|tree| {
+            if tree.len() >= self.capacity {
+                return Err(ReserveError::CapacityOverflow);
+            }
+            tree.insert(key);
+            Ok(())
+        })
+    }
+
+    pub fn delete(&self, key: &T) {
+        self.write(|tree| {
+            tree.delete(key);
+        })
+    }
+
+    // lock-free; retries for as long as writes keep overlapping it
+    pub fn search(&self, key: &T) -> Option<T> {
+        loop {
+            if let Some(found) = self.try_search(key) {
+                return found;
+            }
+            if self.tree.is_poisoned() {
+                panic!("{}", POISONED);
+            }
+            thread::yield_now();
+        }
+    }
+
+    // one optimistic attempt, None if a write got in the way
+    pub fn try_search(&self, key: &T) -> Option<Option<T>> {
+        let before = self.seq.load(Ordering::Acquire);
+        if before % 2 == 1 {
+            return None;
+        }
+        let found = self.racy_search(key);
+        // the walk's loads happen before seq is checked again
+        fence(Ordering::Acquire);
+        if self.seq.load(Ordering::Relaxed) != before {
+            return None;
+        }
+        found
+    }
+
+    // Some(found) if the walk held together, None if it went deeper than
+    // any red-black tree can be, so it was torn for sure
+    fn racy_search(&self, key: &T) -> Option<Option<T>> {
+        let mut x = self.root.load(Ordering::Relaxed) as usize;
+        for _ in 0..2 * u32::BITS {
+            if x == NIL {
+                return Some(None);
+            }
+            let node = &self.nodes[x];
+            let k: T = node.key.load();
+            if k == *key {
+                return Some(Some(k));
+            }
+            let dir = if k < *key { 1 } else { 0 };
+            x = node.children[dir].load(Ordering::Relaxed) as usize;
+        }
+        None
+    }
+
+    pub fn contains(&self, key: &T) -> bool {
+        self.search(key).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::workload;
+    use std::panic;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_capacity() {
+        let rb: SeqlockRedBlack<u32> = SeqlockRedBlack::with_capacity(100);
+        let capacity = rb.capacity();
+        assert_eq!(capacity, 100);
+        for i in 0..capacity as u32 {
+            rb.insert(i).unwrap();
+        }
+        assert_eq!(rb.insert(1000), Err(ReserveError::CapacityOverflow));
+        assert_eq!(rb.capacity(), capacity);
+        rb.delete(&5);
+        assert_eq!(rb.search(&5), None);
+        rb.insert(1000).unwrap();
+        assert_eq!(rb.search(&1000), Some(1000));
+        assert_eq!(rb.len(), capacity);
+        rb.tree.lock().unwrap().is_valid(); // will panic if it must
+    }
+
+    // a key whose comparisons panic on 13, to poison the tree mid-insert
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Touchy(u32);
+
+    impl PartialOrd for Touchy {
+        fn partial_cmp(&self, other: &Touchy) -> Option<std::cmp::Ordering> {
+            assert!(self.0 != 13 && other.0 != 13, "unlucky");
+            self.0.partial_cmp(&other.0)
+        }
+    }
+
+    unsafe impl SeqlockKey for Touchy {}
+
+    #[test]
+    #[should_panic(expected = "seqlock tree poisoned by a panic mid-write")]
+    fn test_poisoned() {
+        let rb: SeqlockRedBlack<Touchy> = SeqlockRedBlack::with_capacity(10);
+        for i in 0..5 {
+            rb.insert(Touchy(i)).unwrap();
+        }
+        assert_eq!(rb.search(&Touchy(3)), Some(Touchy(3)));
+        let insert = panic::catch_unwind(|| rb.insert(Touchy(13)));
+        assert!(insert.is_err());
+
+        // seq stays odd, so this would spin forever if it didn't panic
+        rb.search(&Touchy(3));
+    }
+
+    /*
+     * even keys are inserted once and never touched again, odd keys churn
+     * in and out as fast as the writer can go; readers must find every
+     * even key every time and never anything that was never inserted
+     */
+    #[test]
+    fn test_stress() {
+        let keys = workload(4096) as u64;
+        let rb: Arc<SeqlockRedBlack<u64>> = Arc::new(SeqlockRedBlack::with_capacity(keys as usize));
+        for i in (0..keys).step_by(2) {
+            rb.insert(i).unwrap();
+        }
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|t| {
+                let rb = rb.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let (mut reads, mut retries) = (0u64, 0u64);
+                    let mut i = t;
+                    while !done.load(Ordering::Relaxed) {
+                        i = (i + 7919) % (2 * keys);
+                        let found = loop {
+                            match rb.try_search(&i) {
+                                Some(found) => break found,
+                                None => retries += 1,
+                            }
+                        };
+                        if i >= keys {
+                            assert_eq!(found, None, "{} was never inserted", i);
+                        } else if i % 2 == 0 {
+                            assert_eq!(found, Some(i), "{} went missing", i);
+                        } else {
+                            assert!(found.is_none() || found == Some(i));
+                        }
+                        reads += 1;
+                    }
+                    (reads, retries)
+                })
+            })
+            .collect();
+
+        // every paranoid check walks the whole tree, so churn less under it
+        let rounds = if cfg!(feature = "paranoid") { 10 } else { 200 };
+        for round in 0..rounds {
+            for i in (1..keys).step_by(2) {
+                if (i / 2 + round) % 3 == 0 {
+                    rb.insert(i).unwrap();
+                }
+            }
+            for i in (1..keys).step_by(2) {
+                rb.delete(&i);
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+
+        let mut reads = 0;
+        for reader in readers {
+            reads += reader.join().unwrap().0;
+        }
+        assert!(reads > 0);
+        assert_eq!(rb.len(), keys as usize / 2);
+        rb.tree.lock().unwrap().is_valid(); // will panic if it must
+    }
+}
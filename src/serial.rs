@@ -0,0 +1,210 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+/*
+ * a tree is serialized as the sequence of its keys in ascending order,
+ * the same on every backend, so any store can read what another wrote
+ *
+ * deserializing goes through from_sorted: O(n), and input that isn't
+ * strictly ascending is an error rather than being sorted on the way in
+ */
+impl<T, S> Serialize for RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd + Serialize,
+    S: NodeStore<T>,
+{
+    fn serialize<Z: Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+struct TreeVisitor<T, S> {
+    _tree: PhantomData<(T, S)>,
+}
+
+impl<'de, T, S> Visitor<'de> for TreeVisitor<T, S>
+where
+    T: std::cmp::PartialOrd + Deserialize<'de>,
+    S: NodeStore<T> + Default,
+{
+    type Value = RedBlackTree<T, S>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sequence of strictly ascending keys")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        // don't trust the hint with a huge allocation up front
+        let mut keys = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(key) = seq.next_element()? {
+            keys.push(key);
+        }
+        RedBlackTree::from_sorted(keys).map_err(de::Error::custom)
+    }
+}
+
+impl<'de, T, S> Deserialize<'de> for RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd + Deserialize<'de>,
+    S: NodeStore<T> + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(TreeVisitor { _tree: PhantomData })
+    }
+}
+
+/*
+ * the map form, for trees of (key, value) pairs: a serde map in ascending
+ * key order, e.g. {"a":1,"b":2}, for a field marked
+ * #[serde(with = "red_black_tree::serial::map")]
+ *
+ * as with the sequence form, keys have to come in strictly ascending;
+ * two entries with the same key are duplicates whatever their values
+ */
+pub mod map {
+    use crate::bulk::BuildError;
+    use crate::store::NodeStore;
+    use crate::tree::RedBlackTree;
+    use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::cmp::Ordering;
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<K, V, S, Z>(
+        tree: &RedBlackTree<(K, V), S>,
+        serializer: Z,
+    ) -> Result<Z::Ok, Z::Error>
+    where
+        K: PartialOrd + Serialize,
+        V: PartialOrd + Serialize,
+        S: NodeStore<(K, V)>,
+        Z: Serializer,
+    {
+        serializer.collect_map(tree.iter().map(|(k, v)| (k, v)))
+    }
+
+    pub fn deserialize<'de, K, V, S, D>(
+        deserializer: D,
+    ) -> Result<RedBlackTree<(K, V), S>, D::Error>
+    where
+        K: PartialOrd + Deserialize<'de>,
+        V: PartialOrd + Deserialize<'de>,
+        S: NodeStore<(K, V)> + Default,
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(MapVisitor { _tree: PhantomData })
+    }
+
+    struct MapVisitor<K, V, S> {
+        _tree: PhantomData<(K, V, S)>,
+    }
+
+    impl<'de, K, V, S> Visitor<'de> for MapVisitor<K, V, S>
+    where
+        K: PartialOrd + Deserialize<'de>,
+        V: PartialOrd + Deserialize<'de>,
+        S: NodeStore<(K, V)> + Default,
+    {
+        type Value = RedBlackTree<(K, V), S>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a map with strictly ascending keys")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries: Vec<(K, V)> =
+                Vec::with_capacity(map.size_hint().unwrap_or(0).min(4096));
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            // by key alone, from_sorted would take equal keys with rising values
+            for (index, pair) in entries.windows(2).enumerate() {
+                let index = index + 1;
+                let err = match pair[0].0.partial_cmp(&pair[1].0) {
+                    Some(Ordering::Less) => continue,
+                    Some(Ordering::Equal) => BuildError::Duplicate { index },
+                    _ => BuildError::Unsorted { index },
+                };
+                return Err(de::Error::custom(err));
+            }
+            RedBlackTree::from_sorted(entries).map_err(de::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::map;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    #[test]
+    fn test_serde_round_trip() {
+        let mut rb: SlabRedBlack<u32> = SlabRedBlack::new();
+        for i in &[5, 1, 8, 9, 3] {
+            rb.insert(*i);
+        }
+        let json = serde_json::to_string(&rb).unwrap();
+        assert_eq!(json, "[1,3,5,8,9]");
+
+        // written by a slab tree, read into a pointer tree
+        let back: PointerRedBlack<u32> = serde_json::from_str(&json).unwrap();
+        back.is_valid(); // will panic if it must
+        assert_eq!(
+            back.iter().copied().collect::<Vec<_>>(),
+            vec![1, 3, 5, 8, 9]
+        );
+
+        let pairs: SlabRedBlack<(String, u32)> =
+            serde_json::from_str(r#"[["a", 1], ["b", 2]]"#).unwrap();
+        assert_eq!(pairs.len(), 2);
+    }
+
+    #[test]
+    fn test_serde_rejects() {
+        let err = serde_json::from_str::<SlabRedBlack<u32>>("[1, 3, 2]")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("key 2 sorts before"));
+
+        let err = serde_json::from_str::<PointerRedBlack<u32>>("[1, 1]")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("key 1 is equal"));
+
+        assert!(serde_json::from_str::<SlabRedBlack<u32>>("{}").is_err());
+    }
+
+    #[test]
+    fn test_serde_map_form() {
+        let mut rb: SlabRedBlack<(String, u32)> = SlabRedBlack::new();
+        for (k, v) in &[("b", 2), ("a", 1), ("c", 3)] {
+            rb.insert((k.to_string(), *v));
+        }
+        let mut json = Vec::new();
+        map::serialize(&rb, &mut serde_json::Serializer::new(&mut json)).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), r#"{"a":1,"b":2,"c":3}"#);
+
+        let read = |json: &str| -> Result<PointerRedBlack<(String, u32)>, _> {
+            map::deserialize(&mut serde_json::Deserializer::from_str(json))
+        };
+        let back = read(r#"{"a":1,"b":2,"c":3}"#).unwrap();
+        back.is_valid(); // will panic if it must
+        assert_eq!(
+            back.search(&(String::from("b"), 2)),
+            Some(&(String::from("b"), 2))
+        );
+
+        let err = read(r#"{"a":1,"a":2}"#).err().unwrap();
+        assert!(err.to_string().contains("key 1 is equal"));
+        let err = read(r#"{"b":1,"a":2}"#).err().unwrap();
+        assert!(err.to_string().contains("key 1 sorts before"));
+        assert!(read("[]").is_err());
+    }
+}
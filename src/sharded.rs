@@ -0,0 +1,401 @@
+use crate::redblack::RedBlack;
+use crate::slab::SlabRedBlack;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+// shards never split below this many keys
+const MIN_SHARD: usize = 64;
+
+/*
+ * an ordered map split by key range into shards, each a SlabRedBlack
+ * behind its own lock, so writers to different ranges don't contend
+ *
+ * the shard table (where each shard starts) sits behind a RwLock that
+ * everything but rebalancing only reads. a shard that grows past twice
+ * its fair share (len / target shards) is split at its median, and
+ * neighbours that together hold less than half a share are merged; both
+ * rebuild the shards with from_sorted in O(n) of the shards involved
+ *
+ * results come out as owned keys and values, the locks aren't held past
+ * a call. len is counted while the shard lock is still held, so an insert
+ * is always counted before the delete that undoes it
+ *
+ * a panic in the middle of an update (a K::cmp that panics, say) can leave
+ * a shard half-linked, so a poisoned shard stays poisoned: every later
+ * call that touches it panics too
+ */
+pub struct ShardedRedBlack<K, V> {
+    table: RwLock<Table<K, V>>,
+    target: usize,
+    len: AtomicUsize,
+}
+
+struct Table<K, V> {
+    // first key of every shard but the first, ascending
+    starts: Vec<K>,
+    shards: Vec<Mutex<SlabRedBlack<Entry<K, V>>>>,
+}
+
+// ordered by key alone; the value is only ever None on its way out
+struct Entry<K, V> {
+    key: K,
+    value: Option<V>,
+}
+
+impl<K: Ord, V> PartialEq for Entry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: Ord, V> PartialOrd for Entry<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.key.cmp(&other.key))
+    }
+}
+
+const POISONED: &str = "shard poisoned by a panic mid-update";
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect(POISONED)
+}
+
+fn shard_of<K: Ord>(starts: &[K], key: &K) -> usize {
+    starts.partition_point(|start| start <= key)
+}
+
+fn build<K: Ord, V>(entries: Vec<Entry<K, V>>) -> Mutex<SlabRedBlack<Entry<K, V>>> {
+    match RedBlackTree::from_sorted(entries) {
+        Ok(tree) => Mutex::new(tree),
+        Err(_) => unreachable!("shards hold strictly ascending keys"),
+    }
+}
+
+impl<K: Ord + Clone, V> ShardedRedBlack<K, V> {
+    // one shard to begin with, split as keys come in, aiming for `shards`
+    pub fn new(shards: usize) -> Self {
+        ShardedRedBlack::with_starts(Vec::new(), shards)
+    }
+
+    // shards starting at the given keys from the outset
+    pub fn with_starts(mut starts: Vec<K>, shards: usize) -> Self {
+        starts.sort();
+        starts.dedup();
+        let tables = (0..=starts.len()).map(|_| Mutex::new(SlabRedBlack::new()));
+        ShardedRedBlack {
+            table: RwLock::new(Table {
+                shards: tables.collect(),
+                starts,
+            }),
+            target: shards.max(1),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn shards(&self) -> usize {
+        self.table.read().unwrap().shards.len()
+    }
+
+    // set key to value, handing back the value it had
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let (shard_len, len) = {
+            let table = self.table.read().unwrap();
+            let mut tree = lock(&table.shards[shard_of(&table.starts, &key)]);
+            if let Some(x) = tree.find(|e| e.key.cmp(&key)) {
+                return tree.store.key_mut(x).value.replace(value);
+            }
+            tree.insert(Entry {
+                key,
+                value: Some(value),
+            });
+            let len = self.len.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+            (tree.len(), len)
+        };
+        if shard_len > 2 * self.share(len) {
+            self.rebalance();
+        }
+        None
+    }
+
+    pub fn delete(&self, key: &K) -> Option<V> {
+        let (old, shard_len, len) = {
+            let table = self.table.read().unwrap();
+            let mut tree = lock(&table.shards[shard_of(&table.starts, key)]);
+            let x = tree.find(|e| e.key.cmp(key))?;
+            // take the value out before the node goes
+            let value = tree.store.key_mut(x).value.take();
+            tree.delete_at(x);
+            let len = self.len.fetch_sub(1, AtomicOrdering::Relaxed) - 1;
+            (value, tree.len(), len)
+        };
+        if 2 * shard_len < self.share(len) && self.shards() > 1 {
+            self.rebalance();
+        }
+        old
+    }
+
+    pub fn search(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let table = self.table.read().unwrap();
+        let tree = lock(&table.shards[shard_of(&table.starts, key)]);
+        let x = tree.find(|e| e.key.cmp(key))?;
+        tree.store.key(x).value.clone()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let table = self.table.read().unwrap();
+        let tree = lock(&table.shards[shard_of(&table.starts, key)]);
+        tree.find(|e| e.key.cmp(key)).is_some()
+    }
+
+    pub fn iter(&self) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        self.range(..)
+    }
+
+    /*
+     * pairs within range in ascending key order; shards hold consecutive
+     * key ranges, so merging them is walking them in order. every shard
+     * the range touches is locked for the whole walk, in shard order, so
+     * the result is one consistent cut across them
+     */
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let table = self.table.read().unwrap();
+        let first = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => shard_of(&table.starts, start),
+            Bound::Unbounded => 0,
+        };
+        let last = match range.end_bound() {
+            Bound::Included(end) | Bound::Excluded(end) => shard_of(&table.starts, end),
+            Bound::Unbounded => table.shards.len() - 1,
+        };
+        let trees: Vec<_> = table.shards[first..=last.max(first)]
+            .iter()
+            .map(lock)
+            .collect();
+
+        let before = |e: &Entry<K, V>| match range.start_bound() {
+            Bound::Included(start) => e.key < *start,
+            Bound::Excluded(start) => e.key <= *start,
+            Bound::Unbounded => false,
+        };
+        let within = |e: &&Entry<K, V>| match range.end_bound() {
+            Bound::Included(end) => e.key <= *end,
+            Bound::Excluded(end) => e.key < *end,
+            Bound::Unbounded => true,
+        };
+        let mut pairs = Vec::new();
+        for tree in &trees {
+            let entries = tree.range_by(before).take_while(within);
+            pairs.extend(entries.filter_map(|e| Some((e.key.clone(), e.value.clone()?))));
+        }
+        pairs
+    }
+
+    // a shard's fair share of len keys, never below MIN_SHARD
+    fn share(&self, len: usize) -> usize {
+        (len / self.target).max(MIN_SHARD)
+    }
+
+    // split skewed shards at their medians, merge neighbours that ran dry
+    pub fn rebalance(&self) {
+        let mut guard = self.table.write().unwrap();
+        let table = &mut *guard;
+        let share = self.share(self.len());
+        let len =
+            |shard: &mut Mutex<SlabRedBlack<Entry<K, V>>>| shard.get_mut().expect(POISONED).len();
+
+        let mut i = 0;
+        while i < table.shards.len() {
+            if len(&mut table.shards[i]) > 2 * share {
+                let tree = table.shards[i].get_mut().expect(POISONED);
+                let mut lower = tree.drain();
+                let upper = lower.split_off(lower.len() / 2);
+                table.starts.insert(i, upper[0].key.clone());
+                table.shards[i] = build(lower);
+                table.shards.insert(i + 1, build(upper));
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut i = 0;
+        while i + 1 < table.shards.len() {
+            if 2 * (len(&mut table.shards[i]) + len(&mut table.shards[i + 1])) < share {
+                let next = table.shards.remove(i + 1);
+                let mut upper = next.into_inner().expect(POISONED);
+                let tree = table.shards[i].get_mut().expect(POISONED);
+                let mut entries = tree.drain();
+                entries.extend(upper.drain());
+                table.starts.remove(i);
+                table.shards[i] = build(entries);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::workload;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn is_valid<K: Ord + Clone + std::fmt::Debug, V>(map: &ShardedRedBlack<K, V>) {
+        let table = map.table.read().unwrap();
+        let mut total = 0;
+        for (i, shard) in table.shards.iter().enumerate() {
+            let tree = lock(shard);
+            tree.is_valid(); // will panic if it must
+            total += tree.len();
+            for e in tree.iter() {
+                assert_eq!(
+                    shard_of(&table.starts, &e.key),
+                    i,
+                    "{:?} in the wrong shard",
+                    e.key
+                );
+            }
+        }
+        assert_eq!(total, map.len());
+    }
+
+    #[test]
+    fn test_map_api() {
+        let map: ShardedRedBlack<u32, String> = ShardedRedBlack::with_starts(vec![100, 50], 4);
+        assert_eq!(map.shards(), 3);
+        for i in (0..150).rev() {
+            assert_eq!(map.insert(i, format!("v{}", i)), None);
+        }
+        assert_eq!(
+            map.insert(7, String::from("seven")),
+            Some(String::from("v7"))
+        );
+        assert_eq!(map.search(&7).unwrap(), "seven");
+        assert_eq!(map.delete(&8).unwrap(), "v8");
+        assert_eq!(map.delete(&8), None);
+        assert!(!map.contains_key(&8));
+        assert_eq!(map.len(), 149);
+
+        let keys = |pairs: Vec<(u32, String)>| pairs.into_iter().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(keys(map.range(45..55)), (45..55).collect::<Vec<_>>());
+        assert_eq!(keys(map.range(98..=101)), vec![98, 99, 100, 101]);
+        assert_eq!(keys(map.range(148..)), vec![148, 149]);
+        assert_eq!(keys(map.range(..3)), vec![0, 1, 2]);
+        assert_eq!(map.iter().len(), 149);
+        assert!(map.iter().windows(2).all(|w| w[0].0 < w[1].0));
+        is_valid(&map);
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let map: ShardedRedBlack<u32, u32> = ShardedRedBlack::new(8);
+        for i in 0..10_000 {
+            map.insert(i, i);
+        }
+        // ascending keys all land in the last shard, which keeps splitting
+        assert!(map.shards() >= 4, "{} shards", map.shards());
+        is_valid(&map);
+
+        for i in 0..9_990 {
+            assert_eq!(map.delete(&i), Some(i));
+        }
+        assert!(map.shards() <= 2, "{} shards", map.shards());
+        assert_eq!(map.range(..).len(), 10);
+        is_valid(&map);
+    }
+
+    #[test]
+    fn test_concurrent_ingest() {
+        let n = workload(5_000) as u64;
+        let map: Arc<ShardedRedBlack<u64, u64>> = Arc::new(ShardedRedBlack::new(8));
+        let writers: Vec<_> = (0..4u64)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..n {
+                        let key = (i * 4 + t) * 7919 % (4 * n);
+                        map.insert(key, t);
+                        if i % 3 == 0 {
+                            map.delete(&key);
+                        }
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let pairs = map.range(n..3 * n);
+                    assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(map.len(), 4 * n as usize - 4 * n.div_ceil(3) as usize);
+        is_valid(&map);
+    }
+
+    // every thread inserts and deletes the same few keys, so the count
+    // would wrap if a delete were ever counted before its insert
+    #[test]
+    fn test_concurrent_same_keys() {
+        const KEYS: usize = 100;
+        let map: Arc<ShardedRedBlack<usize, usize>> = Arc::new(ShardedRedBlack::new(4));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let counter = {
+            let (map, done) = (map.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(AtomicOrdering::Relaxed) {
+                    assert!(map.len() <= KEYS, "len {}", map.len());
+                }
+            })
+        };
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in 0..workload(20_000) {
+                        let key = (i + t) % KEYS;
+                        map.insert(key, t);
+                        map.delete(&((i * 7 + t) % KEYS));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        done.store(true, AtomicOrdering::Relaxed);
+        counter.join().unwrap();
+
+        assert_eq!(map.len(), map.iter().len());
+        is_valid(&map);
+    }
+}
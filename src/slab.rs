@@ -1,360 +1,478 @@
-const NULL: usize = !0;
-
-use crate::redblack::RedBlack;
+use crate::memory::ReserveError;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
 use slab::Slab;
 use std::mem;
 
-#[cfg(test)]
-use std::collections::VecDeque;
-
-struct Node<T> {
-    parent: usize,
-    children: [usize; 2],
-    key: T,
-    red: bool,
+/*
+ * width of the links stored in a slab node
+ *
+ * the top bit of the parent link holds the node color, so a u16 slab holds
+ * at most 2^15 - 1 nodes (the sentinel included) and a u32 slab 2^31 - 1
+ */
+pub trait SlabIndex: Copy {
+    const BITS: u32;
+
+    fn from_usize(x: usize) -> Self;
+    fn to_usize(self) -> usize;
 }
 
-impl<T> Node<T> {
-    fn new(key: T, nil_sentinel: usize) -> Node<T> {
-        Node {
-            parent: nil_sentinel,
-            children: [nil_sentinel, nil_sentinel],
-            key,
-            red: false,
+macro_rules! slab_index {
+    ($t:ty) => {
+        impl SlabIndex for $t {
+            const BITS: u32 = <$t>::BITS;
+
+            fn from_usize(x: usize) -> $t {
+                x as $t
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
         }
-    }
+    };
 }
 
-pub struct SlabRedBlack<T> {
-    slab: Slab<Node<T>>,
-    root: usize,
-    nil_sentinel: usize,
+slab_index!(u16);
+slab_index!(u32);
+slab_index!(usize);
+
+fn red_bit<I: SlabIndex>() -> usize {
+    1 << (I::BITS - 1)
 }
 
-impl<T> SlabRedBlack<T>
-where
-    T: std::cmp::PartialOrd,
-{
-    fn rotate(&mut self, x: usize, dir: usize) {
-        let y = self.slab[x].children[dir ^ 1];
-        self.slab[x].children[dir ^ 1] = self.slab[y].children[dir];
-        let y_chld = self.slab[y].children[dir];
-        if y_chld != self.nil_sentinel {
-            self.slab[y_chld].parent = x;
-        }
-        self.slab[y].parent = self.slab[x].parent;
-        let x_parent = self.slab[x].parent;
-        if x_parent == self.nil_sentinel {
-            self.root = y;
-        } else {
-            let sib_dir = if self.slab[x_parent].children[0] == x {
-                0
-            } else {
-                1
-            };
-            self.slab[x_parent].children[sib_dir] = y;
-        }
-        self.slab[y].children[dir] = x;
-        self.slab[x].parent = y;
-    }
+// all ones below the color bit, an impossible index
+pub(crate) fn null<I: SlabIndex>() -> usize {
+    red_bit::<I>() - 1
+}
+
+struct Node<T, I> {
+    parent: I, // color in the top bit
+    children: [I; 2],
+    key: mem::MaybeUninit<T>,
+}
 
-    fn tree_minimum(&mut self, mut x: usize) -> usize {
-        let mut l = self.slab[x].children[0];
-        while l != self.nil_sentinel {
-            x = l;
-            l = self.slab[x].children[0];
+impl<T, I: SlabIndex> Node<T, I> {
+    fn new(key: T, nil_sentinel: usize) -> Node<T, I> {
+        let nil = I::from_usize(nil_sentinel);
+        Node {
+            parent: nil,
+            children: [nil, nil],
+            key: mem::MaybeUninit::new(key),
         }
-        x
     }
 
-    fn tree_successor(&mut self, mut x: usize) -> usize {
-        if self.slab[x].children[1] != self.nil_sentinel {
-            return self.tree_minimum(x);
-        }
-        let mut y = self.slab[x].parent;
-        while y != self.nil_sentinel && x == self.slab[y].children[1] {
-            x = y;
-            y = self.slab[y].parent;
+    fn nil_sentinel() -> Node<T, I> {
+        let null = I::from_usize(null::<I>());
+        Node {
+            parent: null,
+            children: [null, null],
+            key: mem::MaybeUninit::uninit(),
         }
-        y
     }
+}
+
+// same shape as the slab crate's private Entry, only used for its size
+#[allow(dead_code)]
+enum SlabEntry<T> {
+    Vacant(usize),
+    Occupied(T),
+}
+
+// how to put back one change made to a journaled slab
+enum Undo<T, I> {
+    // set_parent and set_red both write the parent word
+    Parent {
+        x: usize,
+        old: I,
+    },
+    Child {
+        x: usize,
+        dir: usize,
+        old: I,
+    },
+    Alloc {
+        x: usize,
+    },
+    // key is the one the delete dropped, i.e. z's before the swap
+    Splice {
+        y: usize,
+        z: usize,
+        parent: I,
+        children: [I; 2],
+        key: T,
+    },
+}
 
-    fn insert_fixup(&mut self, mut z: usize) {
-        let mut p = self.slab[z].parent;
-        let mut pp: usize;
+pub struct SlabStore<T, I: SlabIndex = usize> {
+    slab: Slab<Node<T, I>>,
+    nil_sentinel: usize,
+    journal: Option<Vec<Undo<T, I>>>,
+}
 
-        while self.slab[p].red {
-            p = self.slab[z].parent;
-            pp = self.slab[p].parent;
+impl<T, I: SlabIndex> SlabStore<T, I> {
+    pub fn new() -> SlabStore<T, I> {
+        let mut slab = Slab::new();
 
-            let dir = if self.slab[pp].children[0] == p { 1 } else { 0 };
+        // slab entry 0 is the nil sentinel
+        let nil_sentinel = slab.insert(Node::nil_sentinel());
+        SlabStore {
+            slab,
+            nil_sentinel,
+            journal: None,
+        }
+    }
 
-            let y = self.slab[pp].children[dir];
+    fn log(&mut self, undo: Undo<T, I>) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(undo);
+        }
+    }
 
-            if self.slab[y].red {
-                self.slab[p].red = false;
-                self.slab[y].red = false;
-                self.slab[pp].red = true;
-                z = pp;
+    /*
+     * from here on every change is journaled, down to the slab slot, so
+     * undo_to can take the store back to exactly what it was; see
+     * transaction.rs
+     */
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
 
-                // recompute parent and grandparent after changing z
-                p = self.slab[z].parent;
-            } else {
-                // y is black, or nil sentinel
-                if z == self.slab[p].children[dir] {
-                    z = p;
+    pub(crate) fn stop_journal(&mut self) {
+        self.journal = None;
+    }
 
-                    self.rotate(z, dir ^ 1);
+    pub(crate) fn journal_len(&self) -> usize {
+        self.journal.as_ref().map_or(0, Vec::len)
+    }
 
-                    // recompute parent and grandparent after rotation
-                    p = self.slab[z].parent;
-                    pp = self.slab[p].parent;
+    /*
+     * undo the journal back to its first `mark` entries, newest first
+     *
+     * the slab hands out the slot it freed last, so putting a spliced-out
+     * node back in reverse order lands it in its old slot
+     */
+    pub(crate) fn undo_to(&mut self, mark: usize) {
+        let mut journal = self.journal.take().expect("slab isn't journaled");
+        for undo in journal.drain(mark..).rev() {
+            match undo {
+                Undo::Parent { x, old } => self.slab[x].parent = old,
+                Undo::Child { x, dir, old } => self.slab[x].children[dir] = old,
+                Undo::Alloc { x } => {
+                    let node = self.slab.remove(x);
+                    drop(unsafe { node.key.assume_init() });
+                }
+                Undo::Splice {
+                    y,
+                    z,
+                    parent,
+                    children,
+                    mut key,
+                } => {
+                    if y != z {
+                        mem::swap(self.key_mut(z), &mut key);
+                    }
+                    let at = self.slab.insert(Node {
+                        parent,
+                        children,
+                        key: mem::MaybeUninit::new(key),
+                    });
+                    assert_eq!(at, y, "slab slot moved under the journal");
                 }
-                self.slab[p].red = false;
-                self.slab[pp].red = true;
-                self.rotate(pp, dir);
             }
         }
+        self.journal = Some(journal);
+    }
+}
 
-        // blacken the root
-        self.slab[self.root].red = false;
+impl<T, I: SlabIndex> Default for SlabStore<T, I> {
+    fn default() -> SlabStore<T, I> {
+        SlabStore::new()
     }
+}
 
-    fn delete_fixup(&mut self, mut x: usize) {
-        let mut p: usize;
-        while x != self.root && !self.slab[x].red {
-            p = self.slab[x].parent;
-            let dir = if x == self.slab[p].children[0] { 1 } else { 0 };
-            let mut w = self.slab[p].children[dir];
-            if self.slab[w].red {
-                self.slab[w].red = false;
-                self.slab[p].red = true;
-                self.rotate(p, dir ^ 1);
+impl<T, I: SlabIndex> NodeStore<T> for SlabStore<T, I> {
+    type Link = usize;
 
-                // recompute w after the rotation of p
-                w = self.slab[p].children[dir];
-            }
-            let wl = self.slab[w].children[0];
-            let wr = self.slab[w].children[1];
-            if !self.slab[wl].red && !self.slab[wr].red {
-                self.slab[w].red = true;
-                x = p;
-            } else {
-                let mut wc = self.slab[w].children[dir]; // w child i care about
-                let wo = self.slab[w].children[dir ^ 1]; // w other child
-                if !self.slab[wc].red {
-                    self.slab[wo].red = false;
-                    self.slab[w].red = true;
-                    self.rotate(w, dir);
-                    w = self.slab[p].children[dir];
-
-                    // recompute wc after the rotation of w
-                    wc = self.slab[w].children[dir];
-                }
-                self.slab[w].red = self.slab[p].red;
-                self.slab[p].red = false;
-                self.slab[wc].red = false;
-                self.rotate(p, dir ^ 1);
-                x = self.root
-            }
-        }
+    fn nil(&self) -> usize {
+        self.nil_sentinel
+    }
 
-        // blacken x
-        self.slab[x].red = false;
+    fn alloc(&mut self, key: T) -> usize {
+        assert!(
+            self.slab.vacant_key() < null::<I>(),
+            "slab is full for {}-bit indices",
+            I::BITS
+        );
+        let x = self.slab.insert(Node::new(key, self.nil_sentinel));
+        self.log(Undo::Alloc { x });
+        x
     }
 
-    fn search_(&mut self, key: &T) -> Option<usize> {
-        let mut curr = self.root;
+    fn free(&mut self, x: usize) -> T {
+        let node = self.slab.remove(x);
+        unsafe { node.key.assume_init() }
+    }
 
-        while curr != self.nil_sentinel {
-            if self.slab[curr].key == *key {
-                return Some(curr);
-            }
-            let direction = if self.slab[curr].key < *key { 1 } else { 0 };
-            curr = self.slab[curr].children[direction];
-        }
-        None
+    fn parent(&self, x: usize) -> usize {
+        self.slab[x].parent.to_usize() & !red_bit::<I>()
     }
 
-    #[cfg(test)]
-    fn is_valid(&self) {
-        /*
-         * properties
-         * - root property: root is black
-         * - leaf nodes (NULL) are black (pointless here given my sentinel is a NULL, not a real node)
-         * - red property: children of a red node are black
-         * - simple path from node to descendant leaf contains same number of black nodes
-         */
-        fn verify_black_height<T>(rb: &SlabRedBlack<T>, x: usize) -> i32 {
-            if x == rb.nil_sentinel {
-                return 0;
-            }
-            let left_height = verify_black_height(rb, rb.slab[x].children[0]);
-            let right_height = verify_black_height(rb, rb.slab[x].children[1]);
+    fn set_parent(&mut self, x: usize, parent: usize) {
+        self.log(Undo::Parent {
+            x,
+            old: self.slab[x].parent,
+        });
+        let color = self.slab[x].parent.to_usize() & red_bit::<I>();
+        self.slab[x].parent = I::from_usize(color | parent);
+    }
+
+    fn child(&self, x: usize, dir: usize) -> usize {
+        self.slab[x].children[dir].to_usize()
+    }
 
-            assert!(
-                left_height != -1 && right_height != -1 && left_height == right_height,
-                "red-black properties have been violated!"
-            );
+    fn set_child(&mut self, x: usize, dir: usize, child: usize) {
+        self.log(Undo::Child {
+            x,
+            dir,
+            old: self.slab[x].children[dir],
+        });
+        self.slab[x].children[dir] = I::from_usize(child);
+    }
 
-            let add = if rb.slab[x].red { 0 } else { 1 };
-            left_height + add
+    fn is_red(&self, x: usize) -> bool {
+        self.slab[x].parent.to_usize() & red_bit::<I>() != 0
+    }
+
+    fn set_red(&mut self, x: usize, red: bool) {
+        self.log(Undo::Parent {
+            x,
+            old: self.slab[x].parent,
+        });
+        let parent = self.parent(x);
+        let color = if red { red_bit::<I>() } else { 0 };
+        self.slab[x].parent = I::from_usize(color | parent);
+    }
+
+    fn splice_out(&mut self, y: usize, z: usize) {
+        let node = self.slab.remove(y);
+        let mut key = unsafe { node.key.assume_init() };
+        if y != z {
+            mem::swap(self.key_mut(z), &mut key);
         }
+        // a journal keeps the deleted key around for undo
+        self.log(Undo::Splice {
+            y,
+            z,
+            parent: node.parent,
+            children: node.children,
+            key,
+        });
+    }
 
-        fn verify_children_color<T>(rb: &SlabRedBlack<T>) -> bool {
-            if rb.root == rb.nil_sentinel {
-                return true;
-            }
-            let mut queue: VecDeque<usize> = VecDeque::new();
-            queue.push_front(rb.root);
+    fn key(&self, x: usize) -> &T {
+        debug_assert!(x != self.nil_sentinel);
+        unsafe { &*self.slab[x].key.as_ptr() }
+    }
 
-            while !queue.is_empty() {
-                let curr = queue.pop_front().unwrap();
-                if curr == rb.nil_sentinel {
-                    break;
-                }
+    fn key_mut(&mut self, x: usize) -> &mut T {
+        debug_assert!(x != self.nil_sentinel);
+        unsafe { &mut *self.slab[x].key.as_mut_ptr() }
+    }
 
-                let l = rb.slab[curr].children[0];
-                let r = rb.slab[curr].children[1];
+    fn capacity(&self) -> usize {
+        self.slab.capacity() - 1
+    }
 
-                // red node must not have red children
-                if rb.slab[curr].red {
-                    assert!(
-                        !rb.slab[l].red && !rb.slab[r].red,
-                        "red node has red children"
-                    );
-                }
+    fn slot_size(&self) -> usize {
+        mem::size_of::<SlabEntry<Node<T, I>>>()
+    }
 
-                if l != rb.nil_sentinel {
-                    queue.push_back(l);
-                }
-                if r != rb.nil_sentinel {
-                    queue.push_back(r);
-                }
+    fn reserve(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve(additional) {
+            panic!("{}", e);
+        }
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> Result<(), ReserveError> {
+        // the slab crate has no fallible reserve, so only the index width is checked
+        match self.slab.len().checked_add(additional) {
+            Some(needed) if needed <= null::<I>() => {
+                self.slab.reserve(additional);
+                Ok(())
             }
+            _ => Err(ReserveError::CapacityOverflow),
+        }
+    }
 
+    fn shrink_to_fit(&mut self, root: usize) -> usize {
+        // Slab::compact moves nodes from the back into vacant slots; note where
+        // each one went, then point every link at the new slots
+        let mut remap: Vec<usize> = (0..self.slab.capacity()).collect();
+        self.slab.compact(|_, from, to| {
+            remap[from] = to;
             true
-        }
+        });
 
-        assert!(!self.slab[self.root].red); // root is black
-        verify_children_color(self);
-        verify_black_height(self, self.root);
+        let nil_sentinel = self.nil_sentinel;
+        for (x, node) in self.slab.iter_mut() {
+            if x == nil_sentinel {
+                continue;
+            }
+            let parent = node.parent.to_usize();
+            let color = parent & red_bit::<I>();
+            node.parent = I::from_usize(color | remap[parent & !red_bit::<I>()]);
+            for child in node.children.iter_mut() {
+                *child = I::from_usize(remap[child.to_usize()]);
+            }
+        }
+        remap[root]
     }
 }
 
-impl<T> RedBlack<T> for SlabRedBlack<T>
-where
-    T: std::cmp::PartialOrd,
-{
-    fn new() -> SlabRedBlack<T> {
-        let mut rb = SlabRedBlack {
-            slab: Slab::new(),
-            root: NULL,
-            nil_sentinel: NULL,
-        };
-        unsafe {
-            let nil_sentinel = rb.slab.insert(Node::new(
-                mem::MaybeUninit::<T>::uninit().assume_init(),
-                NULL,
-            ));
-            rb.nil_sentinel = nil_sentinel;
-            rb.root = nil_sentinel;
+pub type SlabRedBlack<T, I = usize> = RedBlackTree<T, SlabStore<T, I>>;
+
+// node orders for RedBlackTree::relayout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    // sorted by key, so neighbouring keys share cache lines
+    InOrder,
+    // level by level, so the top of every search path is packed together
+    BreadthFirst,
+    // recursive top/bottom split, cache-oblivious for searches
+    VanEmdeBoas,
+}
+
+impl<T, I: SlabIndex> SlabStore<T, I> {
+    fn height(&self, x: usize) -> usize {
+        let mut height = 0;
+        let mut stack = vec![(x, 1)];
+        while let Some((x, depth)) = stack.pop() {
+            if x == self.nil_sentinel {
+                continue;
+            }
+            height = height.max(depth);
+            stack.push((self.child(x, 0), depth + 1));
+            stack.push((self.child(x, 1), depth + 1));
         }
-        rb
+        height
     }
 
-    fn search(&mut self, key: &T) -> Option<&T> {
-        if let Some(found_idx) = self.search_(key) {
-            return Some(&self.slab[found_idx].key);
+    fn in_order(&self, root: usize, order: &mut Vec<usize>) {
+        let mut stack = Vec::new();
+        let mut x = root;
+        while x != self.nil_sentinel || !stack.is_empty() {
+            while x != self.nil_sentinel {
+                stack.push(x);
+                x = self.child(x, 0);
+            }
+            x = stack.pop().unwrap();
+            order.push(x);
+            x = self.child(x, 1);
         }
-        None
     }
 
-    fn delete(&mut self, key: &T) {
-        let z = match self.search_(key) {
-            Some(found_idx) => found_idx,
-            None => {
-                return;
+    fn breadth_first(&self, root: usize, order: &mut Vec<usize>) {
+        if root == self.nil_sentinel {
+            return;
+        }
+        order.push(root);
+        let mut i = 0;
+        while i < order.len() {
+            for dir in 0..2 {
+                let c = self.child(order[i], dir);
+                if c != self.nil_sentinel {
+                    order.push(c);
+                }
             }
-        };
-
-        let y = if self.slab[z].children[0] == self.nil_sentinel
-            || self.slab[z].children[1] == self.nil_sentinel
-        {
-            z
-        } else {
-            self.tree_successor(z)
-        };
-
-        let dir = if self.slab[y].children[0] != self.nil_sentinel {
-            0
-        } else {
-            1
-        };
-        let x = self.slab[y].children[dir];
-
-        let yp = self.slab[y].parent;
-
-        self.slab[x].parent = yp;
-
-        if yp == self.nil_sentinel {
-            self.root = x;
-        } else {
-            let dir = if y == self.slab[yp].children[0] { 0 } else { 1 };
-            self.slab[yp].children[dir] = x;
+            i += 1;
         }
+    }
 
-        if !self.slab[y].red {
-            self.delete_fixup(x);
+    /*
+     * lay out the top h/2 levels below x, then each subtree hanging off
+     * the bottom of that top half, recursively
+     */
+    fn van_emde_boas(&self, x: usize, h: usize, order: &mut Vec<usize>) {
+        if x == self.nil_sentinel {
+            return;
         }
-
-        if y == self.nil_sentinel {
+        if h == 1 {
+            order.push(x);
             return;
         }
-
-        let mut y_removed = self.slab.remove(y); // remove the spliced-out node from the slab
-        if y != z {
-            mem::swap(&mut self.slab[z].key, &mut y_removed.key);
+        let top = h / 2;
+        self.van_emde_boas(x, top, order);
+
+        // roots of the bottom subtrees, left to right
+        let mut frontier = vec![x];
+        for _ in 0..top {
+            frontier = frontier
+                .iter()
+                .flat_map(|&y| vec![self.child(y, 0), self.child(y, 1)])
+                .filter(|&y| y != self.nil_sentinel)
+                .collect();
+        }
+        for y in frontier {
+            self.van_emde_boas(y, h - top, order);
         }
     }
 
-    fn insert(&mut self, key: T) {
-        let z = self.slab.insert(Node::new(key, self.nil_sentinel));
-
-        let mut y = self.nil_sentinel;
-        let mut x = self.root;
+    // move the nodes into a fresh slab in `order`, returns the new root
+    fn rebuild(&mut self, order: &[usize], root: usize) -> usize {
+        let mut remap = vec![null::<I>(); self.slab.capacity()];
+        let mut slab = Slab::with_capacity(order.len() + 1);
 
-        while x != self.nil_sentinel {
-            y = x;
-            let dir = if self.slab[z].key < self.slab[x].key {
-                0
-            } else {
-                1
-            };
-            x = self.slab[x].children[dir];
+        remap[self.nil_sentinel] = slab.insert(self.slab.remove(self.nil_sentinel));
+        for &x in order {
+            remap[x] = slab.insert(self.slab.remove(x));
         }
-
-        self.slab[z].parent = y;
-        if y == self.nil_sentinel {
-            self.root = z;
-        } else {
-            let dir = if self.slab[z].key < self.slab[y].key {
-                0
-            } else {
-                1
-            };
-            self.slab[y].children[dir] = z;
+        debug_assert!(self.slab.is_empty(), "relayout missed a node");
+
+        self.slab = slab;
+        self.nil_sentinel = remap[self.nil_sentinel];
+        for (x, node) in self.slab.iter_mut() {
+            if x == self.nil_sentinel {
+                node.parent = I::from_usize(null::<I>());
+                continue;
+            }
+            let parent = node.parent.to_usize();
+            let color = parent & red_bit::<I>();
+            node.parent = I::from_usize(color | remap[parent & !red_bit::<I>()]);
+            for child in node.children.iter_mut() {
+                *child = I::from_usize(remap[child.to_usize()]);
+            }
         }
+        remap[root]
+    }
+}
 
-        self.slab[z].red = true;
+impl<T: PartialOrd, I: SlabIndex> RedBlackTree<T, SlabStore<T, I>> {
+    // rewrite the slab with its nodes in `layout` order, dropping vacant slots
+    pub fn relayout(&mut self, layout: Layout) {
+        let mut order = Vec::with_capacity(self.len());
+        match layout {
+            Layout::InOrder => self.store.in_order(self.root, &mut order),
+            Layout::BreadthFirst => self.store.breadth_first(self.root, &mut order),
+            Layout::VanEmdeBoas => {
+                let height = self.store.height(self.root);
+                self.store.van_emde_boas(self.root, height, &mut order)
+            }
+        }
+        self.root = self.store.rebuild(&order, self.root);
+    }
 
-        self.insert_fixup(z);
+    // the search-friendly layout
+    pub fn compact(&mut self) {
+        self.relayout(Layout::VanEmdeBoas)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::redblack::RedBlack;
+    use crate::tree::workload;
 
     #[test]
     fn test_basic_insert() {
@@ -389,29 +507,29 @@ mod tests {
          *      b   g
          */
 
-        assert_eq!(rb.slab[1].key, 5);
-        assert_eq!(rb.slab[1].parent, rb.nil_sentinel);
-        assert_eq!(rb.slab[1].children[0], 2); // x's left points to 2 in the slab i.e. alpha
-        assert_eq!(rb.slab[1].children[1], 3); // x's right points to 3 in the slab i.e. y
-
-        assert_eq!(rb.slab[2].key, 1);
-        assert_eq!(rb.slab[2].parent, 1);
-        assert_eq!(rb.slab[2].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[2].children[1], rb.nil_sentinel);
-
-        assert_eq!(rb.slab[3].key, 8);
-        assert_eq!(rb.slab[3].parent, 1);
-        assert_eq!(rb.slab[3].children[0], 4); // y's left points to 4 in the slab i.e. beta
-        assert_eq!(rb.slab[3].children[1], 5); // y's right points to 5 in the slab i.e. gamma
-
-        assert_eq!(rb.slab[4].key, 7);
-        assert_eq!(rb.slab[4].parent, 3);
-        assert_eq!(rb.slab[4].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[4].children[1], rb.nil_sentinel);
-        assert_eq!(rb.slab[5].key, 9);
-        assert_eq!(rb.slab[5].parent, 3);
-        assert_eq!(rb.slab[5].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[5].children[1], rb.nil_sentinel);
+        assert_eq!(*rb.store.key(1), 5);
+        assert_eq!(rb.store.parent(1), rb.store.nil());
+        assert_eq!(rb.store.child(1, 0), 2); // x's left points to 2 in the slab i.e. alpha
+        assert_eq!(rb.store.child(1, 1), 3); // x's right points to 3 in the slab i.e. y
+
+        assert_eq!(*rb.store.key(2), 1);
+        assert_eq!(rb.store.parent(2), 1);
+        assert_eq!(rb.store.child(2, 0), rb.store.nil());
+        assert_eq!(rb.store.child(2, 1), rb.store.nil());
+
+        assert_eq!(*rb.store.key(3), 8);
+        assert_eq!(rb.store.parent(3), 1);
+        assert_eq!(rb.store.child(3, 0), 4); // y's left points to 4 in the slab i.e. beta
+        assert_eq!(rb.store.child(3, 1), 5); // y's right points to 5 in the slab i.e. gamma
+
+        assert_eq!(*rb.store.key(4), 7);
+        assert_eq!(rb.store.parent(4), 3);
+        assert_eq!(rb.store.child(4, 0), rb.store.nil());
+        assert_eq!(rb.store.child(4, 1), rb.store.nil());
+        assert_eq!(*rb.store.key(5), 9);
+        assert_eq!(rb.store.parent(5), 3);
+        assert_eq!(rb.store.child(5, 0), rb.store.nil());
+        assert_eq!(rb.store.child(5, 1), rb.store.nil());
 
         rb.rotate(1, 0); // left-rotate x
 
@@ -425,43 +543,43 @@ mod tests {
 
         // slab entries should be the same, but their links should reflect the new tree topology
 
-        assert_eq!(rb.slab[1].key, 5);
-        assert_eq!(rb.slab[2].key, 1);
-        assert_eq!(rb.slab[1].parent, 3); // x's new parent is y
-        assert_eq!(rb.slab[3].children[0], 1); // y's left child is x
-        assert_eq!(rb.slab[3].children[1], 5); // y's right child is gamma
-        assert_eq!(rb.slab[5].key, 9);
-        assert_eq!(rb.slab[5].parent, 3);
-        assert_eq!(rb.slab[1].children[0], 2); // x's left child is alpha
-        assert_eq!(rb.slab[1].children[1], 4); // x's right child is beta
-        assert_eq!(rb.slab[2].parent, 1); // alpha's parent is x
-        assert_eq!(rb.slab[4].parent, 1); // beta's parent is x
+        assert_eq!(*rb.store.key(1), 5);
+        assert_eq!(*rb.store.key(2), 1);
+        assert_eq!(rb.store.parent(1), 3); // x's new parent is y
+        assert_eq!(rb.store.child(3, 0), 1); // y's left child is x
+        assert_eq!(rb.store.child(3, 1), 5); // y's right child is gamma
+        assert_eq!(*rb.store.key(5), 9);
+        assert_eq!(rb.store.parent(5), 3);
+        assert_eq!(rb.store.child(1, 0), 2); // x's left child is alpha
+        assert_eq!(rb.store.child(1, 1), 4); // x's right child is beta
+        assert_eq!(rb.store.parent(2), 1); // alpha's parent is x
+        assert_eq!(rb.store.parent(4), 1); // beta's parent is x
 
         rb.rotate(3, 1); // right-rotate y brings our tree back to the original
 
-        assert_eq!(rb.slab[1].key, 5);
-        assert_eq!(rb.slab[1].parent, rb.nil_sentinel);
-        assert_eq!(rb.slab[1].children[0], 2); // x's left points to 2 in the slab i.e. alpha
-        assert_eq!(rb.slab[1].children[1], 3); // x's right points to 3 in the slab i.e. y
-
-        assert_eq!(rb.slab[2].key, 1);
-        assert_eq!(rb.slab[2].parent, 1);
-        assert_eq!(rb.slab[2].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[2].children[1], rb.nil_sentinel);
-
-        assert_eq!(rb.slab[3].key, 8);
-        assert_eq!(rb.slab[3].parent, 1);
-        assert_eq!(rb.slab[3].children[0], 4); // y's left points to 4 in the slab i.e. beta
-        assert_eq!(rb.slab[3].children[1], 5); // y's right points to 5 in the slab i.e. gamma
-
-        assert_eq!(rb.slab[4].key, 7);
-        assert_eq!(rb.slab[4].parent, 3);
-        assert_eq!(rb.slab[4].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[4].children[1], rb.nil_sentinel);
-        assert_eq!(rb.slab[5].key, 9);
-        assert_eq!(rb.slab[5].parent, 3);
-        assert_eq!(rb.slab[5].children[0], rb.nil_sentinel);
-        assert_eq!(rb.slab[5].children[1], rb.nil_sentinel);
+        assert_eq!(*rb.store.key(1), 5);
+        assert_eq!(rb.store.parent(1), rb.store.nil());
+        assert_eq!(rb.store.child(1, 0), 2); // x's left points to 2 in the slab i.e. alpha
+        assert_eq!(rb.store.child(1, 1), 3); // x's right points to 3 in the slab i.e. y
+
+        assert_eq!(*rb.store.key(2), 1);
+        assert_eq!(rb.store.parent(2), 1);
+        assert_eq!(rb.store.child(2, 0), rb.store.nil());
+        assert_eq!(rb.store.child(2, 1), rb.store.nil());
+
+        assert_eq!(*rb.store.key(3), 8);
+        assert_eq!(rb.store.parent(3), 1);
+        assert_eq!(rb.store.child(3, 0), 4); // y's left points to 4 in the slab i.e. beta
+        assert_eq!(rb.store.child(3, 1), 5); // y's right points to 5 in the slab i.e. gamma
+
+        assert_eq!(*rb.store.key(4), 7);
+        assert_eq!(rb.store.parent(4), 3);
+        assert_eq!(rb.store.child(4, 0), rb.store.nil());
+        assert_eq!(rb.store.child(4, 1), rb.store.nil());
+        assert_eq!(*rb.store.key(5), 9);
+        assert_eq!(rb.store.parent(5), 3);
+        assert_eq!(rb.store.child(5, 0), rb.store.nil());
+        assert_eq!(rb.store.child(5, 1), rb.store.nil());
     }
 
     #[test]
@@ -469,7 +587,7 @@ mod tests {
         let mut num = 1u32;
         let mut rb: SlabRedBlack<u32> = SlabRedBlack::new();
 
-        for _ in 0..1000000 {
+        for _ in 0..workload(1000000) {
             num = num.wrapping_mul(17).wrapping_add(255);
             rb.insert(num);
         }
@@ -480,49 +598,133 @@ mod tests {
     #[test]
     fn test_many_insert_some_delete() {
         let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        let n = workload(1000000) as i32;
 
-        for i in 500000..1000000 {
+        for i in n / 2..n {
             rb.insert(i);
-            rb.insert(1000000 - i);
+            rb.insert(n - i);
         }
 
-        assert_eq!(rb.search(&5), Some(&5));
-        assert_eq!(rb.search(&50), Some(&50));
-        assert_eq!(rb.search(&500), Some(&500));
-        assert_eq!(rb.search(&5000), Some(&5000));
-        assert_eq!(rb.search(&50000), Some(&50000));
-        assert_eq!(rb.search(&500000), Some(&500000));
+        // as many of these as the workload reaches
+        let probes: Vec<i32> = [5, 50, 500, 5000, 50000, 500000]
+            .iter()
+            .copied()
+            .filter(|key| *key <= n / 2)
+            .collect();
+        for key in &probes {
+            assert_eq!(rb.search(key), Some(key));
+        }
 
         rb.is_valid(); // will panic if it must
 
-        rb.delete(&5);
-        rb.is_valid(); // will panic if it must
-        rb.delete(&5);
-        assert_eq!(rb.search(&5), None);
+        for key in &probes {
+            rb.delete(key);
+            rb.is_valid(); // will panic if it must
+            rb.delete(key);
+            assert_eq!(rb.search(key), None);
+        }
+    }
 
-        rb.delete(&50);
-        rb.is_valid(); // will panic if it must
-        rb.delete(&50);
-        assert_eq!(rb.search(&50), None);
+    #[test]
+    fn test_delete_inner_node() {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
 
-        rb.delete(&500);
-        rb.is_valid(); // will panic if it must
-        rb.delete(&500);
-        assert_eq!(rb.search(&500), None);
+        for i in 0..100 {
+            rb.insert(i);
+        }
 
-        rb.delete(&5000);
+        // the root has two children, so its successor gets spliced out instead
+        let root_key = *rb.store.key(rb.root);
+        rb.delete(&root_key);
         rb.is_valid(); // will panic if it must
-        rb.delete(&5000);
-        assert_eq!(rb.search(&5000), None);
 
-        rb.delete(&50000);
-        rb.is_valid(); // will panic if it must
-        rb.delete(&50000);
-        assert_eq!(rb.search(&50000), None);
+        assert_eq!(rb.search(&root_key), None);
+        for i in (0..100).filter(|i| *i != root_key) {
+            assert_eq!(rb.search(&i), Some(&i));
+        }
+    }
 
-        rb.delete(&500000);
+    #[test]
+    fn test_compact_indices() {
+        // 4 byte key + 3 u32 links, against 3 usize links + bool in IndexNode
+        assert_eq!(mem::size_of::<Node<u32, u32>>(), 16);
+        assert_eq!(mem::size_of::<Node<u32, u16>>(), 12);
+        assert!(
+            mem::size_of::<crate::store::IndexNode<u32>>() >= 2 * mem::size_of::<Node<u32, u32>>()
+        );
+
+        let mut rb: SlabRedBlack<i32, u32> = SlabRedBlack::new();
+        let n = workload(10000) as i32;
+        for i in 0..n {
+            rb.insert(i);
+        }
+        for i in (0..n).step_by(2) {
+            rb.delete(&i);
+        }
         rb.is_valid(); // will panic if it must
-        rb.delete(&500000);
-        assert_eq!(rb.search(&500000), None);
+        for i in 0..n {
+            let expected = if i % 2 == 0 { None } else { Some(&i) };
+            assert_eq!(rb.search(&i), expected);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "slab is full for 16-bit indices")]
+    fn test_u16_overflow() {
+        // 2^15 - 2 keys fill every index below null, built without fixups
+        let mut rb: SlabRedBlack<u32, u16> = RedBlackTree::from_sorted(0..(1 << 15) - 2).unwrap();
+        rb.insert(1 << 15);
+    }
+
+    #[test]
+    fn test_try_reserve_index_width() {
+        let mut rb: SlabRedBlack<u32, u16> = SlabRedBlack::new();
+        assert!(rb.try_reserve(1000).is_ok());
+        assert!(rb.capacity() >= 1000);
+        assert_eq!(
+            rb.try_reserve(1 << 15),
+            Err(crate::memory::ReserveError::CapacityOverflow)
+        );
+    }
+
+    #[test]
+    fn test_relayout() {
+        for &layout in &[Layout::InOrder, Layout::BreadthFirst, Layout::VanEmdeBoas] {
+            let mut rb: SlabRedBlack<u32, u32> = SlabRedBlack::new();
+            // a permutation of 0..n, so the slab order is scrambled
+            let n = workload(10007) as u32;
+            for i in 0..n {
+                rb.insert(i * 7919 % n);
+            }
+            for i in (0..n).step_by(3) {
+                rb.delete(&i);
+            }
+            let len = rb.len();
+
+            rb.relayout(layout);
+            rb.is_valid(); // will panic if it must
+
+            assert_eq!(rb.len(), len);
+            assert_eq!(rb.capacity(), len);
+            assert_eq!(rb.store.slab.len(), len + 1);
+            for i in 0..n {
+                assert_eq!(rb.search(&i).is_some(), i % 3 != 0);
+            }
+
+            match layout {
+                Layout::InOrder => {
+                    for x in 2..=len {
+                        assert!(rb.store.key(x - 1) <= rb.store.key(x));
+                    }
+                }
+                Layout::BreadthFirst => assert_eq!(rb.root, 1),
+                Layout::VanEmdeBoas => assert_eq!(rb.root, 1),
+            }
+
+            // still a working tree
+            rb.insert(3);
+            rb.delete(&3);
+            rb.is_valid(); // will panic if it must
+        }
     }
 }
@@ -0,0 +1,411 @@
+use crate::bulk::BuildError;
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::io::{self, Read, Write};
+use std::{error, fmt};
+
+/*
+ * snapshot layout, all integers little-endian:
+ *
+ *     magic     4 bytes  "RBTS"
+ *     version   u16
+ *     codec     u16      SnapshotKey::CODEC of the keys
+ *     count     u64
+ *     keys      count encoded keys, in ascending order
+ *     crc       u32      CRC-32 (IEEE) of everything before it
+ *
+ * a snapshot says nothing about the store that wrote it, so it loads
+ * into any backend, and the in-order payload rebuilds in O(n) the way
+ * from_sorted does, keeping any key the tree held more than once
+ */
+const MAGIC: [u8; 4] = *b"RBTS";
+const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // input ended before the snapshot did
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u16),
+    // the snapshot holds a different key type than the tree being loaded
+    CodecMismatch { expected: u16, found: u16 },
+    ChecksumMismatch { stored: u32, computed: u32 },
+    // a key's bytes don't decode, e.g. a String that isn't UTF-8
+    BadKey,
+    // a write-ahead log record (durable.rs) passed its checksum, but its
+    // op is neither an insert nor a delete
+    UnknownOp(u8),
+    // the keys passed the checksum but aren't in ascending order
+    Build(BuildError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot i/o failed: {}", e),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot (bad magic number)"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::CodecMismatch { expected, found } => write!(
+                f,
+                "snapshot holds keys of codec {}, expected codec {}",
+                found, expected
+            ),
+            SnapshotError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "snapshot checksum mismatch: stored {:08x}, computed {:08x}",
+                stored, computed
+            ),
+            SnapshotError::BadKey => write!(f, "snapshot holds an undecodable key"),
+            SnapshotError::UnknownOp(op) => write!(f, "log record has unknown op {}", op),
+            SnapshotError::Build(e) => write!(f, "snapshot keys are out of order: {}", e),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            SnapshotError::Build(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::Io(e)
+        }
+    }
+}
+
+impl From<BuildError> for SnapshotError {
+    fn from(e: BuildError) -> SnapshotError {
+        SnapshotError::Build(e)
+    }
+}
+
+/*
+ * how a key goes in and out of a snapshot; CODEC identifies the encoding
+ * in the header, so a snapshot of u32s can't be read back as i64s
+ */
+pub trait SnapshotKey: Sized {
+    const CODEC: u16;
+
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(r: &mut R) -> Result<Self, SnapshotError>;
+}
+
+macro_rules! snapshot_int {
+    ($($t:ty = $codec:expr),*) => {
+        $(
+            impl SnapshotKey for $t {
+                const CODEC: u16 = $codec;
+
+                fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                    w.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(r: &mut R) -> Result<$t, SnapshotError> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    r.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+snapshot_int!(u8 = 1, u16 = 2, u32 = 3, u64 = 4, u128 = 5);
+snapshot_int!(i8 = 6, i16 = 7, i32 = 8, i64 = 9, i128 = 10);
+
+// length-prefixed bytes; take() keeps a corrupt length from allocating it all up front
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, SnapshotError> {
+    let len = u64::decode(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(SnapshotError::Truncated);
+    }
+    Ok(bytes)
+}
+
+impl SnapshotKey for Vec<u8> {
+    const CODEC: u16 = 11;
+
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(w)?;
+        w.write_all(self)
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<Vec<u8>, SnapshotError> {
+        read_bytes(r)
+    }
+}
+
+impl SnapshotKey for String {
+    const CODEC: u16 = 12;
+
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(r: &mut R) -> Result<String, SnapshotError> {
+        String::from_utf8(read_bytes(r)?).map_err(|_| SnapshotError::BadKey)
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+// CRC-32 of everything that passes through, in either direction
+struct Crc<T> {
+    inner: T,
+    crc: u32,
+}
+
+impl<T> Crc<T> {
+    fn new(inner: T) -> Crc<T> {
+        Crc { inner, crc: !0 }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.crc = CRC_TABLE[((self.crc ^ *b as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn sum(&self) -> u32 {
+        !self.crc
+    }
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new(());
+    crc.update(bytes);
+    crc.sum()
+}
+
+impl<W: Write> Write for Crc<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Crc<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd + SnapshotKey,
+    S: NodeStore<T>,
+{
+    pub fn save_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut w = Crc::new(w);
+        w.write_all(&MAGIC)?;
+        VERSION.encode(&mut w)?;
+        T::CODEC.encode(&mut w)?;
+        (self.len as u64).encode(&mut w)?;
+        for x in self.links() {
+            self.store.with_key(x, |key| key.encode(&mut w))?;
+        }
+        let crc = w.sum();
+        crc.encode(&mut w.inner)?;
+        w.flush()
+    }
+
+    // checks the whole snapshot, then rebuilds it in O(n)
+    pub fn load_snapshot<R: Read>(r: R) -> Result<RedBlackTree<T, S>, SnapshotError>
+    where
+        S: Default,
+    {
+        let mut r = Crc::new(r);
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::decode(&mut r)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let codec = u16::decode(&mut r)?;
+        if codec != T::CODEC {
+            return Err(SnapshotError::CodecMismatch {
+                expected: T::CODEC,
+                found: codec,
+            });
+        }
+
+        let count = u64::decode(&mut r)?;
+        let mut keys = Vec::with_capacity(count.min(4096) as usize);
+        for _ in 0..count {
+            keys.push(T::decode(&mut r)?);
+        }
+
+        let computed = r.sum();
+        let stored = u32::decode(&mut r.inner)?;
+        if stored != computed {
+            return Err(SnapshotError::ChecksumMismatch { stored, computed });
+        }
+        // a tree can hold a key more than once, and its snapshot with it
+        Ok(RedBlackTree::from_ascending(keys)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::slab::SlabRedBlack;
+
+    fn saved() -> Vec<u8> {
+        let rb: SlabRedBlack<u32> = RedBlackTree::from_sorted(0..100).unwrap();
+        let mut out = Vec::new();
+        rb.save_snapshot(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let bytes = saved();
+        assert_eq!(bytes.len(), 4 + 2 + 2 + 8 + 100 * 4 + 4);
+
+        // the standard CRC-32 check value
+        let mut crc = Crc::new(io::sink());
+        crc.write_all(b"123456789").unwrap();
+        assert_eq!(crc.sum(), 0xcbf4_3926);
+
+        let rb = PointerRedBlack::<u32>::load_snapshot(&bytes[..]).unwrap();
+        rb.is_valid(); // will panic if it must
+        assert_eq!(
+            rb.iter().copied().collect::<Vec<_>>(),
+            (0..100).collect::<Vec<_>>()
+        );
+
+        let mut words: SlabRedBlack<String> = SlabRedBlack::with_store(Default::default());
+        for w in &["pear", "apple", "fig"] {
+            words.insert(w.to_string());
+        }
+        let mut out = Vec::new();
+        words.save_snapshot(&mut out).unwrap();
+        let back = PointerRedBlack::<String>::load_snapshot(&out[..]).unwrap();
+        assert_eq!(
+            back.iter().collect::<Vec<_>>(),
+            vec!["apple", "fig", "pear"]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_duplicate_keys() {
+        let mut rb: SlabRedBlack<u32> = SlabRedBlack::with_store(Default::default());
+        for i in &[3, 1, 3, 2, 3, 1] {
+            rb.insert(*i);
+        }
+        let mut out = Vec::new();
+        rb.save_snapshot(&mut out).unwrap();
+
+        let mut back = PointerRedBlack::<u32>::load_snapshot(&out[..]).unwrap();
+        back.is_valid(); // will panic if it must
+        assert_eq!(
+            back.iter().copied().collect::<Vec<_>>(),
+            vec![1, 1, 2, 3, 3, 3]
+        );
+
+        // each delete takes one copy, as it would have in the original
+        back.delete(&3);
+        back.delete(&1);
+        back.is_valid(); // will panic if it must
+        assert_eq!(back.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3, 3]);
+
+        // descending keys are still refused
+        let mut bytes = saved();
+        bytes[16..20].copy_from_slice(&7u32.to_le_bytes());
+        let body = bytes.len() - 4;
+        let crc = crc32(&bytes[..body]);
+        bytes[body..].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            SlabRedBlack::<u32>::load_snapshot(&bytes[..]).err(),
+            Some(SnapshotError::Build(BuildError::Unsorted { index: 1 }))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_truncated() {
+        let bytes = saved();
+        for len in 0..bytes.len() {
+            let err = SlabRedBlack::<u32>::load_snapshot(&bytes[..len]).err();
+            assert!(matches!(err, Some(SnapshotError::Truncated)), "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_corrupt() {
+        let mut bytes = saved();
+        bytes[40] ^= 0x10;
+        assert!(matches!(
+            SlabRedBlack::<u32>::load_snapshot(&bytes[..]).err(),
+            Some(SnapshotError::ChecksumMismatch { .. })
+        ));
+
+        let mut bytes = saved();
+        bytes[0] = b'X';
+        assert!(matches!(
+            SlabRedBlack::<u32>::load_snapshot(&bytes[..]).err(),
+            Some(SnapshotError::BadMagic)
+        ));
+
+        let mut bytes = saved();
+        bytes[4] = 9;
+        assert!(matches!(
+            SlabRedBlack::<u32>::load_snapshot(&bytes[..]).err(),
+            Some(SnapshotError::UnsupportedVersion(9))
+        ));
+
+        assert!(matches!(
+            SlabRedBlack::<i64>::load_snapshot(&saved()[..]).err(),
+            Some(SnapshotError::CodecMismatch {
+                expected: 9,
+                found: 3
+            })
+        ));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::memory::ReserveError;
+use crate::slab::{null, SlabIndex};
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::mem;
+
+/*
+ * struct-of-arrays slab: keys, child links, parent links and colors each
+ * live in their own array, so a search only pulls keys and children into
+ * cache and never touches parents or colors
+ *
+ * SoaRedBlack<T, I> is the drop-in alternative to the array-of-structs
+ * SlabRedBlack<T, I>, with the same index widths
+ *
+ * like VecStore, vacant slots are chained through their parent link
+ */
+pub struct SoaStore<T, I: SlabIndex = usize> {
+    keys: Vec<mem::MaybeUninit<T>>,
+    children: Vec<[I; 2]>,
+    parents: Vec<I>,
+    red: Vec<u64>, // one bit per slot
+    free_head: usize,
+    nil_sentinel: usize,
+}
+
+impl<T, I: SlabIndex> SoaStore<T, I> {
+    pub fn new() -> SoaStore<T, I> {
+        let null_link = I::from_usize(null::<I>());
+
+        // slot 0 is the nil sentinel
+        SoaStore {
+            keys: vec![mem::MaybeUninit::uninit()],
+            children: vec![[null_link, null_link]],
+            parents: vec![null_link],
+            red: vec![0],
+            free_head: null::<I>(),
+            nil_sentinel: 0,
+        }
+    }
+
+    fn slots(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn push_slot(&mut self) -> usize {
+        let x = self.slots();
+        assert!(x < null::<I>(), "slab is full for {}-bit indices", I::BITS);
+        let nil = I::from_usize(self.nil_sentinel);
+        self.keys.push(mem::MaybeUninit::uninit());
+        self.children.push([nil, nil]);
+        self.parents.push(nil);
+        if x % 64 == 0 {
+            self.red.push(0);
+        }
+        x
+    }
+}
+
+impl<T, I: SlabIndex> Default for SoaStore<T, I> {
+    fn default() -> SoaStore<T, I> {
+        SoaStore::new()
+    }
+}
+
+impl<T, I: SlabIndex> NodeStore<T> for SoaStore<T, I> {
+    type Link = usize;
+
+    fn nil(&self) -> usize {
+        self.nil_sentinel
+    }
+
+    fn alloc(&mut self, key: T) -> usize {
+        let x = if self.free_head == null::<I>() {
+            self.push_slot()
+        } else {
+            let x = self.free_head;
+            self.free_head = self.parents[x].to_usize();
+            x
+        };
+        let nil = I::from_usize(self.nil_sentinel);
+        self.keys[x] = mem::MaybeUninit::new(key);
+        self.children[x] = [nil, nil];
+        self.parents[x] = nil;
+        self.set_red(x, false);
+        x
+    }
+
+    fn free(&mut self, x: usize) -> T {
+        self.parents[x] = I::from_usize(self.free_head);
+        self.free_head = x;
+        let key = mem::replace(&mut self.keys[x], mem::MaybeUninit::uninit());
+        unsafe { key.assume_init() }
+    }
+
+    fn parent(&self, x: usize) -> usize {
+        self.parents[x].to_usize()
+    }
+
+    fn set_parent(&mut self, x: usize, parent: usize) {
+        self.parents[x] = I::from_usize(parent);
+    }
+
+    fn child(&self, x: usize, dir: usize) -> usize {
+        self.children[x][dir].to_usize()
+    }
+
+    fn set_child(&mut self, x: usize, dir: usize, child: usize) {
+        self.children[x][dir] = I::from_usize(child);
+    }
+
+    fn is_red(&self, x: usize) -> bool {
+        self.red[x / 64] & (1 << (x % 64)) != 0
+    }
+
+    fn set_red(&mut self, x: usize, red: bool) {
+        if red {
+            self.red[x / 64] |= 1 << (x % 64);
+        } else {
+            self.red[x / 64] &= !(1 << (x % 64));
+        }
+    }
+
+    fn key(&self, x: usize) -> &T {
+        debug_assert!(x != self.nil_sentinel);
+        unsafe { &*self.keys[x].as_ptr() }
+    }
+
+    fn key_mut(&mut self, x: usize) -> &mut T {
+        debug_assert!(x != self.nil_sentinel);
+        unsafe { &mut *self.keys[x].as_mut_ptr() }
+    }
+
+    fn capacity(&self) -> usize {
+        let slots = self
+            .keys
+            .capacity()
+            .min(self.children.capacity())
+            .min(self.parents.capacity());
+        slots - 1
+    }
+
+    // the color bit is left out, it rounds to nothing per slot
+    fn slot_size(&self) -> usize {
+        mem::size_of::<T>() + 3 * mem::size_of::<I>()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve(additional) {
+            panic!("{}", e);
+        }
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> Result<(), ReserveError> {
+        match self.slots().checked_add(additional) {
+            Some(needed) if needed <= null::<I>() => {}
+            _ => return Err(ReserveError::CapacityOverflow),
+        }
+        self.keys.try_reserve(additional)?;
+        self.children.try_reserve(additional)?;
+        self.parents.try_reserve(additional)?;
+        self.red.try_reserve(additional / 64 + 1)?;
+        Ok(())
+    }
+
+    fn shrink_to_fit(&mut self, root: usize) -> usize {
+        let mut live = vec![false; self.slots()];
+        live[self.nil_sentinel] = true;
+        let mut stack = vec![root];
+        while let Some(x) = stack.pop() {
+            if x == self.nil_sentinel {
+                continue;
+            }
+            live[x] = true;
+            stack.push(self.child(x, 0));
+            stack.push(self.child(x, 1));
+        }
+
+        // slide live slots to the front, keeping their order
+        let mut remap = vec![null::<I>(); self.slots()];
+        let mut next = 0;
+        for old in 0..self.slots() {
+            if live[old] {
+                remap[old] = next;
+                self.keys.swap(old, next);
+                self.children.swap(old, next);
+                self.parents.swap(old, next);
+                let red = self.is_red(old);
+                self.set_red(next, red);
+                next += 1;
+            }
+        }
+
+        self.keys.truncate(next);
+        self.children.truncate(next);
+        self.parents.truncate(next);
+        self.red.truncate(next.div_ceil(64));
+        self.keys.shrink_to_fit();
+        self.children.shrink_to_fit();
+        self.parents.shrink_to_fit();
+        self.red.shrink_to_fit();
+        self.free_head = null::<I>();
+
+        for x in 1..next {
+            self.parents[x] = I::from_usize(remap[self.parents[x].to_usize()]);
+            let [l, r] = self.children[x];
+            self.children[x] = [
+                I::from_usize(remap[l.to_usize()]),
+                I::from_usize(remap[r.to_usize()]),
+            ];
+        }
+        remap[root]
+    }
+}
+
+pub type SoaRedBlack<T, I = usize> = RedBlackTree<T, SoaStore<T, I>>;
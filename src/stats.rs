@@ -0,0 +1,222 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const INSERT: usize = 0;
+const DELETE: usize = 1;
+
+/*
+ * running operation counts, bumped through the stat! macro in tree.rs
+ *
+ * searches go through &self, so those two are atomics; rotations and
+ * recolorings are charged to whichever mutation is in progress
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    op: usize,
+    searches: AtomicU64,
+    comparisons: AtomicU64,
+    ops: [u64; 2],
+    rotations: [u64; 2],
+    recolors: [u64; 2],
+}
+
+impl Counters {
+    pub(crate) fn searched(&self) {
+        self.searches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn compared(&self) {
+        self.comparisons.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inserting(&mut self) {
+        self.op = INSERT;
+        self.ops[INSERT] += 1;
+    }
+
+    pub(crate) fn deleting(&mut self) {
+        self.op = DELETE;
+        self.ops[DELETE] += 1;
+    }
+
+    pub(crate) fn rotated(&mut self) {
+        self.rotations[self.op] += 1;
+    }
+
+    pub(crate) fn recolored(&mut self) {
+        self.recolors[self.op] += 1;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    // lookups, including the one every delete starts with
+    pub searches: u64,
+    // keys looked at by those lookups
+    pub comparisons: u64,
+    pub inserts: u64,
+    pub insert_rotations: u64,
+    pub insert_recolors: u64,
+    // only deletes that found their key
+    pub deletes: u64,
+    pub delete_rotations: u64,
+    pub delete_recolors: u64,
+
+    // the shape of the tree when stats() was called
+    pub len: usize,
+    pub height: usize,
+    // 2 * log2(len + 1), which height never exceeds
+    pub height_bound: f64,
+    pub black_height: usize,
+    // depth_histogram[d] is the number of nodes d links below the root
+    pub depth_histogram: Vec<usize>,
+}
+
+fn per(count: u64, ops: u64) -> f64 {
+    if ops == 0 {
+        0.0
+    } else {
+        count as f64 / ops as f64
+    }
+}
+
+impl Stats {
+    pub fn comparisons_per_search(&self) -> f64 {
+        per(self.comparisons, self.searches)
+    }
+
+    pub fn rotations_per_insert(&self) -> f64 {
+        per(self.insert_rotations, self.inserts)
+    }
+
+    pub fn recolors_per_insert(&self) -> f64 {
+        per(self.insert_recolors, self.inserts)
+    }
+
+    pub fn rotations_per_delete(&self) -> f64 {
+        per(self.delete_rotations, self.deletes)
+    }
+
+    pub fn recolors_per_delete(&self) -> f64 {
+        per(self.delete_recolors, self.deletes)
+    }
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    // counters since the tree was built or reset_stats, plus its current shape
+    pub fn stats(&self) -> Stats {
+        let nil = self.store.nil();
+        let mut depth_histogram = Vec::new();
+        let mut stack = vec![(self.root, 0)];
+        while let Some((x, depth)) = stack.pop() {
+            if x == nil {
+                continue;
+            }
+            if depth_histogram.len() <= depth {
+                depth_histogram.resize(depth + 1, 0);
+            }
+            depth_histogram[depth] += 1;
+            stack.push((self.store.child(x, 0), depth + 1));
+            stack.push((self.store.child(x, 1), depth + 1));
+        }
+
+        // every path has the same black height, so the leftmost one will do
+        let mut black_height = 0;
+        let mut x = self.root;
+        while x != nil {
+            if !self.store.is_red(x) {
+                black_height += 1;
+            }
+            x = self.store.child(x, 0);
+        }
+
+        let c = &self.counters;
+        Stats {
+            searches: c.searches.load(Ordering::Relaxed),
+            comparisons: c.comparisons.load(Ordering::Relaxed),
+            inserts: c.ops[INSERT],
+            insert_rotations: c.rotations[INSERT],
+            insert_recolors: c.recolors[INSERT],
+            deletes: c.ops[DELETE],
+            delete_rotations: c.rotations[DELETE],
+            delete_recolors: c.recolors[DELETE],
+            len: self.len,
+            height: depth_histogram.len(),
+            height_bound: 2.0 * ((self.len + 1) as f64).log2(),
+            black_height,
+            depth_histogram,
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.counters = Counters::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    #[test]
+    fn test_stats_counts() {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        rb.insert(1);
+        rb.insert(2);
+        rb.insert(3);
+        rb.search(&3);
+        rb.search(&4);
+
+        // 1-2-3 takes one rotation; 2 is blackened and 1 reddened, on top
+        // of blackening 1 as the first root
+        let stats = rb.stats();
+        assert_eq!(stats.inserts, 3);
+        assert_eq!(stats.insert_rotations, 1);
+        assert_eq!(stats.insert_recolors, 3);
+        assert_eq!(stats.searches, 2);
+        assert_eq!(stats.comparisons, 4);
+        assert_eq!(stats.comparisons_per_search(), 2.0);
+        assert_eq!(stats.depth_histogram, vec![1, 2]);
+        assert_eq!(stats.black_height, 1);
+
+        rb.delete(&5);
+        rb.delete(&1);
+        let stats = rb.stats();
+        assert_eq!(stats.deletes, 1);
+        assert_eq!(stats.searches, 4);
+        assert_eq!(stats.len, 2);
+
+        rb.reset_stats();
+        let stats = rb.stats();
+        assert_eq!(stats.searches, 0);
+        assert_eq!(stats.inserts, 0);
+        assert_eq!(stats.len, 2);
+    }
+
+    #[test]
+    fn test_stats_shape() {
+        let mut rb: PointerRedBlack<u32> = PointerRedBlack::new();
+        for i in 0..1000 {
+            rb.insert(i);
+        }
+        for i in 0..500 {
+            rb.delete(&(i * 2));
+        }
+
+        let stats = rb.stats();
+        let tree = rb.validate().unwrap();
+        assert_eq!(stats.len, 500);
+        assert_eq!(stats.height, tree.height);
+        assert_eq!(stats.black_height, tree.black_height);
+        assert!(stats.height as f64 <= stats.height_bound);
+        assert_eq!(stats.depth_histogram.iter().sum::<usize>(), 500);
+        assert!(stats.rotations_per_insert() < 1.0);
+        assert!(stats.rotations_per_delete() <= 3.0);
+    }
+}
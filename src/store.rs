@@ -0,0 +1,206 @@
+use crate::memory::ReserveError;
+use std::{fmt, mem};
+
+pub(crate) const NULL: usize = !0;
+
+/*
+ * storage interface shared by every backend
+ *
+ * the red-black algorithms in tree.rs only ever talk to nodes through these
+ * link getters/setters, so a backend is just "how do I find node x"
+ *
+ * every store owns a black nil sentinel; its key is never read, but its
+ * parent and color are (CLRS delete writes x.parent even when x is nil)
+ *
+ * a link is only meaningful to the store that issued it and only until it is
+ * freed, and none of these methods check it: a stale link reads freed memory
+ * or a vacant slot. so the trait stays inside the crate; it's `pub` only to
+ * bound RedBlackTree's parameter, but this module isn't exported, so nothing
+ * outside can name it to call or implement it. RedBlackTree upholds the
+ * contract, and so must any code here that drives a store by hand
+ *
+ * the store does not own the keys of live nodes: RedBlackTree frees every
+ * reachable node when it is dropped, so a store's own Drop only has to
+ * release its memory and the sentinel
+ */
+pub trait NodeStore<T> {
+    type Link: Copy + PartialEq + fmt::Debug;
+
+    fn nil(&self) -> Self::Link;
+
+    // allocate a black node with all links pointing to the nil sentinel
+    fn alloc(&mut self, key: T) -> Self::Link;
+
+    // release a node that is no longer reachable, handing back its key
+    fn free(&mut self, x: Self::Link) -> T;
+
+    fn parent(&self, x: Self::Link) -> Self::Link;
+    fn set_parent(&mut self, x: Self::Link, parent: Self::Link);
+
+    fn child(&self, x: Self::Link, dir: usize) -> Self::Link;
+    fn set_child(&mut self, x: Self::Link, dir: usize, child: Self::Link);
+
+    fn is_red(&self, x: Self::Link) -> bool;
+    fn set_red(&mut self, x: Self::Link, red: bool);
+
+    // must not be called on the nil sentinel
+    fn key(&self, x: Self::Link) -> &T;
+    fn key_mut(&mut self, x: Self::Link) -> &mut T;
+
+    // look at x's key without keeping a reference to it; the tree compares
+    // through this, so a store that has to keep every key it hands out
+    // alive (paged.rs) only does so for the ones that leave the tree
+    fn with_key<R, F: FnOnce(&T) -> R>(&self, x: Self::Link, f: F) -> R {
+        f(self.key(x))
+    }
+
+    // nodes (sentinel excluded) the store can hold before it has to allocate
+    fn capacity(&self) -> usize;
+
+    // bytes taken by one node slot, including any per-slot bookkeeping
+    fn slot_size(&self) -> usize;
+
+    // stores that allocate node by node have nothing to reserve or shrink
+    fn reserve(&mut self, _additional: usize) {}
+
+    fn try_reserve(&mut self, _additional: usize) -> Result<(), ReserveError> {
+        Ok(())
+    }
+
+    // a store that caches nodes on reads through &self can only let go of
+    // them here; the tree calls it before every operation made through &mut
+    fn trim(&mut self) {}
+
+    // free y, spliced out by a delete, moving its key into z if z != y
+    // and dropping the key that was deleted
+    fn splice_out(&mut self, y: Self::Link, z: Self::Link) {
+        let mut y_key = self.free(y);
+        if y != z {
+            mem::swap(self.key_mut(z), &mut y_key);
+        }
+    }
+
+    // give back vacant slots; nodes may move, so every link is rewritten and
+    // the new link of `root` is returned
+    fn shrink_to_fit(&mut self, root: Self::Link) -> Self::Link {
+        root
+    }
+}
+
+// node layout shared by the index-addressed stores (slab, vec arena, bump arena)
+pub(crate) struct IndexNode<T> {
+    pub(crate) parent: usize,
+    pub(crate) children: [usize; 2],
+    pub(crate) key: mem::MaybeUninit<T>,
+    pub(crate) red: bool,
+}
+
+impl<T> IndexNode<T> {
+    pub(crate) fn new(key: T, nil_sentinel: usize) -> IndexNode<T> {
+        IndexNode {
+            parent: nil_sentinel,
+            children: [nil_sentinel, nil_sentinel],
+            key: mem::MaybeUninit::new(key),
+            red: false,
+        }
+    }
+
+    pub(crate) fn nil_sentinel() -> IndexNode<T> {
+        IndexNode {
+            parent: NULL,
+            children: [NULL, NULL],
+            key: mem::MaybeUninit::uninit(),
+            red: false,
+        }
+    }
+
+    // only valid on nodes built with new() whose key hasn't been taken yet
+    pub(crate) unsafe fn key(&self) -> &T {
+        &*self.key.as_ptr()
+    }
+
+    pub(crate) unsafe fn key_mut(&mut self) -> &mut T {
+        &mut *self.key.as_mut_ptr()
+    }
+}
+
+/*
+ * the index stores all implement the link accessors the same way,
+ * given an expression that indexes a node by usize
+ */
+macro_rules! index_node_links {
+    ($nodes:ident) => {
+        fn parent(&self, x: usize) -> usize {
+            self.$nodes[x].parent
+        }
+
+        fn set_parent(&mut self, x: usize, parent: usize) {
+            self.$nodes[x].parent = parent;
+        }
+
+        fn child(&self, x: usize, dir: usize) -> usize {
+            self.$nodes[x].children[dir]
+        }
+
+        fn set_child(&mut self, x: usize, dir: usize, child: usize) {
+            self.$nodes[x].children[dir] = child;
+        }
+
+        fn is_red(&self, x: usize) -> bool {
+            self.$nodes[x].red
+        }
+
+        fn set_red(&mut self, x: usize, red: bool) {
+            self.$nodes[x].red = red;
+        }
+
+        fn key(&self, x: usize) -> &T {
+            debug_assert!(x != self.nil_sentinel);
+            unsafe { self.$nodes[x].key() }
+        }
+
+        fn key_mut(&mut self, x: usize) -> &mut T {
+            debug_assert!(x != self.nil_sentinel);
+            unsafe { self.$nodes[x].key_mut() }
+        }
+    };
+}
+
+/*
+ * move the nodes reachable from `root` to the front of `nodes`, keeping their
+ * relative order, then drop the tail and rewrite every link
+ *
+ * entry 0 (the sentinel) stays put; returns the new index of root
+ */
+pub(crate) fn compact_index_nodes<T>(nodes: &mut Vec<IndexNode<T>>, root: usize) -> usize {
+    let mut live = vec![false; nodes.len()];
+    live[0] = true;
+    let mut stack = vec![root];
+    while let Some(x) = stack.pop() {
+        if x == 0 {
+            continue;
+        }
+        live[x] = true;
+        stack.extend_from_slice(&nodes[x].children);
+    }
+
+    let mut remap = vec![NULL; nodes.len()];
+    let mut next = 0;
+    for old in 0..nodes.len() {
+        if live[old] {
+            remap[old] = next;
+            nodes.swap(old, next);
+            next += 1;
+        }
+    }
+
+    // everything from `next` on is a vacant or dead slot with no key to drop
+    nodes.truncate(next);
+    nodes.shrink_to_fit();
+
+    for node in nodes.iter_mut().skip(1) {
+        node.parent = remap[node.parent];
+        node.children = [remap[node.children[0]], remap[node.children[1]]];
+    }
+    remap[root]
+}
@@ -0,0 +1,299 @@
+use crate::slab::{SlabIndex, SlabRedBlack};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// numbers every transaction, so a savepoint can tell which one it's from
+static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+/*
+ * a batch of updates to a SlabRedBlack that applies fully or not at all
+ *
+ * while a transaction is open the slab journals every link, color and
+ * slot it touches, along with the keys a delete drops or moves; rolling
+ * back plays that journal backwards, so the tree comes back exactly as it
+ * was, node for node and slot for slot, not just with the same keys
+ *
+ * dropping a transaction without committing it rolls it back
+ */
+pub struct Transaction<'a, T: PartialOrd, I: SlabIndex = usize> {
+    tree: &'a mut SlabRedBlack<T, I>,
+    id: u64,
+    begun: Savepoint,
+    // serials of the savepoints that can still be rolled back to, in order
+    live: Vec<u64>,
+    taken: u64,
+    done: bool,
+}
+
+/*
+ * a point inside a transaction to roll back to; rolling back to one
+ * forgets every savepoint taken after it, and rolling back to a forgotten
+ * one, or one from another transaction, panics
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Savepoint {
+    transaction: u64,
+    serial: u64,
+    mark: usize,
+    root: usize,
+    len: usize,
+}
+
+impl<T: PartialOrd, I: SlabIndex> SlabRedBlack<T, I> {
+    pub fn begin(&mut self) -> Transaction<'_, T, I> {
+        self.store.start_journal();
+        let id = TRANSACTIONS.fetch_add(1, Ordering::Relaxed);
+        let begun = Savepoint {
+            transaction: id,
+            serial: 0,
+            mark: 0,
+            root: self.root,
+            len: self.len,
+        };
+        Transaction {
+            tree: self,
+            id,
+            begun,
+            live: Vec::new(),
+            taken: 0,
+            done: false,
+        }
+    }
+}
+
+impl<'a, T: PartialOrd, I: SlabIndex> Transaction<'a, T, I> {
+    pub fn insert(&mut self, key: T) {
+        self.tree.insert(key);
+    }
+
+    pub fn delete(&mut self, key: &T) {
+        self.tree.delete(key);
+    }
+
+    pub fn search(&self, key: &T) -> Option<&T> {
+        self.tree.search(key)
+    }
+
+    // the tree as the transaction has it so far
+    pub fn tree(&self) -> &SlabRedBlack<T, I> {
+        self.tree
+    }
+
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.taken += 1;
+        self.live.push(self.taken);
+        Savepoint {
+            transaction: self.id,
+            serial: self.taken,
+            mark: self.tree.store.journal_len(),
+            root: self.tree.root,
+            len: self.tree.len,
+        }
+    }
+
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        assert!(
+            savepoint.transaction == self.id,
+            "savepoint belongs to another transaction"
+        );
+        match self.live.binary_search(&savepoint.serial) {
+            Ok(i) => self.live.truncate(i + 1),
+            Err(_) => panic!("savepoint was already rolled back past"),
+        }
+        self.restore(savepoint);
+    }
+
+    fn restore(&mut self, savepoint: Savepoint) {
+        self.tree.store.undo_to(savepoint.mark);
+        self.tree.root = savepoint.root;
+        self.tree.len = savepoint.len;
+        // Drop restores too, and a second panic while unwinding would abort
+        #[cfg(debug_assertions)]
+        if !std::thread::panicking() {
+            if let Err(violation) = self.tree.validate() {
+                panic!("rollback left a broken tree: {}", violation);
+            }
+        }
+    }
+
+    // keep every change; the journal and the keys it held go away
+    pub fn commit(mut self) {
+        self.done = true;
+        self.tree.store.stop_journal();
+    }
+
+    pub fn rollback(self) {
+        // Drop does it
+    }
+}
+
+impl<'a, T: PartialOrd, I: SlabIndex> Drop for Transaction<'a, T, I> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.restore(self.begun);
+            self.tree.store.stop_journal();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+    use crate::store::NodeStore;
+
+    // every node with its slot, links, color and key, in slot order
+    fn shape(rb: &SlabRedBlack<u32>) -> Vec<(usize, usize, usize, usize, bool, u32)> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![rb.root];
+        let nil = rb.store.nil();
+        while let Some(x) = stack.pop() {
+            if x == nil {
+                continue;
+            }
+            let s = &rb.store;
+            let (l, r) = (s.child(x, 0), s.child(x, 1));
+            nodes.push((x, s.parent(x), l, r, s.is_red(x), *s.key(x)));
+            stack.push(l);
+            stack.push(r);
+        }
+        nodes.sort_unstable();
+        nodes
+    }
+
+    fn filled() -> SlabRedBlack<u32> {
+        let mut rb: SlabRedBlack<u32> = SlabRedBlack::new();
+        for i in 0..200 {
+            rb.insert(i * 7919 % 200);
+        }
+        for i in (0..200).step_by(7) {
+            rb.delete(&i);
+        }
+        rb
+    }
+
+    #[test]
+    fn test_rollback_restores_exact_tree() {
+        let mut rb = filled();
+        let before = shape(&rb);
+
+        let mut tx = rb.begin();
+        for i in 0..100 {
+            // deletes of inner nodes move keys between slots
+            tx.delete(&(i * 3));
+            tx.insert(1000 + i);
+            tx.insert(i);
+        }
+        assert_eq!(tx.search(&1050), Some(&1050));
+        tx.rollback();
+
+        assert_eq!(shape(&rb), before);
+        assert_eq!(rb.len(), before.len());
+        rb.is_valid(); // will panic if it must
+
+        // the freed slots come back in the same order, too
+        let mut control = filled();
+        rb.insert(5000);
+        control.insert(5000);
+        assert_eq!(shape(&rb), shape(&control));
+    }
+
+    #[test]
+    fn test_commit_and_drop() {
+        let mut rb = filled();
+        let before = shape(&rb);
+        {
+            let mut tx = rb.begin();
+            tx.delete(&1);
+            tx.insert(500);
+            // dropped without commit
+        }
+        assert_eq!(shape(&rb), before);
+
+        let mut tx = rb.begin();
+        tx.delete(&1);
+        tx.insert(500);
+        tx.commit();
+        assert_eq!(rb.search(&1), None);
+        assert_eq!(rb.search(&500), Some(&500));
+        assert_eq!(rb.len(), before.len());
+        rb.is_valid(); // will panic if it must
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut rb = filled();
+        let before = shape(&rb);
+
+        let mut tx = rb.begin();
+        tx.insert(300);
+        let first = tx.savepoint();
+        tx.insert(301);
+        let mid = tx.tree().len();
+        let second = tx.savepoint();
+        tx.delete(&300);
+        tx.delete(&2);
+        tx.rollback_to(second);
+        assert_eq!(tx.tree().len(), mid);
+        assert_eq!(tx.search(&300), Some(&300));
+        assert_eq!(tx.search(&2), Some(&2));
+
+        tx.rollback_to(first);
+        assert_eq!(tx.search(&301), None);
+        tx.insert(302);
+        tx.commit();
+
+        assert_eq!(rb.search(&300), Some(&300));
+        assert_eq!(rb.search(&302), Some(&302));
+        assert_eq!(rb.len(), before.len() + 2);
+        rb.is_valid(); // will panic if it must
+    }
+
+    // a transaction dropped by a panic doesn't panic again in Drop
+    #[test]
+    fn test_rollback_while_unwinding() {
+        let mut rb = filled();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let tx = rb.begin();
+            // a red root the journal never sees, so the tree Drop restores
+            // is broken and validating it would panic
+            let root = tx.tree.root;
+            tx.tree.store.stop_journal();
+            tx.tree.store.set_red(root, true);
+            tx.tree.store.start_journal();
+            panic!("mid-transaction");
+        }));
+        assert!(result.is_err());
+        assert!(rb.validate().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "savepoint was already rolled back past")]
+    fn test_stale_savepoint() {
+        let mut rb = filled();
+        let mut tx = rb.begin();
+        let first = tx.savepoint();
+        tx.insert(300);
+        let second = tx.savepoint();
+        tx.rollback_to(first);
+        tx.rollback_to(first);
+
+        // the journal has grown back past second, which is still forgotten
+        tx.insert(301);
+        tx.insert(302);
+        tx.rollback_to(second);
+    }
+
+    #[test]
+    #[should_panic(expected = "savepoint belongs to another transaction")]
+    fn test_savepoint_of_other_transaction() {
+        let mut rb = filled();
+        let mut tx = rb.begin();
+        tx.insert(300);
+        let old = tx.savepoint();
+        tx.commit();
+
+        let mut tx = rb.begin();
+        tx.insert(301);
+        tx.rollback_to(old);
+    }
+}
@@ -0,0 +1,683 @@
+use crate::memory::{HeapSize, MemoryUsage, ReserveError};
+use crate::observe::{Observer, TreeEvent};
+use crate::pretty::PrettyOptions;
+use crate::redblack::RedBlack;
+use crate::store::NodeStore;
+use std::marker::PhantomData;
+
+/*
+ * with the paranoid feature, the tree is re-validated after every rotation,
+ * fixup case and mutation, panicking with the step that broke it; without
+ * it these expand to nothing
+ *
+ * mid-fixup the color invariants are allowed to be broken, so those steps
+ * only check structure (order, links, len); `full` checks everything
+ */
+#[cfg(feature = "paranoid")]
+macro_rules! paranoid {
+    ($tree:expr, $step:expr) => {
+        $tree.paranoid_check($step, false)
+    };
+    ($tree:expr, $step:expr, full) => {
+        $tree.paranoid_check($step, true)
+    };
+}
+
+#[cfg(not(feature = "paranoid"))]
+macro_rules! paranoid {
+    ($tree:expr, $step:expr) => {};
+    ($tree:expr, $step:expr, full) => {};
+}
+
+/*
+ * with the stats feature, operation counts are kept in the tree's Counters
+ * (see stats.rs); without it these expand to nothing as well
+ */
+#[cfg(feature = "stats")]
+macro_rules! stat {
+    ($tree:expr, $event:ident) => {
+        $tree.counters.$event()
+    };
+}
+
+#[cfg(not(feature = "stats"))]
+macro_rules! stat {
+    ($tree:expr, $event:ident) => {};
+}
+
+/*
+ * the CLRS red-black tree, written once against NodeStore
+ *
+ * SlabRedBlack, PointerRedBlack etc. are aliases that pick a store
+ */
+pub struct RedBlackTree<T, S: NodeStore<T>> {
+    pub(crate) store: S,
+    pub(crate) root: S::Link,
+    pub(crate) len: usize,
+    pub(crate) observer: Option<Box<Observer<T>>>,
+    #[cfg(feature = "paranoid")]
+    op: &'static str,
+    #[cfg(feature = "stats")]
+    pub(crate) counters: crate::stats::Counters,
+    _key: PhantomData<T>,
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    pub fn with_store(store: S) -> RedBlackTree<T, S> {
+        let root = store.nil();
+        RedBlackTree {
+            store,
+            root,
+            len: 0,
+            observer: None,
+            #[cfg(feature = "paranoid")]
+            op: "rotate",
+            #[cfg(feature = "stats")]
+            counters: Default::default(),
+            _key: PhantomData,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> RedBlackTree<T, S>
+    where
+        S: Default,
+    {
+        let mut rb = RedBlackTree::with_store(S::default());
+        rb.reserve(capacity);
+        rb
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // number of keys the tree can hold before its store allocates again
+    pub fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.store.reserve(additional)
+    }
+
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), ReserveError> {
+        self.store.try_reserve(additional)
+    }
+
+    // compacts the store, which may move nodes (and renumber slab indices)
+    pub fn shrink_to_fit(&mut self) {
+        self.root = self.store.shrink_to_fit(self.root);
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage
+    where
+        T: HeapSize,
+    {
+        let nil = self.store.nil();
+        let mut keys = 0;
+        let mut stack = vec![self.root];
+        while let Some(x) = stack.pop() {
+            if x == nil {
+                continue;
+            }
+            keys += self.store.with_key(x, T::heap_size);
+            stack.push(self.store.child(x, 0));
+            stack.push(self.store.child(x, 1));
+        }
+
+        let slot = self.store.slot_size();
+        MemoryUsage {
+            nodes: (self.len + 1) * slot,
+            vacant: (self.store.capacity() - self.len) * slot,
+            keys,
+        }
+    }
+
+    pub(crate) fn rotate(&mut self, x: S::Link, dir: usize) {
+        self.observe(TreeEvent::Rotate { node: x, dir });
+        stat!(self, rotated);
+        let nil = self.store.nil();
+        let y = self.store.child(x, dir ^ 1);
+        let y_chld = self.store.child(y, dir);
+        self.store.set_child(x, dir ^ 1, y_chld);
+        if y_chld != nil {
+            self.store.set_parent(y_chld, x);
+        }
+        let x_parent = self.store.parent(x);
+        self.store.set_parent(y, x_parent);
+        if x_parent == nil {
+            self.root = y;
+        } else {
+            let sib_dir = if self.store.child(x_parent, 0) == x {
+                0
+            } else {
+                1
+            };
+            self.store.set_child(x_parent, sib_dir, y);
+        }
+        self.store.set_child(y, dir, x);
+        self.store.set_parent(x, y);
+
+        paranoid!(self, "rotate");
+    }
+
+    // set_red that reports actual color changes to the observer
+    fn recolor(&mut self, x: S::Link, red: bool) {
+        if self.store.is_red(x) != red {
+            self.store.set_red(x, red);
+            stat!(self, recolored);
+            self.observe(TreeEvent::Recolor { node: x, red });
+        }
+    }
+
+    fn tree_minimum(&self, mut x: S::Link) -> S::Link {
+        let nil = self.store.nil();
+        let mut l = self.store.child(x, 0);
+        while l != nil {
+            x = l;
+            l = self.store.child(x, 0);
+        }
+        x
+    }
+
+    fn tree_successor(&self, mut x: S::Link) -> S::Link {
+        let nil = self.store.nil();
+        let r = self.store.child(x, 1);
+        if r != nil {
+            return self.tree_minimum(r);
+        }
+        let mut y = self.store.parent(x);
+        while y != nil && x == self.store.child(y, 1) {
+            x = y;
+            y = self.store.parent(y);
+        }
+        y
+    }
+
+    fn insert_fixup(&mut self, mut z: S::Link) {
+        let mut p = self.store.parent(z);
+
+        while self.store.is_red(p) {
+            let pp = self.store.parent(p);
+
+            let dir = if self.store.child(pp, 0) == p { 1 } else { 0 };
+
+            let y = self.store.child(pp, dir);
+
+            if self.store.is_red(y) {
+                self.observe(TreeEvent::InsertCase(1));
+                self.recolor(p, false);
+                self.recolor(y, false);
+                self.recolor(pp, true);
+                z = pp;
+                paranoid!(self, "insert fixup case 1");
+            } else {
+                // y is black, or nil sentinel
+                if z == self.store.child(p, dir) {
+                    self.observe(TreeEvent::InsertCase(2));
+                    z = p;
+                    self.rotate(z, dir ^ 1);
+                    paranoid!(self, "insert fixup case 2");
+                }
+
+                // z may have moved down a level, so reload its parent
+                self.observe(TreeEvent::InsertCase(3));
+                let p = self.store.parent(z);
+                self.recolor(p, false);
+                self.recolor(pp, true);
+                self.rotate(pp, dir);
+                paranoid!(self, "insert fixup case 3");
+            }
+
+            // recompute parent after changing z or rotating
+            p = self.store.parent(z);
+        }
+
+        // blacken the root
+        let root = self.root;
+        self.recolor(root, false);
+    }
+
+    fn delete_fixup(&mut self, mut x: S::Link) {
+        while x != self.root && !self.store.is_red(x) {
+            let p = self.store.parent(x);
+            let dir = if x == self.store.child(p, 0) { 1 } else { 0 };
+            let mut w = self.store.child(p, dir);
+            if self.store.is_red(w) {
+                self.observe(TreeEvent::DeleteCase(1));
+                self.recolor(w, false);
+                self.recolor(p, true);
+                self.rotate(p, dir ^ 1);
+
+                // recompute w after the rotation of p
+                w = self.store.child(p, dir);
+                paranoid!(self, "delete fixup case 1");
+            }
+            let wl = self.store.child(w, 0);
+            let wr = self.store.child(w, 1);
+            if !self.store.is_red(wl) && !self.store.is_red(wr) {
+                self.observe(TreeEvent::DeleteCase(2));
+                self.recolor(w, true);
+                x = p;
+                paranoid!(self, "delete fixup case 2");
+            } else {
+                let mut wc = self.store.child(w, dir); // w child i care about
+                let wo = self.store.child(w, dir ^ 1); // w other child
+                if !self.store.is_red(wc) {
+                    self.observe(TreeEvent::DeleteCase(3));
+                    self.recolor(wo, false);
+                    self.recolor(w, true);
+                    self.rotate(w, dir);
+                    w = self.store.child(p, dir);
+
+                    // recompute wc after the rotation of w
+                    wc = self.store.child(w, dir);
+                    paranoid!(self, "delete fixup case 3");
+                }
+                self.observe(TreeEvent::DeleteCase(4));
+                let p_red = self.store.is_red(p);
+                self.recolor(w, p_red);
+                self.recolor(p, false);
+                self.recolor(wc, false);
+                self.rotate(p, dir ^ 1);
+                x = self.root;
+                paranoid!(self, "delete fixup case 4");
+            }
+        }
+
+        // blacken x
+        self.recolor(x, false);
+    }
+
+    // whether x's key sorts before y's
+    pub(crate) fn less(&self, x: S::Link, y: S::Link) -> bool {
+        self.store
+            .with_key(x, |a| self.store.with_key(y, |b| *a < *b))
+    }
+
+    pub(crate) fn search_(&self, key: &T) -> Option<S::Link> {
+        let nil = self.store.nil();
+        let mut curr = self.root;
+        stat!(self, searched);
+
+        while curr != nil {
+            stat!(self, compared);
+            let direction = match self.store.with_key(curr, |curr_key| {
+                if *curr_key == *key {
+                    None
+                } else {
+                    Some(if *curr_key < *key { 1 } else { 0 })
+                }
+            }) {
+                Some(direction) => direction,
+                None => return Some(curr),
+            };
+            curr = self.store.child(curr, direction);
+        }
+        None
+    }
+
+    /*
+     * search by something other than a whole key, e.g. the key half of a
+     * key-value pair; cmp orders a key in the tree against the one wanted
+     */
+    pub(crate) fn find<F: Fn(&T) -> std::cmp::Ordering>(&self, cmp: F) -> Option<S::Link> {
+        let nil = self.store.nil();
+        let mut curr = self.root;
+        stat!(self, searched);
+
+        while curr != nil {
+            stat!(self, compared);
+            curr = match self.store.with_key(curr, &cmp) {
+                std::cmp::Ordering::Equal => return Some(curr),
+                std::cmp::Ordering::Less => self.store.child(curr, 1),
+                std::cmp::Ordering::Greater => self.store.child(curr, 0),
+            };
+        }
+        None
+    }
+
+    // take every key out, in ascending order, leaving the tree empty
+    pub(crate) fn drain(&mut self) -> Vec<T> {
+        self.store.trim();
+        let links: Vec<S::Link> = {
+            let mut links = Vec::with_capacity(self.len);
+            let mut stack = Vec::new();
+            let nil = self.store.nil();
+            let mut x = self.root;
+            while x != nil || !stack.is_empty() {
+                while x != nil {
+                    stack.push(x);
+                    x = self.store.child(x, 0);
+                }
+                x = stack.pop().unwrap();
+                links.push(x);
+                x = self.store.child(x, 1);
+            }
+            links
+        };
+        self.root = self.store.nil();
+        self.len = 0;
+        links.into_iter().map(|x| self.store.free(x)).collect()
+    }
+
+    pub fn search(&self, key: &T) -> Option<&T> {
+        if let Some(found) = self.search_(key) {
+            return Some(self.store.key(found));
+        }
+        None
+    }
+
+    pub fn insert(&mut self, key: T) {
+        self.store.trim();
+        #[cfg(feature = "paranoid")]
+        {
+            self.op = "insert";
+        }
+        stat!(self, inserting);
+        let nil = self.store.nil();
+        let z = self.store.alloc(key);
+        self.len += 1;
+
+        let mut y = nil;
+        let mut x = self.root;
+
+        while x != nil {
+            y = x;
+            let dir = if self.less(z, x) { 0 } else { 1 };
+            x = self.store.child(x, dir);
+        }
+
+        self.store.set_parent(z, y);
+        if y == nil {
+            self.root = z;
+        } else {
+            let dir = if self.less(z, y) { 0 } else { 1 };
+            self.store.set_child(y, dir, z);
+        }
+
+        self.store.set_red(z, true);
+        paranoid!(self, "link");
+
+        self.insert_fixup(z);
+        paranoid!(self, "done", full);
+    }
+
+    pub fn delete(&mut self, key: &T) {
+        self.store.trim();
+        if let Some(z) = self.search_(key) {
+            self.delete_at(z);
+        }
+    }
+
+    // delete node z, found by search_ or find
+    pub(crate) fn delete_at(&mut self, z: S::Link) {
+        let nil = self.store.nil();
+        #[cfg(feature = "paranoid")]
+        {
+            self.op = "delete";
+        }
+        stat!(self, deleting);
+
+        let y = if self.store.child(z, 0) == nil || self.store.child(z, 1) == nil {
+            z
+        } else {
+            self.tree_successor(z)
+        };
+
+        let dir = if self.store.child(y, 0) != nil { 0 } else { 1 };
+        let x = self.store.child(y, dir);
+
+        self.observe(TreeEvent::Splice { node: y });
+        let yp = self.store.parent(y);
+
+        self.store.set_parent(x, yp);
+
+        if yp == nil {
+            self.root = x;
+        } else {
+            let dir = if y == self.store.child(yp, 0) { 0 } else { 1 };
+            self.store.set_child(yp, dir, x);
+        }
+        self.len -= 1;
+        paranoid!(self, "splice");
+
+        if !self.store.is_red(y) {
+            self.delete_fixup(x);
+        }
+
+        // the spliced-out node doesn't necessarily have to be the deleted one
+        self.store.splice_out(y, z);
+        paranoid!(self, "done", full);
+    }
+
+    #[cfg(feature = "paranoid")]
+    fn paranoid_check(&self, step: &str, full: bool) {
+        if let Err(violation) = self.check_invariants(full) {
+            panic!(
+                "paranoid: {} {} broke the tree: {}",
+                self.op, step, violation
+            );
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_valid(&self) {
+        if let Err(violation) = self.validate() {
+            panic!("red-black properties have been violated: {}", violation);
+        }
+    }
+}
+
+/*
+ * how many keys a test workload of n should use: paranoid validates the
+ * whole tree at every step, O(n) each time, so there it stops at a thousand
+ */
+#[cfg(test)]
+pub(crate) const fn workload(n: usize) -> usize {
+    if cfg!(feature = "paranoid") && n > 1000 {
+        1000
+    } else {
+        n
+    }
+}
+
+impl<T, S> RedBlack<T> for RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T> + Default,
+{
+    fn new() -> RedBlackTree<T, S> {
+        RedBlackTree::with_store(S::default())
+    }
+
+    fn search(&mut self, key: &T) -> Option<&T> {
+        self.store.trim();
+        RedBlackTree::search(self, key)
+    }
+
+    fn delete(&mut self, key: &T) {
+        RedBlackTree::delete(self, key)
+    }
+
+    fn insert(&mut self, key: T) {
+        RedBlackTree::insert(self, key)
+    }
+
+    fn pretty_with(&self, options: PrettyOptions) -> String
+    where
+        T: std::fmt::Debug,
+    {
+        RedBlackTree::pretty_with(self, options)
+    }
+}
+
+impl<T, S: NodeStore<T>> Drop for RedBlackTree<T, S> {
+    fn drop(&mut self) {
+        // the store only owns the nil sentinel, every real node is freed here
+        let nil = self.store.nil();
+        let mut stack = vec![self.root];
+        while let Some(x) = stack.pop() {
+            if x == nil {
+                continue;
+            }
+            stack.push(self.store.child(x, 0));
+            stack.push(self.store.child(x, 1));
+            self.store.free(x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::{BumpStore, VecStore};
+    use crate::pointer::PointerStore;
+    use crate::slab::SlabStore;
+    use crate::soa::SoaStore;
+
+    // same workload and invariant checks, whatever the backend
+    fn exercise_store<S: NodeStore<u32> + Default>() {
+        let mut rb: RedBlackTree<u32, S> = RedBlackTree::new();
+        let mut num = 1u32;
+        let mut keys = Vec::new();
+
+        for _ in 0..workload(10000) {
+            num = num.wrapping_mul(17).wrapping_add(255);
+            rb.insert(num);
+            keys.push(num);
+        }
+        rb.is_valid(); // will panic if it must
+
+        for key in keys.iter().step_by(3) {
+            rb.delete(key);
+            rb.delete(key);
+        }
+        rb.is_valid(); // will panic if it must
+
+        // reinsert so the vec arena reuses its free-list
+        for key in keys.iter().step_by(6) {
+            rb.insert(*key);
+        }
+        rb.is_valid(); // will panic if it must
+
+        for (i, key) in keys.iter().enumerate() {
+            if i % 3 != 0 || i % 6 == 0 {
+                assert_eq!(rb.search(key), Some(key));
+            }
+        }
+    }
+
+    // shrinking renumbers index stores, so check every key survives the move
+    fn exercise_capacity<S: NodeStore<u32> + Default>() {
+        let mut rb: RedBlackTree<u32, S> = RedBlackTree::with_capacity(1000);
+        assert!(rb.capacity() >= 1000 || rb.capacity() == 0);
+
+        for i in 0..1000 {
+            rb.insert(i);
+        }
+        for i in 100..1000 {
+            rb.delete(&i);
+        }
+        assert_eq!(rb.len(), 100);
+        let before = rb.memory_usage();
+        assert_eq!(before.nodes, 101 * rb.store.slot_size());
+
+        rb.shrink_to_fit();
+        rb.is_valid(); // will panic if it must
+
+        let after = rb.memory_usage();
+        assert_eq!(after.nodes, before.nodes);
+        assert!(after.vacant < before.vacant || before.vacant == 0);
+        assert!(rb.capacity() >= rb.len());
+        for i in 0..1000 {
+            let expected = if i < 100 { Some(&i) } else { None };
+            assert_eq!(rb.search(&i), expected);
+        }
+
+        // and it is still a working tree afterwards
+        for i in 100..200 {
+            rb.insert(i);
+        }
+        rb.is_valid(); // will panic if it must
+        assert_eq!(rb.len(), 200);
+    }
+
+    #[test]
+    fn test_slab_store() {
+        exercise_store::<SlabStore<u32>>();
+        exercise_capacity::<SlabStore<u32>>();
+        exercise_capacity::<SlabStore<u32, u16>>();
+    }
+
+    #[test]
+    fn test_soa_store() {
+        exercise_store::<SoaStore<u32>>();
+        exercise_capacity::<SoaStore<u32>>();
+        exercise_capacity::<SoaStore<u32, u16>>();
+    }
+
+    #[test]
+    fn test_vec_store() {
+        exercise_store::<VecStore<u32>>();
+        exercise_capacity::<VecStore<u32>>();
+    }
+
+    #[test]
+    fn test_bump_store() {
+        exercise_store::<BumpStore<u32>>();
+        exercise_capacity::<BumpStore<u32>>();
+    }
+
+    #[test]
+    fn test_pointer_store() {
+        exercise_store::<PointerStore<u32>>();
+        exercise_capacity::<PointerStore<u32>>();
+    }
+
+    #[test]
+    fn test_memory_usage_counts_key_heap() {
+        let mut rb: RedBlackTree<String, SlabStore<String>> = RedBlackTree::new();
+        let mut expected = 0;
+        for i in 0..100 {
+            let key = format!("key number {}", i);
+            expected += key.capacity();
+            rb.insert(key);
+        }
+        assert_eq!(rb.memory_usage().keys, expected);
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    fn test_paranoid_workload() {
+        let mut rb: RedBlackTree<u32, SlabStore<u32>> = RedBlackTree::new();
+        for i in 0..300 {
+            rb.insert(i * 7 % 300);
+        }
+        for i in 0..300 {
+            rb.delete(&(i * 11 % 300));
+        }
+        assert!(rb.is_empty());
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    #[should_panic(expected = "paranoid: insert link broke the tree: key at L is smaller")]
+    fn test_paranoid_reports_step() {
+        let mut rb: RedBlackTree<u32, SlabStore<u32>> = RedBlackTree::new();
+        for i in 10..20 {
+            rb.insert(i);
+        }
+        let l = rb.store.child(rb.root, 0);
+        *rb.store.key_mut(l) = 0;
+        rb.insert(100);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::store::NodeStore;
+use crate::tree::RedBlackTree;
+use std::{error, fmt};
+
+// directions from the root to a node, 0 = left and 1 = right
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodePath(pub Vec<usize>);
+
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "root");
+        }
+        for dir in &self.0 {
+            write!(f, "{}", if *dir == 0 { 'L' } else { 'R' })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    pub len: usize,
+    // nodes on the longest root to leaf path
+    pub height: usize,
+    // black nodes on every root to leaf path, the nil leaf excluded
+    pub black_height: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantViolation {
+    RedRoot,
+    RedSentinel,
+    // a node's parent link doesn't point back at the node that links to it
+    BrokenParentLink {
+        path: NodePath,
+    },
+    // the node's key sorts before its in-order predecessor's
+    OutOfOrder {
+        path: NodePath,
+    },
+    // red node with a red child
+    RedRed {
+        path: NodePath,
+    },
+    BlackHeightMismatch {
+        path: NodePath,
+        left: usize,
+        right: usize,
+    },
+    // deeper than the 2 * log2(n + 1) a red-black tree allows
+    TooTall {
+        height: usize,
+        len: usize,
+    },
+    LenMismatch {
+        counted: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvariantViolation::RedRoot => write!(f, "root is red"),
+            InvariantViolation::RedSentinel => write!(f, "nil sentinel is red"),
+            InvariantViolation::BrokenParentLink { path } => {
+                write!(f, "parent link of {} doesn't point at its parent", path)
+            }
+            InvariantViolation::OutOfOrder { path } => {
+                write!(f, "key at {} is smaller than its predecessor", path)
+            }
+            InvariantViolation::RedRed { path } => {
+                write!(f, "red node at {} has a red child", path)
+            }
+            InvariantViolation::BlackHeightMismatch { path, left, right } => write!(
+                f,
+                "black height under {} differs: {} on the left, {} on the right",
+                path, left, right
+            ),
+            InvariantViolation::TooTall { height, len } => {
+                write!(f, "height {} is too tall for {} nodes", height, len)
+            }
+            InvariantViolation::LenMismatch { counted, len } => {
+                write!(f, "tree holds {} nodes but len is {}", counted, len)
+            }
+        }
+    }
+}
+
+impl error::Error for InvariantViolation {}
+
+struct Walk<L> {
+    colors: bool,
+    path: Vec<usize>,
+    prev: Option<L>,
+    counted: usize,
+    height: usize,
+}
+
+impl<T, S> RedBlackTree<T, S>
+where
+    T: std::cmp::PartialOrd,
+    S: NodeStore<T>,
+{
+    /*
+     * properties
+     * - binary search tree: an in-order walk never goes down
+     * - every child's parent link points back at its parent
+     * - root property: root is black (and so is the nil sentinel)
+     * - red property: children of a red node are black
+     * - simple path from node to descendant leaf contains same number of black nodes
+     * - height is at most 2 * log2(n + 1), and len matches the node count
+     */
+    pub fn validate(&self) -> Result<TreeStats, InvariantViolation> {
+        self.check_invariants(true)
+    }
+
+    // with `colors` off only order, links and len are checked, which is all
+    // that holds halfway through a fixup
+    pub(crate) fn check_invariants(&self, colors: bool) -> Result<TreeStats, InvariantViolation> {
+        let nil = self.store.nil();
+        if self.store.is_red(nil) {
+            return Err(InvariantViolation::RedSentinel);
+        }
+        if colors && self.store.is_red(self.root) {
+            return Err(InvariantViolation::RedRoot);
+        }
+        if self.root != nil && self.store.parent(self.root) != nil {
+            return Err(InvariantViolation::BrokenParentLink {
+                path: NodePath::default(),
+            });
+        }
+
+        let mut walk = Walk {
+            colors,
+            path: Vec::new(),
+            prev: None,
+            counted: 0,
+            height: 0,
+        };
+        let black_height = self.validate_subtree(self.root, &mut walk)?;
+
+        if walk.counted != self.len() {
+            return Err(InvariantViolation::LenMismatch {
+                counted: walk.counted,
+                len: self.len(),
+            });
+        }
+        if colors && walk.height as f64 > 2.0 * ((walk.counted + 1) as f64).log2() {
+            return Err(InvariantViolation::TooTall {
+                height: walk.height,
+                len: walk.counted,
+            });
+        }
+
+        Ok(TreeStats {
+            len: walk.counted,
+            height: walk.height,
+            black_height,
+        })
+    }
+
+    // returns the black height of x
+    fn validate_subtree(
+        &self,
+        x: S::Link,
+        walk: &mut Walk<S::Link>,
+    ) -> Result<usize, InvariantViolation> {
+        let nil = self.store.nil();
+        if x == nil {
+            return Ok(0);
+        }
+        walk.height = walk.height.max(walk.path.len() + 1);
+
+        // no valid tree is this deep, and a cycle in the links would recurse forever
+        if walk.height > 2 * usize::BITS as usize {
+            return Err(InvariantViolation::TooTall {
+                height: walk.height,
+                len: self.len(),
+            });
+        }
+
+        let mut heights = [0; 2];
+        for (dir, height) in heights.iter_mut().enumerate() {
+            let c = self.store.child(x, dir);
+            walk.path.push(dir);
+            if c != nil && self.store.parent(c) != x {
+                return Err(InvariantViolation::BrokenParentLink {
+                    path: NodePath(walk.path.clone()),
+                });
+            }
+            if walk.colors && self.store.is_red(x) && self.store.is_red(c) {
+                walk.path.pop();
+                return Err(InvariantViolation::RedRed {
+                    path: NodePath(walk.path.clone()),
+                });
+            }
+            *height = self.validate_subtree(c, walk)?;
+            walk.path.pop();
+
+            // in-order visit between the two subtrees
+            if dir == 0 {
+                if let Some(prev) = walk.prev {
+                    if self.less(x, prev) {
+                        return Err(InvariantViolation::OutOfOrder {
+                            path: NodePath(walk.path.clone()),
+                        });
+                    }
+                }
+                walk.prev = Some(x);
+                walk.counted += 1;
+            }
+        }
+
+        if walk.colors && heights[0] != heights[1] {
+            return Err(InvariantViolation::BlackHeightMismatch {
+                path: NodePath(walk.path.clone()),
+                left: heights[0],
+                right: heights[1],
+            });
+        }
+        let add = if self.store.is_red(x) { 0 } else { 1 };
+        Ok(heights[0] + add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PointerRedBlack;
+    use crate::redblack::RedBlack;
+    use crate::slab::SlabRedBlack;
+
+    fn filled() -> SlabRedBlack<i32> {
+        let mut rb: SlabRedBlack<i32> = SlabRedBlack::new();
+        for i in 0..100 {
+            rb.insert(i);
+        }
+        rb
+    }
+
+    #[test]
+    fn test_validate_stats() {
+        let mut rb: PointerRedBlack<i32> = PointerRedBlack::new();
+        assert_eq!(rb.validate(), Ok(TreeStats::default()));
+
+        for i in 0..1000 {
+            rb.insert(i);
+        }
+        let stats = rb.validate().unwrap();
+        assert_eq!(stats.len, 1000);
+        assert!(stats.height <= 2 * 10);
+        assert!(stats.black_height >= 5 && stats.black_height <= stats.height);
+    }
+
+    #[test]
+    fn test_validate_red_root() {
+        let mut rb = filled();
+        rb.store.set_red(rb.root, true);
+        assert_eq!(rb.validate(), Err(InvariantViolation::RedRoot));
+    }
+
+    #[test]
+    fn test_validate_out_of_order() {
+        let mut rb = filled();
+        let l = rb.store.child(rb.root, 0);
+        *rb.store.key_mut(l) = -1000;
+        assert_eq!(
+            rb.validate(),
+            Err(InvariantViolation::OutOfOrder {
+                path: NodePath(vec![0])
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_broken_parent_link() {
+        let mut rb = filled();
+        let r = rb.store.child(rb.root, 1);
+        let rl = rb.store.child(r, 0);
+        rb.store.set_parent(rl, rb.root);
+        let violation = rb.validate().unwrap_err();
+        assert_eq!(
+            violation,
+            InvariantViolation::BrokenParentLink {
+                path: NodePath(vec![1, 0])
+            }
+        );
+        assert_eq!(
+            violation.to_string(),
+            "parent link of RL doesn't point at its parent"
+        );
+    }
+
+    #[test]
+    fn test_validate_red_red_and_black_height() {
+        let mut rb = filled();
+        let r = rb.store.child(rb.root, 1);
+        let rr = rb.store.child(r, 1);
+        rb.store.set_red(r, true);
+        rb.store.set_red(rr, true);
+        assert_eq!(
+            rb.validate(),
+            Err(InvariantViolation::RedRed {
+                path: NodePath(vec![1])
+            })
+        );
+
+        // a lone red node only throws off the black height
+        rb.store.set_red(rr, false);
+        assert!(matches!(
+            rb.validate(),
+            Err(InvariantViolation::BlackHeightMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_validate_len() {
+        let mut rb = filled();
+        rb.len += 1;
+        assert_eq!(
+            rb.validate(),
+            Err(InvariantViolation::LenMismatch {
+                counted: 100,
+                len: 101
+            })
+        );
+    }
+}
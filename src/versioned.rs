@@ -0,0 +1,321 @@
+use crate::persistent::{self, PersistentRedBlack};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::sync::Arc;
+
+/*
+ * a map that keeps its history: writes go to a working version, commit
+ * numbers it and freezes it, and reads can ask for any version still kept
+ *
+ * every version is a PersistentRedBlack of key-value entries, so a commit
+ * costs nothing but a clone and versions share all the nodes and values
+ * that didn't change between them; gc_before drops the versions nobody
+ * reads any more, and with them whatever only they still pointed at
+ */
+pub struct VersionedMap<K, V> {
+    working: PersistentRedBlack<Entry<K, V>>,
+    versions: BTreeMap<u64, PersistentRedBlack<Entry<K, V>>>,
+    latest: u64,
+}
+
+// ordered by key alone, the value rides along behind an Arc
+struct Entry<K, V> {
+    key: K,
+    value: Arc<V>,
+}
+
+impl<K: Clone, V> Clone for Entry<K, V> {
+    fn clone(&self) -> Self {
+        Entry {
+            key: self.key.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<K: Ord, V> PartialEq for Entry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: Ord, V> PartialOrd for Entry<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.key.cmp(&other.key))
+    }
+}
+
+// why a version can't be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionError {
+    Collected { version: u64, oldest: u64 },
+    NotCommitted { version: u64, latest: u64 },
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionError::Collected { version, oldest } => write!(
+                f,
+                "version {} was collected, the oldest kept is {}",
+                version, oldest
+            ),
+            VersionError::NotCommitted { version, latest } => write!(
+                f,
+                "version {} isn't committed yet, the latest is {}",
+                version, latest
+            ),
+        }
+    }
+}
+
+impl error::Error for VersionError {}
+
+impl<K: Ord + Clone, V> Default for VersionedMap<K, V> {
+    fn default() -> Self {
+        VersionedMap::new()
+    }
+}
+
+impl<K: Ord + Clone, V> VersionedMap<K, V> {
+    // starts out with version 0, the empty map
+    pub fn new() -> Self {
+        let mut versions = BTreeMap::new();
+        versions.insert(0, PersistentRedBlack::new());
+        VersionedMap {
+            working: PersistentRedBlack::new(),
+            versions,
+            latest: 0,
+        }
+    }
+
+    // set key to value in the working version
+    pub fn insert(&mut self, key: K, value: V) {
+        let entry = Entry {
+            key,
+            value: Arc::new(value),
+        };
+        // delete leaves the tree alone when the key isn't there
+        self.working = self.working.delete(&entry).insert(entry);
+    }
+
+    // remove key from the working version, false if it wasn't there
+    pub fn remove(&mut self, key: &K) -> bool {
+        let entry = match self.working.find(|e| e.key.cmp(key)) {
+            Some(entry) => entry.clone(),
+            None => return false,
+        };
+        self.working = self.working.delete(&entry);
+        true
+    }
+
+    // read from the working version, uncommitted writes included
+    pub fn get(&self, key: &K) -> Option<&V> {
+        get(&self.working, key)
+    }
+
+    // number of keys in the working version
+    pub fn len(&self) -> usize {
+        self.working.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.working.is_empty()
+    }
+
+    // freeze the working version under the next version number
+    pub fn commit(&mut self) -> u64 {
+        self.latest += 1;
+        self.versions.insert(self.latest, self.working.clone());
+        self.latest
+    }
+
+    // throw away uncommitted writes
+    pub fn rollback(&mut self) {
+        self.working = self.versions[&self.latest].clone();
+    }
+
+    pub fn latest(&self) -> u64 {
+        self.latest
+    }
+
+    pub fn oldest(&self) -> u64 {
+        *self.versions.keys().next().unwrap()
+    }
+
+    pub fn get_at(&self, key: &K, version: u64) -> Result<Option<&V>, VersionError> {
+        Ok(get(self.at(version)?, key))
+    }
+
+    // entries of a version within range in ascending key order
+    pub fn range_at<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        version: u64,
+    ) -> Result<Range<'_, K, V, R>, VersionError> {
+        let tree = self.at(version)?;
+        let before = |e: &Entry<K, V>| match range.start_bound() {
+            Bound::Included(start) => e.key < *start,
+            Bound::Excluded(start) => e.key <= *start,
+            Bound::Unbounded => false,
+        };
+        let entries = tree.range_by(before);
+        Ok(Range {
+            entries,
+            range,
+            done: false,
+        })
+    }
+
+    /*
+     * forget every version before this one, returning how many went; the
+     * latest committed version is always kept, so gc_before(u64::MAX)
+     * keeps just that
+     */
+    pub fn gc_before(&mut self, version: u64) -> usize {
+        let keep = self.versions.split_off(&version.min(self.latest));
+        let collected = self.versions.len();
+        self.versions = keep;
+        collected
+    }
+
+    fn at(&self, version: u64) -> Result<&PersistentRedBlack<Entry<K, V>>, VersionError> {
+        match self.versions.get(&version) {
+            Some(tree) => Ok(tree),
+            None if version > self.latest => Err(VersionError::NotCommitted {
+                version,
+                latest: self.latest,
+            }),
+            None => Err(VersionError::Collected {
+                version,
+                oldest: self.oldest(),
+            }),
+        }
+    }
+}
+
+fn get<'a, K: Ord + Clone, V>(tree: &'a PersistentRedBlack<Entry<K, V>>, key: &K) -> Option<&'a V> {
+    tree.find(|e| e.key.cmp(key)).map(|e| &*e.value)
+}
+
+// key-value pairs of one version, ascending, up to the end of the range
+pub struct Range<'a, K, V, R> {
+    entries: persistent::Range<'a, Entry<K, V>, RangeFull>,
+    range: R,
+    // past the end of the range, entries would go on beyond it
+    done: bool,
+}
+
+impl<'a, K: Ord + Clone, V, R: RangeBounds<K>> Iterator for Range<'a, K, V, R> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.done {
+            return None;
+        }
+        let e = self.entries.next()?;
+        let within = match self.range.end_bound() {
+            Bound::Included(end) => e.key <= *end,
+            Bound::Excluded(end) => e.key < *end,
+            Bound::Unbounded => true,
+        };
+        if !within {
+            self.done = true;
+            return None;
+        }
+        Some((&e.key, &*e.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_in_time_reads() {
+        let mut map: VersionedMap<u32, String> = VersionedMap::new();
+        for i in 0..100 {
+            map.insert(i, format!("a{}", i));
+        }
+        let v1 = map.commit();
+        for i in (0..100).step_by(2) {
+            map.insert(i, format!("b{}", i));
+        }
+        map.remove(&5);
+        assert!(!map.remove(&500));
+        let v2 = map.commit();
+        map.insert(7, String::from("uncommitted"));
+
+        assert_eq!(map.get_at(&4, 0), Ok(None));
+        assert_eq!(map.get_at(&4, v1).unwrap().unwrap(), "a4");
+        assert_eq!(map.get_at(&4, v2).unwrap().unwrap(), "b4");
+        assert_eq!(map.get_at(&5, v1).unwrap().unwrap(), "a5");
+        assert_eq!(map.get_at(&5, v2), Ok(None));
+        assert_eq!(map.get_at(&7, v2).unwrap().unwrap(), "a7");
+        assert_eq!(map.get(&7).unwrap(), "uncommitted");
+        assert_eq!(map.len(), 99);
+
+        // a value nobody changed is the same allocation in both versions
+        let a = map.get_at(&3, v1).unwrap().unwrap();
+        let b = map.get_at(&3, v2).unwrap().unwrap();
+        assert!(std::ptr::eq(a, b));
+
+        fn pairs<'a>(r: impl Iterator<Item = (&'a u32, &'a String)>) -> Vec<String> {
+            r.map(|(k, v)| format!("{}={}", k, v)).collect()
+        }
+        assert_eq!(
+            pairs(map.range_at(3..7, v1).unwrap()),
+            vec!["3=a3", "4=a4", "5=a5", "6=a6"]
+        );
+        assert_eq!(
+            pairs(map.range_at(3..=7, v2).unwrap()),
+            vec!["3=a3", "4=b4", "6=b6", "7=a7"]
+        );
+        assert_eq!(map.range_at(.., 0).unwrap().count(), 0);
+
+        // nothing past the end, however often it's asked
+        let mut range = map.range_at(3..5, v1).unwrap();
+        assert_eq!(range.by_ref().count(), 2);
+        assert_eq!(range.next(), None);
+        assert_eq!(range.next(), None);
+        assert_eq!(map.range_at(.., v2).unwrap().count(), 99);
+
+        map.rollback();
+        assert_eq!(map.get(&7).unwrap(), "a7");
+    }
+
+    #[test]
+    fn test_gc() {
+        let mut map: VersionedMap<u32, u32> = VersionedMap::new();
+        for i in 0..10 {
+            map.insert(0, i);
+            map.commit();
+        }
+        assert_eq!(map.gc_before(4), 4);
+        assert_eq!(map.oldest(), 4);
+        assert_eq!(
+            map.get_at(&0, 3),
+            Err(VersionError::Collected {
+                version: 3,
+                oldest: 4
+            })
+        );
+        assert_eq!(map.get_at(&0, 4), Ok(Some(&3)));
+        assert_eq!(
+            map.get_at(&0, 11),
+            Err(VersionError::NotCommitted {
+                version: 11,
+                latest: 10
+            })
+        );
+
+        // the latest version always survives
+        assert_eq!(map.gc_before(u64::MAX), 6);
+        assert_eq!(map.oldest(), 10);
+        assert_eq!(map.get_at(&0, 10), Ok(Some(&9)));
+        assert_eq!(map.gc_before(0), 0);
+    }
+}